@@ -0,0 +1,231 @@
+//! one json line per finished tcp connection and udp association, apart from diagnostic logs
+use std::{
+    io::Write,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::logging::{now_rfc3339, open_rolling, LogRotation};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use ss_light::limit::Usage;
+use tracing::{warn, Span};
+use tracing_appender::non_blocking::{NonBlocking, WorkerGuard};
+use tracing_subscriber::fmt::MakeWriter;
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct AccessLogConfig {
+    pub dir: String,
+    #[serde(default = "default_file_name")]
+    pub file_name: String,
+    #[serde(default)]
+    pub rotation: LogRotation,
+    /// bytes, for size rotation
+    #[serde(default = "default_max_size")]
+    pub max_size: u64,
+    /// files to keep including the current one, 0 means keep all
+    #[serde(default)]
+    pub max_files: usize,
+}
+
+fn default_file_name() -> String {
+    "access.log".into()
+}
+
+fn default_max_size() -> u64 {
+    100 * 1024 * 1024
+}
+
+#[derive(Debug, Serialize)]
+pub struct AccessRecord {
+    /// rfc3339, when the flow finished
+    pub timestamp: String,
+    /// tcp or udp
+    pub protocol: &'static str,
+    pub listener: String,
+    pub user: String,
+    pub peer: SocketAddr,
+    pub target: String,
+    pub resolved: Option<SocketAddr>,
+    pub upload: u64,
+    pub download: u64,
+    pub duration_ms: u128,
+    pub close_reason: String,
+    pub error: Option<String>,
+}
+
+/// records are written by a background thread, so tokio workers never block on the file
+pub struct AccessLog {
+    writer: NonBlocking,
+}
+
+impl AccessLog {
+    /// records are flushed when the returned guard is dropped
+    pub fn new(cfg: &AccessLogConfig) -> anyhow::Result<(Self, WorkerGuard)> {
+        let writer = open_rolling(
+            &cfg.dir,
+            &cfg.file_name,
+            cfg.rotation,
+            cfg.max_size,
+            cfg.max_files,
+        )
+        .context("open access log")?;
+        let (writer, guard) = tracing_appender::non_blocking(writer);
+        Ok((AccessLog { writer }, guard))
+    }
+
+    pub fn write(&self, record: &AccessRecord) {
+        let mut line = match serde_json::to_vec(record) {
+            Ok(l) => l,
+            Err(e) => {
+                warn!("serialize access record error: {}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+        if let Err(e) = self.writer.make_writer().write_all(&line) {
+            warn!("write access log error: {}", e);
+        }
+    }
+}
+
+/// record of a tcp connection being proxied, written to the access log and recorded on the
+/// connection span when dropped, so every way the connection ends is logged, including
+/// force close on shutdown
+pub struct TcpAccess {
+    log: Option<Arc<AccessLog>>,
+    start: Instant,
+    /// bytes counted by the relay
+    pub usage: Arc<Usage>,
+    pub listener: String,
+    pub user: String,
+    pub peer: SocketAddr,
+    pub target: String,
+    pub resolved: Option<SocketAddr>,
+    pub close_reason: Option<String>,
+    pub error: Option<String>,
+}
+
+impl TcpAccess {
+    pub fn new(
+        log: Option<Arc<AccessLog>>,
+        start: Instant,
+        listener: String,
+        user: String,
+        peer: SocketAddr,
+        target: String,
+    ) -> Self {
+        TcpAccess {
+            log,
+            start,
+            usage: Arc::new(Usage::default()),
+            listener,
+            user,
+            peer,
+            target,
+            resolved: None,
+            close_reason: None,
+            error: None,
+        }
+    }
+
+    pub fn close(&mut self, reason: &str, error: Option<String>) {
+        self.close_reason = Some(reason.to_string());
+        self.error = error;
+    }
+}
+
+impl Drop for TcpAccess {
+    fn drop(&mut self) {
+        let close_reason = self.close_reason.take().unwrap_or_else(|| "aborted".into());
+        let span = Span::current();
+        span.record("upload", self.usage.upload())
+            .record("download", self.usage.download())
+            .record("close_reason", close_reason.as_str());
+        if let Some(ref e) = self.error {
+            span.record("error", e.as_str());
+        }
+        let log = match self.log {
+            Some(ref log) => log,
+            None => return,
+        };
+        log.write(&AccessRecord {
+            timestamp: now_rfc3339(),
+            protocol: "tcp",
+            listener: std::mem::take(&mut self.listener),
+            user: std::mem::take(&mut self.user),
+            peer: self.peer,
+            target: std::mem::take(&mut self.target),
+            resolved: self.resolved,
+            upload: self.usage.upload(),
+            download: self.usage.download(),
+            duration_ms: self.start.elapsed().as_millis(),
+            close_reason,
+            error: self.error.take(),
+        });
+    }
+}
+
+/// record of a finished udp association
+pub fn udp_record(
+    listener: String,
+    user: String,
+    peer: SocketAddr,
+    target: String,
+    resolved: Option<SocketAddr>,
+    (upload, download): (u64, u64),
+    duration: Duration,
+) -> AccessRecord {
+    AccessRecord {
+        timestamp: now_rfc3339(),
+        protocol: "udp",
+        listener,
+        user,
+        peer,
+        target,
+        resolved,
+        upload,
+        download,
+        duration_ms: duration.as_millis(),
+        close_reason: "expired".into(),
+        error: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flushed_on_guard_drop() {
+        let dir = std::env::temp_dir().join(format!("ss-light-access-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let cfg = AccessLogConfig {
+            dir: dir.to_string_lossy().into(),
+            file_name: default_file_name(),
+            rotation: LogRotation::Never,
+            max_size: default_max_size(),
+            max_files: 0,
+        };
+        let (log, guard) = AccessLog::new(&cfg).unwrap();
+        let peer = "1.2.3.4:50000".parse().unwrap();
+        log.write(&udp_record(
+            "0.0.0.0:6789".into(),
+            "alice".into(),
+            peer,
+            "example.com:53".into(),
+            None,
+            (1, 2),
+            Duration::from_millis(3),
+        ));
+        drop(guard);
+
+        let content = std::fs::read_to_string(dir.join("access.log")).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        let record: serde_json::Value = serde_json::from_str(content.trim_end()).unwrap();
+        assert_eq!(record["user"], "alice");
+        assert_eq!(record["protocol"], "udp");
+        assert_eq!(record["download"], 2);
+    }
+}
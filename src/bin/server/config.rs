@@ -1,6 +1,7 @@
-use std::{sync::Arc, time::Duration};
+use std::{collections::HashSet, sync::Arc, time::Duration};
 
-use anyhow::Context;
+use anyhow::{bail, Context};
+use bytes::Bytes;
 use clap::{Arg, Command};
 
 use serde::{Deserialize, Serialize};
@@ -10,29 +11,107 @@ use derivative::Derivative;
 #[derive(Derivative, Deserialize, Serialize)]
 #[derivative(Debug)]
 pub struct Config {
+    #[serde(default)]
     pub passwd: String,
     pub bind_addr: String,
     pub bind_port: u16,
     pub method: ss_light::CipherKind,
     #[serde(default)]
     pub timeout: u32,
+    #[serde(default)]
+    pub handshake_timeout: u32,
     #[serde(default = "default_level")]
     pub log_level: String,
     #[serde(default)]
     pub console_log: bool,
     pub file_log_dir: Option<String>,
+    #[serde(default)]
+    pub log_format: crate::logging::LogFormat,
+    #[serde(default)]
+    pub log_filter: String,
+    #[serde(default)]
+    pub file_log_rotation: crate::logging::LogRotation,
+    #[serde(default = "default_log_max_size")]
+    pub file_log_max_size: u64,
+    #[serde(default)]
+    pub file_log_max_files: usize,
+    #[serde(default)]
+    pub users: Vec<UserConfig>,
     #[serde(skip)]
     #[derivative(Debug = "ignore")]
-    pub key: Arc<Box<[u8]>>,
+    keys: Option<Arc<[Bytes]>>,
+    #[serde(skip)]
+    #[derivative(Debug = "ignore")]
+    user_names: Vec<String>,
+    #[serde(default)]
+    pub limit: ss_light::limit::LimitConfig,
     pub udp_capacity: usize,
     pub udp_expiry_time: usize,
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout: u64,
+    pub manager_addr: Option<String>,
+    pub quota_state_file: Option<String>,
+    pub stats_file: Option<String>,
     pub plugin: Option<ss_light::plugin::PluginConfig>,
+    #[serde(default)]
+    pub tcp: ss_light::net::TcpConfig,
+    #[serde(default)]
+    pub relay: ss_light::relay::RelayConfig,
+    #[serde(default)]
+    pub mux: ss_light::mux::MuxConfig,
+    #[serde(default)]
+    pub outbound: ss_light::outbound::OutboundConfig,
+    #[serde(skip)]
+    #[derivative(Debug = "ignore")]
+    outbounds: Option<Arc<ss_light::outbound::Outbound>>,
+    #[serde(default)]
+    pub probe_defense: ss_light::defense::ProbeDefense,
+    #[serde(default)]
+    pub ban: ss_light::defense::BanConfig,
+    pub access_log: Option<crate::access_log::AccessLogConfig>,
+    #[cfg(feature = "otel")]
+    pub otel: Option<ss_light::otel::OtelConfig>,
+    #[cfg(feature = "tls")]
+    pub tls: Option<ss_light::tls::TlsConfig>,
+}
+
+#[derive(Derivative, Deserialize, Serialize, Clone)]
+#[derivative(Debug)]
+pub struct UserConfig {
+    pub name: String,
+    #[derivative(Debug = "ignore")]
+    pub passwd: String,
+    #[serde(default)]
+    pub limit: ss_light::limit::LimitConfig,
+    /// bytes per month, upload + download, 0 means no quota
+    #[serde(default)]
+    pub quota: u64,
+    /// day of month the quota resets, 1-28
+    #[serde(default = "default_quota_reset_day")]
+    pub quota_reset_day: u8,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub expires_at: Option<time::OffsetDateTime>,
 }
 
+/// name of the user from top level `passwd`
+pub const DEFAULT_USER: &str = "default";
+
 fn default_level() -> String {
     "info".into()
 }
 
+fn default_log_max_size() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_drain_timeout() -> u64 {
+    30
+}
+
+fn default_quota_reset_day() -> u8 {
+    1
+}
+
 impl Config {
     pub fn load_from_file(file_name: &str) -> anyhow::Result<Config> {
         let s = std::fs::read_to_string(file_name)
@@ -54,8 +133,68 @@ impl Config {
     pub fn get_listen_ip_port(&self) -> String {
         format!("{}:{}", self.bind_addr, self.bind_port)
     }
-    pub fn get_key(&self) -> &[u8] {
-        &self.key
+    /// derive keys of `passwd` and `users`, must be called after all overrides applied
+    pub fn init_users(&mut self) -> anyhow::Result<()> {
+        let mut users = Vec::new();
+        if !self.passwd.is_empty() {
+            users.push((DEFAULT_USER, self.passwd.as_str()));
+        }
+        for u in &self.users {
+            if !(1..=28).contains(&u.quota_reset_day) {
+                bail!("user {} quota_reset_day must be in 1-28", u.name);
+            }
+            users.push((u.name.as_str(), u.passwd.as_str()));
+        }
+        if users.is_empty() {
+            bail!("no passwd or users configured");
+        }
+
+        let mut names = HashSet::new();
+        let mut passwds = HashSet::new();
+        for (name, passwd) in &users {
+            if !names.insert(*name) {
+                bail!("duplicate user name {}", name);
+            }
+            // users are identified by the key, so they must be unique
+            if passwd.is_empty() || !passwds.insert(*passwd) {
+                bail!("user {} has empty or duplicate passwd", name);
+            }
+        }
+
+        let key_len = self.method.key_len();
+        self.keys = Some(
+            users
+                .iter()
+                .map(|(_, passwd)| {
+                    Bytes::from(ss_light::util::evp_bytes_to_key(passwd.as_bytes(), key_len))
+                })
+                .collect(),
+        );
+        self.user_names = users.iter().map(|(name, _)| name.to_string()).collect();
+        Ok(())
+    }
+    /// parse upstreams and rules of `outbound`, must be called after all overrides applied
+    pub fn init_outbound(&mut self) -> anyhow::Result<()> {
+        let outbound = ss_light::outbound::Outbound::new(&self.outbound, &self.tcp)
+            .context("invalid outbound")?;
+        self.outbounds = Some(Arc::new(outbound));
+        Ok(())
+    }
+    pub fn get_outbound(&self) -> &Arc<ss_light::outbound::Outbound> {
+        self.outbounds.as_ref().expect("init_outbound not called")
+    }
+    pub fn get_keys(&self) -> Arc<[Bytes]> {
+        self.keys.clone().expect("init_users not called")
+    }
+    pub fn get_user_names(&self) -> &[String] {
+        &self.user_names
+    }
+    /// name of user identified by key index
+    pub fn get_user_name(&self, user: usize) -> &str {
+        &self.user_names[user]
+    }
+    pub fn get_packet_cipher(&self) -> ss_light::crypto::PacketCipher {
+        ss_light::crypto::PacketCipher::new_with_keys(self.method, self.get_keys())
     }
     pub fn get_method(&self) -> ss_light::CipherKind {
         self.method
@@ -63,12 +202,44 @@ impl Config {
     pub fn get_timeout(&self) -> Duration {
         Duration::from_millis(self.timeout as u64)
     }
+    /// tls, plugin and shadowsocks handshakes of clients, `timeout` if not set
+    pub fn get_handshake_timeout(&self) -> Duration {
+        match self.handshake_timeout {
+            0 => self.get_timeout(),
+            ms => Duration::from_millis(ms as u64),
+        }
+    }
     pub fn get_udp_capacity(&self) -> usize {
         self.udp_capacity
     }
     pub fn get_udp_expiry_time(&self) -> Duration {
         Duration::from_secs(self.udp_expiry_time as u64)
     }
+    pub fn get_drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.drain_timeout)
+    }
+}
+
+#[cfg(test)]
+impl Config {
+    /// required fields followed by `extra` toml, with users and outbound initialized
+    pub fn for_test(extra: &str) -> anyhow::Result<Config> {
+        let mut cfg: Config = toml::from_str(&format!(
+            r#"
+            passwd = "passwd"
+            method = "aes-256-gcm"
+            bind_addr = "127.0.0.1"
+            bind_port = 0
+            timeout = 1000
+            udp_capacity = 10
+            udp_expiry_time = 30
+            {}"#,
+            extra
+        ))?;
+        cfg.init_users()?;
+        cfg.init_outbound()?;
+        Ok(cfg)
+    }
 }
 
 pub fn add_command_line_args(mut app: Command) -> Command {
@@ -119,6 +290,23 @@ pub fn add_command_line_args(mut app: Command) -> Command {
                 .long("plugin-opts")
                 .takes_value(true)
                 .help("overrid plugin opts in config file"),
+        )
+        .arg(
+            Arg::new("plugin-mode")
+                .long("plugin-mode")
+                .takes_value(true)
+                .possible_values(["tcp_only", "tcp_and_udp"])
+                .help("overrid plugin mode in config file"),
+        )
+        .subcommand(
+            Command::new("stats")
+                .about("print traffic stats saved in stats_file")
+                .arg(
+                    Arg::new("file")
+                        .long("file")
+                        .takes_value(true)
+                        .help("stats file, default is stats_file in config file"),
+                ),
         );
 
     app
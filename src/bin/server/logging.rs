@@ -0,0 +1,881 @@
+//! diagnostic logs: format, filter directives and rolling files
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tracing::{
+    field::{Field, Visit},
+    info,
+    metadata::LevelFilter,
+    span::{Attributes, Id, Record},
+    subscriber::Interest,
+    warn, Event, Metadata, Subscriber,
+};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::{
+    field::RecordFields,
+    filter,
+    fmt::{format::Writer, FmtContext, FormatEvent, FormatFields, FormattedFields, MakeWriter},
+    layer::{Context, Filter},
+    prelude::__tracing_subscriber_SubscriberExt,
+    registry::LookupSpan,
+    reload as log_reload,
+    util::SubscriberInitExt,
+    Layer, Registry,
+};
+
+use crate::config::Config;
+
+/// how often log files roll over
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogRotation {
+    Minutely,
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+    /// when the file would exceed max size
+    Size,
+}
+
+/// format of diagnostic logs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    #[default]
+    Full,
+    Compact,
+    Pretty,
+    Json,
+}
+
+pub fn now_rfc3339() -> String {
+    OffsetDateTime::now_utc()
+        .format(&Rfc3339)
+        .unwrap_or_default()
+}
+
+/// open `file_name` in `dir`, rolled over by `rotation`, keeping at most `max_files`
+/// files including the current one, 0 means keep all
+pub fn open_rolling(
+    dir: &str,
+    file_name: &str,
+    rotation: LogRotation,
+    max_size: u64,
+    max_files: usize,
+) -> anyhow::Result<Box<dyn Write + Send>> {
+    let rotation = match rotation {
+        LogRotation::Minutely => Rotation::MINUTELY,
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Daily => Rotation::DAILY,
+        LogRotation::Never => Rotation::NEVER,
+        LogRotation::Size => {
+            return Ok(Box::new(SizeRolling::new(
+                dir, file_name, max_size, max_files,
+            )?))
+        }
+    };
+    let mut builder = RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(file_name);
+    if max_files != 0 {
+        builder = builder.max_log_files(max_files);
+    }
+    Ok(Box::new(builder.build(dir).with_context(|| {
+        format!("open {} in {}", file_name, dir)
+    })?))
+}
+
+/// rolls `name` over to `name.1` when it would exceed `max_size`, older files shift to
+/// `name.2` and so on
+struct SizeRolling {
+    dir: PathBuf,
+    name: String,
+    max_size: u64,
+    max_files: usize,
+    file: File,
+    written: u64,
+}
+
+impl SizeRolling {
+    fn new(dir: &str, name: &str, max_size: u64, max_files: usize) -> anyhow::Result<Self> {
+        if max_size == 0 {
+            anyhow::bail!("max_size of size rotation must not be 0");
+        }
+        fs::create_dir_all(dir).with_context(|| format!("create log dir {}", dir))?;
+        let dir = PathBuf::from(dir);
+        let file = Self::open(&dir, name)?;
+        let written = file.metadata()?.len();
+        Ok(SizeRolling {
+            dir,
+            name: name.into(),
+            max_size,
+            max_files,
+            file,
+            written,
+        })
+    }
+
+    fn open(dir: &std::path::Path, name: &str) -> io::Result<File> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(name))
+    }
+
+    fn rotated(&self, i: usize) -> PathBuf {
+        self.dir.join(format!("{}.{}", self.name, i))
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let current = self.dir.join(&self.name);
+        if self.max_files == 1 {
+            fs::remove_file(&current)?;
+        } else {
+            // first free index, or the oldest kept one which is overwritten
+            let mut last = 1;
+            while (self.max_files == 0 || last + 1 < self.max_files) && self.rotated(last).exists()
+            {
+                last += 1;
+            }
+            for i in (1..last).rev() {
+                fs::rename(self.rotated(i), self.rotated(i + 1))?;
+            }
+            fs::rename(&current, self.rotated(1))?;
+        }
+        self.file = Self::open(&self.dir, &self.name)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl Write for SizeRolling {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written > 0 && self.written + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let n = self.file.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// collects fields into a json object
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().into(), Value::String(format!("{:?}", value)));
+    }
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().into(), value.into());
+    }
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().into(), value.into());
+    }
+}
+
+/// span fields stored as a json object
+struct JsonFields;
+
+impl<'w> FormatFields<'w> for JsonFields {
+    fn format_fields<R: RecordFields>(&self, mut writer: Writer<'w>, fields: R) -> fmt::Result {
+        let mut map = Map::new();
+        fields.record(&mut JsonVisitor(&mut map));
+        write!(writer, "{}", Value::Object(map))
+    }
+
+    fn add_fields(
+        &self,
+        current: &'w mut FormattedFields<Self>,
+        fields: &Record<'_>,
+    ) -> fmt::Result {
+        let mut map = match serde_json::from_str(&current.fields) {
+            Ok(Value::Object(map)) => map,
+            _ => Map::new(),
+        };
+        fields.record(&mut JsonVisitor(&mut map));
+        current.fields = Value::Object(map).to_string();
+        Ok(())
+    }
+}
+
+/// one json object per line, with fields of the event and its spans
+struct JsonFormat;
+
+impl<S> FormatEvent<S, JsonFields> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, JsonFields>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let meta = event.metadata();
+        let mut fields = Map::new();
+        event.record(&mut JsonVisitor(&mut fields));
+        let mut spans = Vec::new();
+        if let Some(scope) = ctx.event_scope() {
+            for span in scope.from_root() {
+                let mut obj = match span
+                    .extensions()
+                    .get::<FormattedFields<JsonFields>>()
+                    .and_then(|f| serde_json::from_str(&f.fields).ok())
+                {
+                    Some(Value::Object(map)) => map,
+                    _ => Map::new(),
+                };
+                obj.insert("name".into(), span.name().into());
+                spans.push(Value::Object(obj));
+            }
+        }
+        let mut line = Map::new();
+        line.insert("timestamp".into(), now_rfc3339().into());
+        line.insert("level".into(), meta.level().as_str().into());
+        line.insert("target".into(), meta.target().into());
+        line.insert("fields".into(), Value::Object(fields));
+        line.insert("spans".into(), Value::Array(spans));
+        writeln!(writer, "{}", Value::Object(line))
+    }
+}
+
+/// filter directives, `RUST_LOG` takes precedence over `log_filter` of config
+fn directives(c: &Config) -> String {
+    match std::env::var("RUST_LOG") {
+        Ok(s) if !s.is_empty() => s,
+        _ => c.log_filter.clone(),
+    }
+}
+
+/// parse directives like `ss_light=debug,server::run=trace`
+pub fn parse_directives(s: &str) -> anyhow::Result<filter::Targets> {
+    if s.trim().is_empty() {
+        return Ok(filter::Targets::new());
+    }
+    s.parse()
+        .with_context(|| format!("parse log filter directives {:?}", s))
+}
+
+/// `level` for our crates and plugin output overridden by `directives`
+fn targets(level: LevelFilter, directives: &str) -> anyhow::Result<filter::Targets> {
+    let directives = parse_directives(directives)?;
+    let mut targets = filter::Targets::new()
+        .with_target("server", level)
+        .with_target("ss_light", level)
+        .with_target("plugin", level);
+    if let Some(default) = directives.default_level() {
+        targets = targets.with_default(default);
+    }
+    Ok(targets.with_targets(directives))
+}
+
+fn is_ours(target: &str) -> bool {
+    target.starts_with("server") || target.starts_with("ss_light")
+}
+
+/// what a temporary debug filter matches, against fields of `tcp` and `udp` spans
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DebugTarget {
+    Peer(IpAddr),
+    User(String),
+}
+
+impl fmt::Display for DebugTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DebugTarget::Peer(ip) => write!(f, "peer {}", ip),
+            DebugTarget::User(name) => write!(f, "user {}", name),
+        }
+    }
+}
+
+/// peer and user of a connection span
+#[derive(Default)]
+struct ConnFields {
+    peer: Option<IpAddr>,
+    user: Option<String>,
+}
+
+impl Visit for ConnFields {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match field.name() {
+            "peer" => {
+                self.peer = format!("{:?}", value)
+                    .parse::<SocketAddr>()
+                    .ok()
+                    .map(|a| a.ip())
+            }
+            "user" => self.user = Some(format!("{:?}", value)),
+            _ => {}
+        }
+    }
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "user" {
+            self.user = Some(value.into());
+        }
+    }
+}
+
+/// temporary debug filters and when they expire
+#[derive(Default)]
+pub struct DebugTargets(RwLock<Vec<(DebugTarget, Instant)>>);
+
+impl DebugTargets {
+    fn matches(&self, f: &ConnFields) -> bool {
+        self.0.read().unwrap().iter().any(|(t, _)| match t {
+            DebugTarget::Peer(ip) => f.peer == Some(*ip),
+            DebugTarget::User(name) => f.user.as_ref() == Some(name),
+        })
+    }
+}
+
+/// `targets`, plus everything inside spans matched by debug filters while any is active
+#[derive(Clone)]
+pub struct LogFilter {
+    targets: filter::Targets,
+    off: bool,
+    debug: Option<Arc<DebugTargets>>,
+}
+
+impl LogFilter {
+    fn in_debugged_span<S>(&self, cx: &Context<'_, S>) -> bool
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let debug = match self.debug {
+            Some(ref d) => d,
+            None => return false,
+        };
+        match cx.lookup_current() {
+            Some(span) => span.scope().any(|s| {
+                s.extensions()
+                    .get::<ConnFields>()
+                    .is_some_and(|f| debug.matches(f))
+            }),
+            None => false,
+        }
+    }
+}
+
+impl<S> Filter<S> for LogFilter
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn enabled(&self, meta: &Metadata<'_>, cx: &Context<'_, S>) -> bool {
+        if self.off {
+            return false;
+        }
+        if self.targets.would_enable(meta.target(), meta.level()) {
+            return true;
+        }
+        // spans of ours are always kept, so a debug filter also matches existing connections
+        is_ours(meta.target()) && (meta.is_span() || self.in_debugged_span(cx))
+    }
+
+    fn callsite_enabled(&self, meta: &'static Metadata<'static>) -> Interest {
+        if self.off {
+            Interest::never()
+        } else if self.targets.would_enable(meta.target(), meta.level())
+            || (is_ours(meta.target()) && meta.is_span())
+        {
+            Interest::always()
+        } else if is_ours(meta.target()) && self.debug.is_some() {
+            Interest::sometimes()
+        } else {
+            Interest::never()
+        }
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        if self.off {
+            Some(LevelFilter::OFF)
+        } else if self.debug.is_some() {
+            Some(LevelFilter::TRACE)
+        } else {
+            // spans are info
+            Filter::<S>::max_level_hint(&self.targets).map(|l| l.max(LevelFilter::INFO))
+        }
+    }
+
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, cx: Context<'_, S>) {
+        self.on_record(id, &Record::new(attrs.values()), cx)
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, cx: Context<'_, S>) {
+        if let Some(span) = cx.span(id) {
+            let fields = span.metadata().fields();
+            if fields.field("peer").is_none() && fields.field("user").is_none() {
+                return;
+            }
+            let mut ext = span.extensions_mut();
+            match ext.get_mut::<ConnFields>() {
+                Some(f) => values.record(f),
+                None => {
+                    let mut f = ConnFields::default();
+                    values.record(&mut f);
+                    ext.insert(f);
+                }
+            }
+        }
+    }
+}
+
+/// log level and filter, from config or set at runtime
+#[derive(Clone)]
+struct LogSettings {
+    level: LevelFilter,
+    directives: String,
+    console: bool,
+}
+
+impl LogSettings {
+    fn from_config(c: &Config) -> Self {
+        LogSettings {
+            level: LevelFilter::from(c.get_log_level()),
+            directives: directives(c),
+            console: c.console_log,
+        }
+    }
+}
+
+/// control of the running subscriber: config reloads, runtime level and filter set
+/// through the manager or SIGUSR1, and temporary debug filters
+pub struct LogControl {
+    console: log_reload::Handle<LogFilter, Registry>,
+    file: Option<log_reload::Handle<LogFilter, Registry>>,
+    config: Mutex<LogSettings>,
+    runtime: Mutex<Option<LogSettings>>,
+    debug: Arc<DebugTargets>,
+    #[cfg(feature = "otel")]
+    pub otel: Option<ss_light::otel::OtelExporter>,
+}
+
+impl LogControl {
+    fn apply(&self) -> anyhow::Result<()> {
+        let settings = match *self.runtime.lock().unwrap() {
+            Some(ref s) => s.clone(),
+            None => self.config.lock().unwrap().clone(),
+        };
+        let targets = targets(settings.level, &settings.directives)?;
+        let debug = (!self.debug.0.read().unwrap().is_empty()).then(|| self.debug.clone());
+        self.console.reload(LogFilter {
+            targets: targets.clone(),
+            off: !settings.console,
+            debug: debug.clone(),
+        })?;
+        if let Some(ref h) = self.file {
+            h.reload(LogFilter {
+                targets,
+                off: false,
+                debug,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// apply a new config, level and filter set at runtime are dropped
+    pub fn reload(&self, c: &Config) -> anyhow::Result<()> {
+        *self.config.lock().unwrap() = LogSettings::from_config(c);
+        *self.runtime.lock().unwrap() = None;
+        self.apply()
+    }
+
+    fn current(&self) -> LogSettings {
+        match *self.runtime.lock().unwrap() {
+            Some(ref s) => s.clone(),
+            None => self.config.lock().unwrap().clone(),
+        }
+    }
+
+    /// set level and directives until `reset` or a config reload
+    pub fn set(&self, level: Option<LevelFilter>, directives: Option<&str>) -> anyhow::Result<()> {
+        let mut settings = self.current();
+        if let Some(level) = level {
+            settings.level = level;
+        }
+        if let Some(d) = directives {
+            parse_directives(d)?;
+            settings.directives = d.into();
+        }
+        *self.runtime.lock().unwrap() = Some(settings);
+        self.apply()
+    }
+
+    /// back to level and filter of config
+    pub fn reset(&self) -> anyhow::Result<()> {
+        *self.runtime.lock().unwrap() = None;
+        self.apply()
+    }
+
+    pub fn is_overridden(&self) -> bool {
+        self.runtime.lock().unwrap().is_some()
+    }
+
+    /// (level, directives, set at runtime)
+    pub fn status(&self) -> (LevelFilter, String, bool) {
+        let s = self.current();
+        (s.level, s.directives, self.is_overridden())
+    }
+
+    /// log everything of connections matching `target` for `duration`
+    pub fn add_debug(&self, target: DebugTarget, duration: Duration) -> anyhow::Result<()> {
+        {
+            let mut entries = self.debug.0.write().unwrap();
+            entries.retain(|(t, _)| t != &target);
+            entries.push((target, Instant::now() + duration));
+        }
+        self.apply()
+    }
+
+    pub fn clear_debug(&self) -> anyhow::Result<()> {
+        self.debug.0.write().unwrap().clear();
+        self.apply()
+    }
+
+    /// active debug filters and their remaining time
+    pub fn debugs(&self) -> Vec<(DebugTarget, Duration)> {
+        let now = Instant::now();
+        self.debug
+            .0
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(t, until)| (t.clone(), until.saturating_duration_since(now)))
+            .collect()
+    }
+
+    /// drop expired debug filters every second
+    pub async fn expire_debug(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            let now = Instant::now();
+            let expired: Vec<_> = {
+                let mut entries = self.debug.0.write().unwrap();
+                let expired = entries
+                    .iter()
+                    .filter(|(_, until)| *until <= now)
+                    .map(|(t, _)| t.clone())
+                    .collect();
+                entries.retain(|(_, until)| *until > now);
+                expired
+            };
+            if !expired.is_empty() {
+                for t in expired {
+                    info!("debug log of {} expired", t);
+                }
+                if let Err(e) = self.apply() {
+                    warn!("apply log filter error: {:#}", e);
+                }
+            }
+        }
+    }
+}
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+fn fmt_layer<W>(format: LogFormat, writer: W, ansi: bool) -> BoxedLayer
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi);
+    match format {
+        LogFormat::Full => layer.boxed(),
+        LogFormat::Compact => layer.compact().boxed(),
+        LogFormat::Pretty => layer.pretty().boxed(),
+        LogFormat::Json => layer
+            .fmt_fields(JsonFields)
+            .event_format(JsonFormat)
+            .boxed(),
+    }
+}
+
+pub fn init_tracing_subscriber(c: &Config) -> anyhow::Result<Arc<LogControl>> {
+    let (subscriber, control) = new_subscriber(c, io::stdout)?;
+    subscriber.init();
+    Ok(control)
+}
+
+/// subscriber of `c` with console output to `console_writer`, and its control
+pub fn new_subscriber<W>(
+    c: &Config,
+    console_writer: W,
+) -> anyhow::Result<(impl Subscriber + Send + Sync, Arc<LogControl>)>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let settings = LogSettings::from_config(c);
+    let targets = targets(settings.level, &settings.directives)?;
+
+    let (console_filter, console) = log_reload::Layer::new(LogFilter {
+        targets: targets.clone(),
+        off: !settings.console,
+        debug: None,
+    });
+    let mut layers = vec![fmt_layer(c.log_format, console_writer, true)
+        .with_filter(console_filter)
+        .boxed()];
+
+    let mut file = None;
+    if let Some(dir) = &c.file_log_dir {
+        let writer = open_rolling(
+            dir,
+            "ss-light.log",
+            c.file_log_rotation,
+            c.file_log_max_size,
+            c.file_log_max_files,
+        )?;
+        let (file_filter, handle) = log_reload::Layer::new(LogFilter {
+            targets,
+            off: false,
+            debug: None,
+        });
+        layers.push(
+            fmt_layer(c.log_format, Mutex::new(writer), false)
+                .with_filter(file_filter)
+                .boxed(),
+        );
+        file = Some(handle);
+    }
+    #[cfg(feature = "otel")]
+    let otel = c.otel.as_ref().map(|cfg| {
+        let (layer, exporter) = ss_light::otel::new(cfg);
+        // only spans of ours, events stay with the filters above
+        let only_spans = filter::filter_fn(|meta| meta.is_span() && is_ours(meta.target()))
+            .with_max_level_hint(LevelFilter::INFO);
+        layers.push(layer.with_filter(only_spans).boxed());
+        exporter
+    });
+    let subscriber = tracing_subscriber::registry().with(layers);
+
+    let control = Arc::new(LogControl {
+        console,
+        file,
+        config: Mutex::new(settings),
+        runtime: Mutex::new(None),
+        debug: Arc::default(),
+        #[cfg(feature = "otel")]
+        otel,
+    });
+    Ok((subscriber, control))
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing::{debug, field, info, info_span, Span};
+
+    use super::*;
+
+    /// contents of `name` in `dir`, None if missing
+    fn read(dir: &std::path::Path, name: &str) -> Option<String> {
+        fs::read_to_string(dir.join(name)).ok()
+    }
+
+    #[test]
+    fn test_size_rolling() {
+        let dir = std::env::temp_dir().join(format!("ss-light-log-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.to_str().unwrap();
+        assert!(SizeRolling::new(path, "zero.log", 0, 3).is_err());
+
+        // keeps the current file and 2 rotated ones, newest first
+        let mut w = SizeRolling::new(path, "keep.log", 10, 3).unwrap();
+        for i in 0..6 {
+            w.write_all(format!("line{}\n", i).as_bytes()).unwrap();
+        }
+        assert_eq!(read(&dir, "keep.log").unwrap(), "line5\n");
+        assert_eq!(read(&dir, "keep.log.1").unwrap(), "line4\n");
+        assert_eq!(read(&dir, "keep.log.2").unwrap(), "line3\n");
+        assert_eq!(read(&dir, "keep.log.3"), None);
+
+        // size of an existing file counts, a write larger than max size is not split
+        let mut w = SizeRolling::new(path, "keep.log", 10, 3).unwrap();
+        w.write_all(b"0123456789abc\n").unwrap();
+        assert_eq!(read(&dir, "keep.log").unwrap(), "0123456789abc\n");
+        assert_eq!(read(&dir, "keep.log.1").unwrap(), "line5\n");
+        assert_eq!(read(&dir, "keep.log.2").unwrap(), "line4\n");
+
+        // 0 keeps all
+        let mut w = SizeRolling::new(path, "all.log", 10, 0).unwrap();
+        for i in 0..4 {
+            w.write_all(format!("line{}\n", i).as_bytes()).unwrap();
+        }
+        assert_eq!(read(&dir, "all.log.3").unwrap(), "line0\n");
+        assert_eq!(read(&dir, "all.log.1").unwrap(), "line2\n");
+
+        // 1 keeps only the current one
+        let mut w = SizeRolling::new(path, "one.log", 10, 1).unwrap();
+        for i in 0..3 {
+            w.write_all(format!("line{}\n", i).as_bytes()).unwrap();
+        }
+        assert_eq!(read(&dir, "one.log").unwrap(), "line2\n");
+        assert_eq!(read(&dir, "one.log.1"), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// writer appending to a shared buffer
+    #[derive(Clone, Default)]
+    struct Buf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_json_format() {
+        let buf = Buf::default();
+        let writer = buf.clone();
+        let subscriber = tracing_subscriber::registry().with(fmt_layer(
+            LogFormat::Json,
+            move || writer.clone(),
+            false,
+        ));
+        tracing::subscriber::with_default(subscriber, || {
+            let conn = info_span!("tcp", peer = %"127.0.0.1:1234", user = tracing::field::Empty);
+            conn.record("user", "alice");
+            let _conn = conn.enter();
+            let _relay = info_span!("relay", target = "example.com:443").entered();
+            info!(up = 10u64, ok = true, "relay done");
+        });
+
+        let out = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines.len(), 1);
+        let line: Value = serde_json::from_str(lines[0]).unwrap();
+        assert!(OffsetDateTime::parse(line["timestamp"].as_str().unwrap(), &Rfc3339).is_ok());
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["target"], module_path!());
+        assert_eq!(line["fields"]["message"], "relay done");
+        assert_eq!(line["fields"]["up"], 10);
+        assert_eq!(line["fields"]["ok"], true);
+        assert_eq!(
+            line["spans"],
+            serde_json::json!([
+                {"name": "tcp", "peer": "127.0.0.1:1234", "user": "alice"},
+                {"name": "relay", "target": "example.com:443"},
+            ])
+        );
+    }
+
+    /// subscriber of a config logging info to `buf`, and its control
+    fn subscriber(buf: &Buf) -> (impl Subscriber + Send + Sync, Arc<LogControl>) {
+        let cfg = Config::for_test("log_level = \"info\"\nconsole_log = true").unwrap();
+        let writer = buf.clone();
+        new_subscriber(&cfg, move || writer.clone()).unwrap()
+    }
+
+    /// a connection span as in run, with the user recorded after the handshake
+    fn conn(peer: &str, user: &str) -> Span {
+        let span = info_span!("tcp", peer = %peer, user = field::Empty);
+        span.record("user", user);
+        span
+    }
+
+    impl Buf {
+        /// output since the last call
+        fn take(&self) -> String {
+            String::from_utf8(std::mem::take(&mut *self.0.lock().unwrap())).unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_debug_filter() {
+        let buf = Buf::default();
+        let (subscriber, control) = subscriber(&buf);
+        let _default = tracing::subscriber::set_default(subscriber);
+        let alice = conn("10.0.0.1:1000", "alice");
+        let bob = conn("10.0.0.2:1000", "bob");
+        let log = |span: &Span, msg: &str| span.in_scope(|| debug!("{}", msg));
+
+        log(&alice, "none");
+        info!("info");
+        let out = buf.take();
+        assert!(!out.contains("none"));
+        assert!(out.contains("info"));
+
+        // per peer, spans created before the filter included
+        let peer = DebugTarget::Peer("10.0.0.1".parse().unwrap());
+        control
+            .add_debug(peer.clone(), Duration::from_secs(60))
+            .unwrap();
+        log(&alice, "peer alice");
+        log(&bob, "peer bob");
+        debug!("peer outside");
+        let out = buf.take();
+        assert!(out.contains("peer alice"));
+        assert!(!out.contains("peer bob"));
+        assert!(!out.contains("peer outside"));
+        // inner spans of a matched connection too
+        alice.in_scope(|| info_span!("relay").in_scope(|| debug!("inner")));
+        assert!(buf.take().contains("inner"));
+
+        // per user, the same target replaces the old one
+        let user = DebugTarget::User("bob".into());
+        control.add_debug(user.clone(), Duration::ZERO).unwrap();
+        control
+            .add_debug(user.clone(), Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(control.debugs().len(), 2);
+        log(&bob, "user bob");
+        assert!(buf.take().contains("user bob"));
+
+        control.clear_debug().unwrap();
+        assert!(control.debugs().is_empty());
+        log(&alice, "cleared");
+        assert!(!buf.take().contains("cleared"));
+
+        // expired ones are dropped on the first tick
+        control.add_debug(peer.clone(), Duration::ZERO).unwrap();
+        control
+            .add_debug(user.clone(), Duration::from_secs(60))
+            .unwrap();
+        let _ =
+            tokio::time::timeout(Duration::from_millis(100), control.clone().expire_debug()).await;
+        let debugs = control.debugs();
+        assert_eq!(debugs.len(), 1);
+        assert_eq!(debugs[0].0, user);
+        assert!(buf.take().contains("debug log of peer 10.0.0.1 expired"));
+        log(&alice, "expired alice");
+        log(&bob, "still bob");
+        let out = buf.take();
+        assert!(!out.contains("expired alice"));
+        assert!(out.contains("still bob"));
+    }
+}
@@ -1,18 +1,26 @@
-use std::{process, sync::Arc};
+use std::{io, process, sync::Arc};
 
 use clap::{ArgMatches, Command};
 use config::Config;
 use futures::future;
+use tokio::sync::watch;
 
 use ss_light::plugin::PluginConfig;
-use tracing::{error, info, metadata::LevelFilter};
-use tracing_subscriber::{
-    filter, prelude::__tracing_subscriber_SubscriberExt, util::SubscriberInitExt, Layer,
-};
+use tracing::{error, info, warn};
 
+mod access_log;
 mod config;
+mod logging;
+mod manager;
+mod quota;
+mod reload;
 mod run;
+mod shutdown;
+mod state;
+mod stats;
 use run::*;
+use shutdown::Shutdown;
+use state::ServerState;
 
 fn main() -> anyhow::Result<()> {
     let mut app = Command::new("ss-light")
@@ -22,26 +30,85 @@ fn main() -> anyhow::Result<()> {
 
     let matches = app.get_matches();
 
+    if let Some(("stats", sub)) = matches.subcommand() {
+        return print_stats(&matches, sub);
+    }
+
     let config = parse_config(&matches)?;
-    init_tracing_subscriber(&config);
+    let log_control = logging::init_tracing_subscriber(&config)?;
     info!("start with {:#?}", config);
 
     tokio::runtime::Runtime::new().unwrap().block_on(async {
-        let server = run_server(Arc::new(config));
-        let sig = tokio::signal::ctrl_c();
+        let (cfg_tx, cfg_rx) = watch::channel(Arc::new(config));
+        let shutdown = Shutdown::new();
+        let state = match ServerState::new(&cfg_rx.borrow()) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("exit with error: {:#}", e);
+                process::exit(-1)
+            }
+        };
+        if let Some(addr) = cfg_rx.borrow().manager_addr.clone() {
+            let state = state.clone();
+            let log_control = log_control.clone();
+            tokio::spawn(async move {
+                if let Err(e) = manager::run(addr, state, log_control).await {
+                    error!("manager exit with error: {:#}", e);
+                }
+            });
+        }
+        tokio::spawn(state.clone().purge(cfg_rx.clone()));
+        tokio::spawn(state.clone().maintain(cfg_rx.clone()));
+        tokio::spawn(log_control.clone().expire_debug());
+        #[cfg(feature = "otel")]
+        if let Some(exporter) = log_control.otel.clone() {
+            tokio::spawn(exporter.run());
+        }
+        tokio::spawn(reload::watch_debug_signal(log_control.clone()));
+        #[cfg(feature = "otel")]
+        let otel = log_control.otel.clone();
+        tokio::spawn(reload::watch_reload(matches, cfg_tx, log_control));
+        let server = Box::pin(run_server(cfg_rx.clone(), shutdown.clone(), state.clone()));
+        let sig = wait_exit_signal();
 
-        tokio::pin!(server, sig);
+        tokio::pin!(sig);
 
         match future::select(server, sig).await {
             future::Either::Left((result, ..)) => match result {
                 Ok(()) => {}
                 Err(e) => {
                     error!("exit with error: {}", e);
+                    state.save(&cfg_rx.borrow());
+                    state.flush_access_log();
                     process::exit(-1)
                 }
             },
-            future::Either::Right(_) => {
-                info!("receive exit signal")
+            future::Either::Right((sig, server)) => {
+                // stop following config changes, listeners are stopped by shutdown
+                drop(server);
+                let drain_timeout = cfg_rx.borrow().get_drain_timeout();
+                info!(
+                    "receive exit signal {}, draining {} tasks in {:?}",
+                    sig.unwrap_or("unknown"),
+                    shutdown.active(),
+                    drain_timeout
+                );
+                tokio::select! {
+                    force_closed = shutdown.shutdown(drain_timeout) => {
+                        info!("shutdown complete, {} tasks force closed", force_closed);
+                    }
+                    _ = wait_exit_signal() => {
+                        warn!("receive exit signal again, exit immediately");
+                    }
+                }
+                state.save(&cfg_rx.borrow());
+                state.flush_access_log();
+                #[cfg(feature = "otel")]
+                if let Some(ref exporter) = otel {
+                    if let Err(e) = exporter.export().await {
+                        warn!("otel export on exit error: {}", e);
+                    }
+                }
             }
         }
     });
@@ -49,6 +116,39 @@ fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// SIGTERM is what docker and systemd send, SIGINT is ctrl-c
+async fn wait_exit_signal() -> io::Result<&'static str> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut term = signal(SignalKind::terminate())?;
+        let mut int = signal(SignalKind::interrupt())?;
+        tokio::select! {
+            _ = term.recv() => Ok("SIGTERM"),
+            _ = int.recv() => Ok("SIGINT"),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await?;
+        Ok("ctrl-c")
+    }
+}
+
+fn print_stats(matches: &ArgMatches, sub: &ArgMatches) -> anyhow::Result<()> {
+    let path = match sub.value_of("file") {
+        Some(f) => f.to_string(),
+        None => parse_config(matches)?
+            .stats_file
+            .ok_or_else(|| anyhow::anyhow!("no stats_file in config file"))?,
+    };
+    if !std::path::Path::new(&path).exists() {
+        anyhow::bail!("stats file {} not found", path);
+    }
+    stats::SavedStats::load(&path)?.print();
+    Ok(())
+}
+
 fn parse_config(matches: &ArgMatches) -> anyhow::Result<Config> {
     let mut config = Config::load_from_file(matches.value_of("config").unwrap())?;
 
@@ -69,63 +169,26 @@ fn parse_config(matches: &ArgMatches) -> anyhow::Result<Config> {
     }
 
     if let Some(plugin) = matches.value_of("plugin") {
-        let plugin_cfg = config.plugin.get_or_insert(PluginConfig {
-            name: "".into(),
-            opts: None,
-            args: vec![],
-        });
+        let plugin_cfg = config.plugin.get_or_insert_with(PluginConfig::default);
         plugin_cfg.name = plugin.into();
     }
 
     if let Some(plugin_opts) = matches.value_of("plugin-opts") {
-        let plugin_cfg = config.plugin.get_or_insert(PluginConfig {
-            name: "".into(),
-            opts: None,
-            args: vec![],
-        });
+        let plugin_cfg = config.plugin.get_or_insert_with(PluginConfig::default);
         plugin_cfg.opts = Some(plugin_opts.into());
     }
 
-    let key = ss_light::util::evp_bytes_to_key(config.passwd.as_bytes(), config.method.key_len());
-    config.key = Arc::new(key);
-
-    Ok(config)
-}
-
-fn init_tracing_subscriber(c: &Config) {
-    let formateter = tracing_subscriber::fmt::format()
-        .with_level(true)
-        .with_target(true);
-
-    let file_level_filter = LevelFilter::from(c.get_log_level());
-    let mut console_level_filter = file_level_filter;
-    if !c.console_log {
-        console_level_filter = LevelFilter::OFF;
+    if let Some(plugin_mode) = matches.value_of("plugin-mode") {
+        let plugin_cfg = config.plugin.get_or_insert_with(PluginConfig::default);
+        plugin_cfg.plugin_mode = plugin_mode.parse().map_err(anyhow::Error::msg)?;
     }
 
-    let layer = tracing_subscriber::registry().with(
-        filter::Targets::new()
-            .with_target("server", console_level_filter)
-            .with_target("ss_light", console_level_filter)
-            .and_then(tracing_subscriber::fmt::layer().event_format(formateter.clone())),
-    );
-
-    if let Some(dir) = &c.file_log_dir {
-        let file_appender = tracing_appender::rolling::daily(dir, "ss-light.log");
-        layer
-            .with(
-                filter::Targets::new()
-                    .with_target("server", file_level_filter)
-                    .with_target("ss_light", file_level_filter)
-                    .and_then(
-                        tracing_subscriber::fmt::layer()
-                            .event_format(formateter)
-                            .with_writer(file_appender)
-                            .with_ansi(false),
-                    ),
-            )
-            .init();
-    } else {
-        layer.init();
+    if let Some(ref plugin) = config.plugin {
+        plugin.check_builtin().map_err(anyhow::Error::msg)?;
     }
+    config.init_users()?;
+    config.init_outbound()?;
+    logging::parse_directives(&config.log_filter)?;
+
+    Ok(config)
 }
@@ -0,0 +1,184 @@
+//! management socket, a line based text protocol over tcp.
+//! every command gets its response lines followed by an empty line, e.g. `nc 127.0.0.1 6790`
+use std::{str::FromStr, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{debug, info, metadata::LevelFilter};
+
+use crate::{
+    logging::{DebugTarget, LogControl},
+    state::ServerState,
+};
+
+const HELP: &str = "commands:
+  ping                     check the server is alive
+  bans                     list banned sources: <source> <remaining sec> <failures>
+  log                      show log level and filter
+  log level <level>        set log level until reset or config reload
+  log filter <directives>  set filter directives, e.g. ss_light::udprelay=trace
+  log reset                back to log level and filter of config
+  debug                    list debug filters: <peer|user> <ip|name> <remaining sec>
+  debug peer <ip> [sec]    log everything of connections from ip, 300s by default
+  debug user <name> [sec]  log everything of connections of user, 300s by default
+  debug clear              drop all debug filters
+  help                     show this message
+";
+
+const DEFAULT_DEBUG_DURATION: u64 = 300;
+
+pub async fn run(
+    addr: String,
+    state: Arc<ServerState>,
+    log_control: Arc<LogControl>,
+) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(&addr)
+        .await
+        .with_context(|| format!("bind manager address {}", addr))?;
+    info!("manager listening on {}", addr);
+    loop {
+        let (socket, peer) = listener.accept().await?;
+        let state = state.clone();
+        let log_control = log_control.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve(socket, &state, &log_control).await {
+                debug!("manager peer {} error: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn serve(
+    socket: TcpStream,
+    state: &ServerState,
+    log_control: &LogControl,
+) -> std::io::Result<()> {
+    let (r, mut w) = socket.into_split();
+    let mut lines = BufReader::new(r).lines();
+    while let Some(line) = lines.next_line().await? {
+        let mut resp = handle(line.trim(), state, log_control);
+        resp.push('\n');
+        w.write_all(resp.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+fn handle(cmd: &str, state: &ServerState, log_control: &LogControl) -> String {
+    let mut args = cmd.split_whitespace();
+    match args.next() {
+        Some("ping") => "pong\n".into(),
+        Some("bans") => {
+            let mut bans = state.auth_failures.bans();
+            bans.sort_by_key(|b| b.remaining);
+            bans.iter()
+                .map(|b| format!("{} {} {}\n", b.source, b.remaining.as_secs(), b.failures))
+                .collect()
+        }
+        Some("log") => {
+            let res = match (args.next(), args.next()) {
+                (None, _) => {
+                    let (level, directives, runtime) = log_control.status();
+                    let from = if runtime { "runtime" } else { "config" };
+                    return format!("level {}\nfilter {}\nfrom {}\n", level, directives, from);
+                }
+                (Some("level"), Some(l)) => match LevelFilter::from_str(l) {
+                    Ok(level) => log_control.set(Some(level), None),
+                    Err(_) => return format!("error: invalid level {}\n", l),
+                },
+                (Some("filter"), Some(d)) => log_control.set(None, Some(d)),
+                (Some("reset"), None) => log_control.reset(),
+                _ => return "error: invalid arguments, try help\n".into(),
+            };
+            ok_or_error(res)
+        }
+        Some("debug") => {
+            let target = match (args.next(), args.next()) {
+                (None, _) => {
+                    return log_control
+                        .debugs()
+                        .iter()
+                        .map(|(t, remaining)| format!("{} {}\n", t, remaining.as_secs()))
+                        .collect()
+                }
+                (Some("clear"), None) => return ok_or_error(log_control.clear_debug()),
+                (Some("peer"), Some(ip)) => match ip.parse() {
+                    Ok(ip) => DebugTarget::Peer(ip),
+                    Err(_) => return format!("error: invalid ip {}\n", ip),
+                },
+                (Some("user"), Some(name)) => DebugTarget::User(name.into()),
+                _ => return "error: invalid arguments, try help\n".into(),
+            };
+            let secs = match args.next().map(u64::from_str) {
+                None => DEFAULT_DEBUG_DURATION,
+                Some(Ok(secs)) => secs,
+                Some(Err(_)) => return "error: invalid sec\n".into(),
+            };
+            info!("debug log of {} for {}s", target, secs);
+            ok_or_error(log_control.add_debug(target, Duration::from_secs(secs)))
+        }
+        Some("help") => HELP.into(),
+        Some(c) => format!("error: unknown command {}, try help\n", c),
+        None => String::new(),
+    }
+}
+
+fn ok_or_error(res: anyhow::Result<()>) -> String {
+    match res {
+        Ok(()) => "ok\n".into(),
+        Err(e) => format!("error: {:#}\n", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, logging};
+
+    #[test]
+    fn test_log_and_debug_commands() {
+        let cfg = Config::for_test("log_level = \"info\"").unwrap();
+        let state = ServerState::new(&cfg).unwrap();
+        let (_subscriber, control) = logging::new_subscriber(&cfg, std::io::sink).unwrap();
+        let handle = |cmd| handle(cmd, &state, &control);
+
+        assert_eq!(handle("log"), "level info\nfilter \nfrom config\n");
+        assert_eq!(handle("log level debug"), "ok\n");
+        assert_eq!(handle("log filter ss_light::udprelay=trace"), "ok\n");
+        assert_eq!(
+            handle("log"),
+            "level debug\nfilter ss_light::udprelay=trace\nfrom runtime\n"
+        );
+        assert_eq!(handle("log level loud"), "error: invalid level loud\n");
+        assert!(handle("log filter ss_light=[").starts_with("error: "));
+        assert_eq!(handle("log level"), "error: invalid arguments, try help\n");
+        assert_eq!(
+            handle("log reset now"),
+            "error: invalid arguments, try help\n"
+        );
+        assert_eq!(handle("log reset"), "ok\n");
+        assert_eq!(handle("log"), "level info\nfilter \nfrom config\n");
+
+        assert_eq!(handle("debug"), "");
+        assert_eq!(handle("debug peer 10.0.0.1"), "ok\n");
+        assert_eq!(handle("debug user alice 60"), "ok\n");
+        let debugs = handle("debug");
+        let debugs: Vec<_> = debugs.lines().collect();
+        assert_eq!(debugs.len(), 2);
+        assert!(["peer 10.0.0.1 300", "peer 10.0.0.1 299"].contains(&debugs[0]));
+        assert!(["user alice 60", "user alice 59"].contains(&debugs[1]));
+        assert_eq!(handle("debug peer alice"), "error: invalid ip alice\n");
+        assert_eq!(handle("debug user alice soon"), "error: invalid sec\n");
+        assert_eq!(
+            handle("debug host x"),
+            "error: invalid arguments, try help\n"
+        );
+        assert_eq!(handle("debug clear"), "ok\n");
+        assert_eq!(handle("debug"), "");
+
+        assert_eq!(handle("ping"), "pong\n");
+        assert_eq!(handle("nope"), "error: unknown command nope, try help\n");
+    }
+}
@@ -0,0 +1,266 @@
+//! monthly traffic quota and expiry of users, usage is saved to `quota_state_file`
+use std::{
+    collections::HashMap,
+    fs, io,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use ss_light::limit::Usage;
+use time::{Date, Month, OffsetDateTime};
+use tracing::{info, warn};
+
+use crate::{config::Config, state::save_json};
+
+#[derive(Serialize, Deserialize)]
+struct SavedUsage {
+    /// unix timestamp
+    period_start: i64,
+    upload: u64,
+    download: u64,
+}
+
+struct UserQuota {
+    usage: Arc<Usage>,
+    period_start: OffsetDateTime,
+    blocked: bool, // last logged
+}
+
+#[derive(Default)]
+pub struct Quotas {
+    users: Mutex<HashMap<String, UserQuota>>,
+}
+
+/// start of the quota period containing `now`, a period starts on `reset_day` of every month
+fn period_start(now: OffsetDateTime, reset_day: u8) -> OffsetDateTime {
+    let date = now.date();
+    let (mut year, mut month) = (date.year(), date.month());
+    if date.day() < reset_day {
+        if month == Month::January {
+            year -= 1;
+        }
+        month = month.previous();
+    }
+    Date::from_calendar_date(year, month, reset_day)
+        .expect("reset day in 1-28")
+        .midnight()
+        .assume_utc()
+}
+
+impl Quotas {
+    /// load usage saved in `path`, no file means nothing used yet
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let s = match fs::read_to_string(path) {
+            Ok(s) => s,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e).with_context(|| format!("read quota state {}", path)),
+        };
+        let saved: HashMap<String, SavedUsage> =
+            serde_json::from_str(&s).with_context(|| format!("parse quota state {}", path))?;
+        let mut users = HashMap::new();
+        for (name, u) in saved {
+            users.insert(
+                name,
+                UserQuota {
+                    usage: Arc::new(Usage::new(u.upload, u.download)),
+                    period_start: OffsetDateTime::from_unix_timestamp(u.period_start)?,
+                    blocked: false,
+                },
+            );
+        }
+        Ok(Quotas {
+            users: Mutex::new(users),
+        })
+    }
+
+    pub fn save(&self, path: &str) -> anyhow::Result<()> {
+        let saved: HashMap<String, SavedUsage> = self
+            .users
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, q)| {
+                (
+                    name.clone(),
+                    SavedUsage {
+                        period_start: q.period_start.unix_timestamp(),
+                        upload: q.usage.upload(),
+                        download: q.usage.download(),
+                    },
+                )
+            })
+            .collect();
+        save_json(path, &saved)
+    }
+
+    pub fn usage(&self, name: &str) -> Option<Arc<Usage>> {
+        self.users
+            .lock()
+            .unwrap()
+            .get(name)
+            .map(|q| q.usage.clone())
+    }
+
+    /// apply quotas of users at `now`, usage is reset when a new period starts
+    pub fn update(&self, cfg: &Config, now: OffsetDateTime) {
+        let mut users = self.users.lock().unwrap();
+        users.retain(|name, _| cfg.get_user_names().contains(name));
+        for name in cfg.get_user_names() {
+            // the default user has no quota
+            let (quota, reset_day, expires_at) = match cfg.users.iter().find(|u| &u.name == name) {
+                Some(u) => (u.quota, u.quota_reset_day, u.expires_at),
+                None => (0, 1, None),
+            };
+            let start = period_start(now, reset_day);
+            let q = users.entry(name.clone()).or_insert_with(|| UserQuota {
+                usage: Arc::new(Usage::default()),
+                period_start: start,
+                blocked: false,
+            });
+            if q.period_start != start {
+                info!(
+                    "user {} starts a new quota period, {} bytes used in last one",
+                    name,
+                    q.usage.total()
+                );
+                q.usage.reset();
+                q.period_start = start;
+            }
+            q.usage.set_quota((quota != 0).then_some(quota));
+            let expired = matches!(expires_at, Some(t) if t <= now);
+            q.usage.set_expired(expired);
+
+            let blocked = q.usage.is_blocked();
+            if blocked != q.blocked {
+                q.blocked = blocked;
+                if !blocked {
+                    info!("user {} unblocked", name);
+                } else if expired {
+                    warn!("user {} blocked, expired at {:?}", name, expires_at);
+                } else {
+                    warn!(
+                        "user {} blocked, used {} of quota {} bytes",
+                        name,
+                        q.usage.total(),
+                        quota
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::Time;
+
+    use super::*;
+
+    fn at(year: i32, month: Month, day: u8, hour: u8) -> OffsetDateTime {
+        Date::from_calendar_date(year, month, day)
+            .unwrap()
+            .with_time(Time::from_hms(hour, 0, 0).unwrap())
+            .assume_utc()
+    }
+
+    #[test]
+    fn test_period_start() {
+        let start = |now, reset_day| period_start(now, reset_day);
+        // on or after the reset day, in this month
+        assert_eq!(
+            start(at(2026, Month::May, 20, 12), 15),
+            at(2026, Month::May, 15, 0)
+        );
+        assert_eq!(
+            start(at(2026, Month::May, 15, 0), 15),
+            at(2026, Month::May, 15, 0)
+        );
+        // before it, in the last month
+        assert_eq!(
+            start(at(2026, Month::May, 14, 23), 15),
+            at(2026, Month::April, 15, 0)
+        );
+        assert_eq!(
+            start(at(2026, Month::January, 10, 0), 15),
+            at(2025, Month::December, 15, 0)
+        );
+        assert_eq!(
+            start(at(2026, Month::January, 1, 0), 1),
+            at(2026, Month::January, 1, 0)
+        );
+        // day 28 exists in every february
+        assert_eq!(
+            start(at(2026, Month::March, 1, 0), 28),
+            at(2026, Month::February, 28, 0)
+        );
+        assert_eq!(
+            start(at(2024, Month::February, 29, 0), 28),
+            at(2024, Month::February, 28, 0)
+        );
+    }
+
+    #[test]
+    fn test_reset_day_range() {
+        let user = |day: u8| {
+            Config::for_test(&format!(
+                "[[users]]\nname = \"alice\"\npasswd = \"a\"\nquota_reset_day = {}",
+                day
+            ))
+        };
+        assert!(user(0).is_err());
+        assert!(user(29).is_err());
+        assert!(user(1).is_ok());
+        assert!(user(28).is_ok());
+    }
+
+    #[test]
+    fn test_update() {
+        let cfg = Config::for_test(
+            r#"
+            [[users]]
+            name = "alice"
+            passwd = "a"
+            quota = 100
+            quota_reset_day = 15
+            [[users]]
+            name = "bob"
+            passwd = "b"
+            expires_at = "2026-06-01T00:00:00Z"
+            "#,
+        )
+        .unwrap();
+        let quotas = Quotas::default();
+        quotas.update(&cfg, at(2026, Month::May, 20, 0));
+        let alice = quotas.usage("alice").unwrap();
+        let bob = quotas.usage("bob").unwrap();
+        let default = quotas.usage(crate::config::DEFAULT_USER).unwrap();
+
+        // over quota until the next period
+        alice.add_upload(60);
+        assert!(!alice.is_blocked());
+        alice.add_download(40);
+        assert!(alice.is_blocked());
+        quotas.update(&cfg, at(2026, Month::June, 14, 23));
+        assert!(alice.is_blocked());
+        quotas.update(&cfg, at(2026, Month::June, 15, 0));
+        assert!(!alice.is_blocked());
+        assert_eq!(alice.total(), 0);
+
+        // blocked from the expiry on, usage kept
+        bob.add_upload(1000);
+        quotas.update(&cfg, at(2026, Month::May, 31, 23));
+        assert!(!bob.is_blocked());
+        quotas.update(&cfg, at(2026, Month::June, 1, 0));
+        assert!(bob.is_blocked());
+
+        // no quota for the default user
+        default.add_upload(u64::MAX / 2);
+        assert!(!default.is_blocked());
+
+        // removed users are dropped
+        let cfg = Config::for_test("").unwrap();
+        quotas.update(&cfg, at(2026, Month::June, 1, 0));
+        assert!(quotas.usage("alice").is_none());
+    }
+}
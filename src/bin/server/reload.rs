@@ -0,0 +1,108 @@
+use std::sync::Arc;
+
+use clap::ArgMatches;
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+
+use crate::{config::Config, logging::LogControl, parse_config};
+
+/// reload config on SIGHUP, an invalid config is logged and the running one is kept
+pub async fn watch_reload(
+    matches: ArgMatches,
+    cfg_tx: watch::Sender<Arc<Config>>,
+    log_control: Arc<LogControl>,
+) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hup = match signal(SignalKind::hangup()) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("listen SIGHUP error: {}, config reload disabled", e);
+                return;
+            }
+        };
+        while hup.recv().await.is_some() {
+            info!("receive SIGHUP, reloading config");
+            reload(&matches, &cfg_tx, &log_control);
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (matches, cfg_tx, log_control);
+    }
+}
+
+fn reload(matches: &ArgMatches, cfg_tx: &watch::Sender<Arc<Config>>, log_control: &LogControl) {
+    let new = match parse_config(matches) {
+        Ok(c) => c,
+        Err(e) => {
+            error!(
+                "reload config error: {:#}, keep running with current config",
+                e
+            );
+            return;
+        }
+    };
+
+    let old = cfg_tx.borrow().clone();
+    if old.file_log_dir != new.file_log_dir
+        || old.log_format != new.log_format
+        || old.file_log_rotation != new.file_log_rotation
+        || old.file_log_max_size != new.file_log_max_size
+        || old.file_log_max_files != new.file_log_max_files
+    {
+        warn!("file_log_dir, log_format and file log rotation take effect after restart");
+    }
+    if old.access_log != new.access_log {
+        warn!("access_log takes effect after restart");
+    }
+    #[cfg(feature = "otel")]
+    if old.otel != new.otel {
+        warn!("otel takes effect after restart");
+    }
+    if old.manager_addr != new.manager_addr {
+        warn!("manager_addr takes effect after restart");
+    }
+    if log_control.is_overridden() {
+        info!("log level and filter set at runtime are replaced by config");
+    }
+    if let Err(e) = log_control.reload(&new) {
+        warn!("reload log level error: {:#}", e);
+    }
+
+    info!("reload with {:#?}", new);
+    cfg_tx.send_replace(Arc::new(new));
+}
+
+/// SIGUSR1 toggles trace level for everything of ours, until sent again or config reload
+pub async fn watch_debug_signal(log_control: Arc<LogControl>) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        use tracing::metadata::LevelFilter;
+        let mut usr1 = match signal(SignalKind::user_defined1()) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("listen SIGUSR1 error: {}, log level toggle disabled", e);
+                return;
+            }
+        };
+        while usr1.recv().await.is_some() {
+            let res = if log_control.is_overridden() {
+                info!("receive SIGUSR1, log level back to config");
+                log_control.reset()
+            } else {
+                info!("receive SIGUSR1, log level trace");
+                log_control.set(Some(LevelFilter::TRACE), None)
+            };
+            if let Err(e) = res {
+                warn!("toggle log level error: {:#}", e);
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = log_control;
+    }
+}
@@ -1,72 +1,830 @@
-use std::{io::ErrorKind, net::SocketAddr, sync::Arc};
+use std::{
+    io::ErrorKind,
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Instant,
+};
 
+use anyhow::{anyhow, Context};
+use futures::{future, FutureExt};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
 use tokio::{
-    net::{TcpListener, TcpStream, UdpSocket},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite},
+    net::{lookup_host, TcpListener, TcpStream, UdpSocket},
+    sync::{mpsc, oneshot, watch},
+    task::{JoinHandle, JoinSet},
     time,
 };
-use tracing::{debug, error, info, trace, warn};
+use tracing::{debug, error, field, info, info_span, trace, warn, Instrument, Span};
+
+use ss_light::{
+    defense::{BanAction, ProbeStream},
+    limit::{ConnectionGuard, FlowLimit, RateLimited},
+    mux::{self, MuxServer, MuxStream},
+    plugin::{AcceptError, Builtin, LocalAddr, LocalListeners, PluginStream},
+    relay::CloseReason,
+    UdpAssociation, UdpPolicy, UdpUsers,
+};
 
+use crate::access_log::{self, AccessLog, TcpAccess};
 use crate::config::Config;
+use crate::shutdown::Shutdown;
+use crate::state::{Limiters, ServerState};
+
+/// tcp side of the listener: plugin and acceptors, restarted when listen address,
+/// plugin, tls or tcp options change. dropping `retire` stops it, a subprocess plugin is
+/// kept until connections through it drained.
+struct TcpListenerGroup {
+    cfg: Arc<Config>,
+    /// local address of a `tcp_and_udp` plugin, where the udp server listens
+    udp_addr: Option<String>,
+    /// bound on `udp_addr` with the tcp listeners, taken by the udp server
+    udp_socket: Option<std::net::UdpSocket>,
+    /// true to keep the plugin until connections drained, false to stop it at once
+    retire: oneshot::Sender<bool>,
+    done: JoinHandle<anyhow::Result<()>>,
+}
+
+impl TcpListenerGroup {
+    fn should_restart(&self, new: &Config) -> bool {
+        self.cfg.get_listen_ip_port() != new.get_listen_ip_port()
+            || self.cfg.plugin != new.plugin
+            || self.cfg.tcp != new.tcp
+            || self.tls_changed(new)
+    }
+
+    #[cfg(feature = "tls")]
+    fn tls_changed(&self, new: &Config) -> bool {
+        self.cfg.tls != new.tls
+    }
+
+    #[cfg(not(feature = "tls"))]
+    fn tls_changed(&self, _: &Config) -> bool {
+        false
+    }
+
+    /// where the udp server listens
+    fn udp_addr(&self) -> String {
+        self.udp_addr
+            .clone()
+            .unwrap_or_else(|| self.cfg.get_listen_ip_port())
+    }
+
+    /// stop accepting, the plugin exits now or in background once its connections drained.
+    /// it can't be kept when `new` listens on the same address, which the plugin holds
+    async fn stop(self, new: &Config) -> anyhow::Result<()> {
+        let keep_plugin = self.cfg.get_listen_ip_port() != new.get_listen_ip_port();
+        let _ = self.retire.send(keep_plugin);
+        self.done.await?
+    }
+}
+
+/// layers between an accepted socket and the shadowsocks stream
+#[derive(Clone)]
+struct Transport {
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<ss_light::tls::TlsAcceptor>>,
+    builtin: Option<Arc<Builtin>>,
+}
+
+/// udp server, restarted only when its address changes, new keys are applied through
+/// the shared users. dropping `retire` lets it drain.
+struct UdpListener {
+    cfg: Arc<Config>,
+    /// listen address, or the local address of a `tcp_and_udp` plugin
+    addr: String,
+    _retire: oneshot::Sender<()>,
+}
+
+/// run listeners of config received from `cfg_rx` and follow its changes, every new
+/// connection uses the latest config, existing ones keep their own.
+/// spawned tasks are stopped through `shutdown`
+pub async fn run_server(
+    mut cfg_rx: watch::Receiver<Arc<Config>>,
+    shutdown: Arc<Shutdown>,
+    state: Arc<ServerState>,
+) -> anyhow::Result<()> {
+    let cfg = cfg_rx.borrow_and_update().clone();
+    let (users_tx, users_rx) = watch::channel(udp_users(&cfg, &state));
+    // tcp first, a tcp_and_udp plugin has to own the public udp port
+    let mut tcp = start_tcp(cfg.clone(), cfg_rx.clone(), shutdown.clone(), state.clone()).await?;
+    let mut udp = start_udp(
+        cfg.clone(),
+        tcp.udp_addr(),
+        tcp.udp_socket.take(),
+        users_rx.clone(),
+        shutdown.clone(),
+    )
+    .await?;
+
+    loop {
+        tokio::select! {
+            res = &mut tcp.done => return res?,
+            changed = cfg_rx.changed() => {
+                if changed.is_err() {
+                    // config sender dropped, nothing to follow anymore
+                    return tcp.done.await?;
+                }
+                let new = cfg_rx.borrow_and_update().clone();
+                log_user_changes(&udp.cfg, &new);
+                state.update(&new);
+                users_tx.send_replace(udp_users(&new, &state));
+
+                if tcp.should_restart(&new) {
+                    if plugin_has_udp(&new) && !plugin_has_udp(&tcp.cfg) {
+                        warn!("plugin binds udp {} after the udp server there drained, restart if it fails",
+                            new.get_listen_ip_port());
+                    }
+                    let old_cfg = tcp.cfg.clone();
+                    if let Err(e) = tcp.stop(&new).await {
+                        warn!("tcp listener on {} stopped with error: {}", old_cfg.get_listen_ip_port(), e);
+                    }
+                    tcp = match start_tcp(new.clone(), cfg_rx.clone(), shutdown.clone(), state.clone()).await {
+                        Ok(t) => t,
+                        Err(e) => {
+                            error!("start tcp listener on {} error: {}, restore {}",
+                                new.get_listen_ip_port(), e, old_cfg.get_listen_ip_port());
+                            start_tcp(old_cfg, cfg_rx.clone(), shutdown.clone(), state.clone()).await?
+                        }
+                    };
+                }
+
+                let udp_addr = tcp.udp_addr();
+                if udp.addr != udp_addr {
+                    let socket = tcp.udp_socket.take();
+                    match start_udp(new.clone(), udp_addr.clone(), socket, users_rx.clone(), shutdown.clone()).await {
+                        Ok(new_udp) => udp = new_udp,
+                        Err(e) => error!("start udp server on {} error: {}, keep {}",
+                            udp_addr, e, udp.addr),
+                    }
+                } else if udp.cfg.udp_capacity != new.udp_capacity
+                    || udp.cfg.udp_expiry_time != new.udp_expiry_time
+                {
+                    warn!("udp_capacity and udp_expiry_time take effect after listen address changed or restart");
+                }
+            }
+        }
+    }
+}
+
+/// a subprocess plugin carrying udp, built-in plugins are tcp only
+fn plugin_has_udp(cfg: &Config) -> bool {
+    cfg.plugin
+        .as_ref()
+        .is_some_and(|p| p.plugin_mode.has_udp() && p.builtin().is_none())
+}
+
+fn log_user_changes(old: &Config, new: &Config) {
+    let old_users = old.get_user_names();
+    let new_users = new.get_user_names();
+    for u in new_users.iter().filter(|u| !old_users.contains(u)) {
+        info!("user {} added", u);
+    }
+    for u in old_users.iter().filter(|u| !new_users.contains(u)) {
+        info!("user {} removed", u);
+    }
+}
+
+/// udp limits of users in key order, finished associations go to the access log
+struct UdpUserPolicy {
+    limits: Vec<FlowLimit>,
+    names: Vec<String>,
+    listener: String,
+    access_log: Option<Arc<AccessLog>>,
+}
+
+impl UdpPolicy for UdpUserPolicy {
+    fn associate(&self, user: usize) -> Option<FlowLimit> {
+        let limit = &self.limits[user];
+        if limit.is_blocked() {
+            None
+        } else {
+            Some(limit.clone())
+        }
+    }
+
+    fn user_name(&self, user: usize) -> Option<String> {
+        self.names.get(user).cloned()
+    }
+
+    fn finished(&self, a: UdpAssociation) {
+        if let Some(ref log) = self.access_log {
+            log.write(&access_log::udp_record(
+                self.listener.clone(),
+                self.names[a.user].clone(),
+                a.peer,
+                a.target.map(|t| t.to_string()).unwrap_or_default(),
+                a.resolved,
+                (a.upload, a.download),
+                a.duration,
+            ));
+        }
+    }
+}
+
+fn flow_limit(cfg: &Config, state: &ServerState, limiters: &Limiters, user: &str) -> FlowLimit {
+    let flow = limiters
+        .flow_limit(user)
+        .with_usage(state.stats.user(user))
+        .with_usage(state.stats.listener(&cfg.get_listen_ip_port()));
+    match state.quotas.usage(user) {
+        Some(usage) => flow.with_usage(usage),
+        None => flow,
+    }
+}
 
-pub async fn run_server(cfg: Arc<Config>) -> anyhow::Result<()> {
-    // run udp
-    let udp_socket = UdpSocket::bind(cfg.get_listen_ip_port()).await?;
-    info!("udp server listening on {}", cfg.get_listen_ip_port());
-    let cfg_for_udp = cfg.clone();
-    tokio::spawn(async move { run_udp(udp_socket, cfg_for_udp).await });
-
-    let mut tcp_listen_ip_port = cfg.get_listen_ip_port();
-    // check plugin
-    if let Some(plugin_cfg) = &cfg.plugin {
-        let p =
-            ss_light::plugin::Plugin::start(plugin_cfg, &cfg.bind_addr, &cfg.bind_port.to_string())
-                .expect("start plugin");
-        tcp_listen_ip_port = p.local_addr().to_string();
-        tokio::spawn(async move {
-            match p.join().await {
-                Ok(status) => {
-                    error!("plugin exited with status: {}", status);
+fn udp_users(cfg: &Config, state: &ServerState) -> UdpUsers {
+    let limiters = state.limiters();
+    let limits = cfg
+        .get_user_names()
+        .iter()
+        .map(|name| flow_limit(cfg, state, &limiters, name))
+        .collect();
+    UdpUsers {
+        cipher: Arc::new(cfg.get_packet_cipher()),
+        policy: Some(Arc::new(UdpUserPolicy {
+            limits,
+            names: cfg.get_user_names().to_vec(),
+            listener: cfg.get_listen_ip_port(),
+            access_log: state.access_log.clone(),
+        })),
+        outbound: Some(cfg.get_outbound().clone()),
+        timeout: cfg.get_timeout(),
+    }
+}
+
+async fn start_udp(
+    cfg: Arc<Config>,
+    addr: String,
+    socket: Option<std::net::UdpSocket>,
+    users: watch::Receiver<UdpUsers>,
+    shutdown: Arc<Shutdown>,
+) -> anyhow::Result<UdpListener> {
+    let udp_socket = match socket {
+        Some(socket) => {
+            socket.set_nonblocking(true)?;
+            UdpSocket::from_std(socket)?
+        }
+        None => UdpSocket::bind(&addr).await?,
+    };
+    info!("udp server listening on {}", addr);
+    let (retire_tx, retire_rx) = oneshot::channel::<()>();
+    let guard = shutdown.track();
+    let udp_server = ss_light::UdpServer::new_with_users(
+        udp_socket,
+        users,
+        cfg.get_udp_capacity(),
+        cfg.get_udp_expiry_time(),
+    );
+    let listen_ip_port = addr.clone();
+    tokio::spawn(async move {
+        let _guard = guard;
+        let stop = async {
+            tokio::select! {
+                _ = shutdown.stopped() => {}
+                _ = retire_rx => {}
+            }
+        };
+        let res = shutdown
+            .run_until_forced(udp_server.run_with_drain(stop))
+            .await;
+        if res.is_none() {
+            warn!("udp server force closed with active associations");
+        }
+        debug!("udp server on {} stopped", listen_ip_port);
+    });
+    Ok(UdpListener {
+        cfg,
+        addr,
+        _retire: retire_tx,
+    })
+}
+
+/// tls acceptor reloading certificates while the listener runs
+#[cfg(feature = "tls")]
+fn load_tls(cfg: &Config) -> anyhow::Result<Option<Arc<ss_light::tls::TlsAcceptor>>> {
+    let Some(ref tls_cfg) = cfg.tls else {
+        return Ok(None);
+    };
+    let tls = Arc::new(ss_light::tls::TlsAcceptor::new(tls_cfg).context("load tls certificates")?);
+    ss_light::tls::TlsAcceptor::watch(&tls);
+    info!("tls enabled with alpn {:?}", tls_cfg.alpn);
+    Ok(Some(tls))
+}
+
+/// where shadowsocks streams are accepted, a subprocess plugin may forward over a unix socket
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    async fn accept(&self) -> std::io::Result<(Accepted, SocketAddr)> {
+        match self {
+            Listener::Tcp(l) => l.accept().await.map(|(s, peer)| (Accepted::Tcp(s), peer)),
+            #[cfg(unix)]
+            Listener::Unix(l) => l
+                .accept()
+                .await
+                .map(|(s, _)| (Accepted::Unix(s), UNIX_PEER)),
+        }
+    }
+}
+
+enum Accepted {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+/// peer of connections over the unix socket of a plugin, which come from the plugin like its
+/// loopback tcp ones
+#[cfg(unix)]
+const UNIX_PEER: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+async fn start_tcp(
+    cfg: Arc<Config>,
+    cfg_rx: watch::Receiver<Arc<Config>>,
+    shutdown: Arc<Shutdown>,
+    state: Arc<ServerState>,
+) -> anyhow::Result<TcpListenerGroup> {
+    // check plugin, simple-obfs and v2ray-plugin websocket run in process
+    let mut plugin = None;
+    let mut udp_addr = None;
+    let mut udp_socket = None;
+    let mut unix_path = None;
+    let transport = Transport {
+        #[cfg(feature = "tls")]
+        tls: load_tls(&cfg)?,
+        builtin: cfg.plugin.as_ref().and_then(|p| p.builtin()).map(Arc::new),
+    };
+    let acceptors = cfg.tcp.acceptors.max(1);
+    let (listeners, listen_addr) = match (&transport.builtin, &cfg.plugin) {
+        (None, Some(plugin_cfg)) => {
+            if cfg.ban.threshold > 0 {
+                warn!(
+                    "auth failure bans are disabled behind plugin {}, every peer is the plugin",
+                    plugin_cfg.name
+                );
+            }
+            // bound and kept before the plugin starts, so the port can not be taken meanwhile
+            let local = LocalListeners::bind(plugin_cfg, &cfg.tcp, acceptors)
+                .context("bind plugin local address")?;
+            let local_addr = local.local_addr()?;
+            let listeners = match local {
+                LocalListeners::Tcp { listeners, udp } => {
+                    if udp.is_some() {
+                        udp_addr = Some(local_addr.to_string());
+                    }
+                    udp_socket = udp;
+                    listeners.into_iter().map(Listener::Tcp).collect()
                 }
-                Err(e) => {
-                    error!("plugin exited with error: {}", e);
+                #[cfg(unix)]
+                LocalListeners::Unix(listener) => vec![Listener::Unix(listener)],
+            };
+            if let LocalAddr::Unix(ref path) = local_addr {
+                unix_path = Some(path.clone());
+            }
+            let p = ss_light::plugin::Supervisor::start(
+                plugin_cfg,
+                &cfg.bind_addr,
+                &cfg.bind_port.to_string(),
+                local_addr.clone(),
+            )
+            .await
+            .context("start plugin")?;
+            plugin = Some(p);
+            (listeners, local_addr.to_string())
+        }
+        (builtin, plugin_cfg) => {
+            if let Some(b) = builtin {
+                info!("built-in plugin server with {}", b);
+                if plugin_cfg.as_ref().is_some_and(|p| p.plugin_mode.has_udp()) {
+                    warn!("built-in plugin is tcp only, udp is served on the listen address");
                 }
             }
-            std::process::exit(-1)
-        });
+            let tcp_listen_ip_port = cfg.get_listen_ip_port();
+            let listen_addr = lookup_host(&tcp_listen_ip_port)
+                .await?
+                .next()
+                .ok_or_else(|| {
+                    anyhow!("resolve tcp listen address {} empty", tcp_listen_ip_port)
+                })?;
+            let mut listeners = Vec::with_capacity(acceptors);
+            for _ in 0..acceptors {
+                listeners.push(Listener::Tcp(ss_light::net::bind_listener(
+                    listen_addr,
+                    &cfg.tcp,
+                    acceptors > 1,
+                )?));
+            }
+            (listeners, listen_addr.to_string())
+        }
+    };
+    info!(
+        "tcp server listening on {} with {} acceptors",
+        listen_addr,
+        listeners.len()
+    );
+
+    let (retire_tx, retire_rx) = oneshot::channel::<bool>();
+    let guard = shutdown.track();
+    let done = tokio::spawn(async move {
+        let _guard = guard;
+        // held by every connection of the group, recv returns None once all closed
+        let (conns, mut drained) = mpsc::channel::<()>(1);
+        let accept = future::select_all(listeners.into_iter().map(|l| {
+            Box::pin(run_tcp(
+                l,
+                transport.clone(),
+                cfg_rx.clone(),
+                shutdown.clone(),
+                state.clone(),
+                conns.clone(),
+            ))
+        }));
+        let plugin_exit = async {
+            match plugin {
+                Some(ref mut p) => p.supervise().await,
+                None => future::pending().await,
+            }
+        };
+        let (res, keep_plugin) = tokio::select! {
+            (res, ..) = accept => (res, false),
+            e = plugin_exit => (Err(anyhow!("plugin stopped: {}", e)), false),
+            _ = shutdown.stopped() => (Ok(()), true),
+            keep = retire_rx => (Ok(()), keep.unwrap_or(true)),
+        };
+        debug!("tcp server on {} stopped", listen_addr);
+        if let Some(path) = unix_path {
+            let _ = std::fs::remove_file(path);
+        }
+        drop(conns);
+        match plugin {
+            // connections through the plugin finish, or are force closed by shutdown
+            Some(p) if keep_plugin => {
+                let guard = shutdown.track();
+                tokio::spawn(async move {
+                    let _guard = guard;
+                    let _ = drained.recv().await;
+                    stop_plugin(p).await;
+                });
+            }
+            Some(p) => stop_plugin(p).await,
+            None => {}
+        }
+        res
+    });
+
+    Ok(TcpListenerGroup {
+        cfg,
+        udp_addr,
+        udp_socket,
+        retire: retire_tx,
+        done,
+    })
+}
+
+async fn stop_plugin(mut plugin: ss_light::plugin::Supervisor) {
+    if let Err(e) = plugin.stop().await {
+        error!("stop plugin error: {}", e);
     }
+}
 
-    // run tcp
-    let listener = TcpListener::bind(&tcp_listen_ip_port).await?;
-    info!("tcp server listening on {}", tcp_listen_ip_port);
+async fn run_tcp(
+    listener: Listener,
+    transport: Transport,
+    cfg_rx: watch::Receiver<Arc<Config>>,
+    shutdown: Arc<Shutdown>,
+    state: Arc<ServerState>,
+    group: mpsc::Sender<()>,
+) -> anyhow::Result<()> {
     loop {
         let (socket, peer) = listener.accept().await?;
         trace!("new connetion from {}", peer.to_string());
-        let cfg = cfg.clone();
-        tokio::spawn(async move { process(socket, peer, cfg).await });
+        let cfg = cfg_rx.borrow().clone();
+        let conn = match state.limiters().global.try_connect() {
+            Some(conn) => conn,
+            None => {
+                debug!(
+                    "proxy peer tcp:{} refused, over server connection limit",
+                    peer
+                );
+                continue;
+            }
+        };
+        let transport = transport.clone();
+        let state = state.clone();
+        let conn = (conn, group.clone());
+        match socket {
+            Accepted::Tcp(socket) => {
+                if let Err(e) = cfg.tcp.apply_to_stream(&socket) {
+                    warn!("set socket options for peer tcp:{} error: {}", peer, e);
+                }
+                spawn_process(socket, peer, transport, cfg, state, conn, &shutdown);
+            }
+            #[cfg(unix)]
+            Accepted::Unix(socket) => {
+                spawn_process(socket, peer, transport, cfg, state, conn, &shutdown)
+            }
+        }
     }
 }
 
-async fn process(socket: TcpStream, peer: SocketAddr, cfg: Arc<Config>) {
-    let mut ss = ss_light::crypto::Stream::new_from_stream(socket, cfg.get_method(), cfg.get_key());
+/// serve a connection, its listener group is kept by the sender in `conn` until it ends
+fn spawn_process<S: ProbeStream + Send + 'static>(
+    socket: S,
+    peer: SocketAddr,
+    transport: Transport,
+    cfg: Arc<Config>,
+    state: Arc<ServerState>,
+    (conn, group): (ConnectionGuard, mpsc::Sender<()>),
+    shutdown: &Arc<Shutdown>,
+) {
+    let guard = shutdown.track();
+    let shutdown = shutdown.clone();
+    tokio::spawn(async move {
+        let _guard = (guard, group);
+        let span = info_span!(
+            "tcp",
+            %peer,
+            user = field::Empty,
+            target = field::Empty,
+            resolve_ms = field::Empty,
+            connect_ms = field::Empty,
+            upload = field::Empty,
+            download = field::Empty,
+            close_reason = field::Empty,
+            error = field::Empty,
+        );
+        if shutdown
+            .run_until_forced(process(socket, peer, transport, cfg, state, conn).instrument(span))
+            .await
+            .is_none()
+        {
+            debug!("proxy peer tcp:{} force closed by shutdown", peer);
+        }
+    });
+}
 
-    let target_addr = match ss_light::Address::read_from(&mut ss).await {
-        Ok(addr) => addr,
-        Err(ss_light::Error::IoError(ref err)) if err.kind() == ErrorKind::UnexpectedEof => {
-            debug!("proxy peer tcp:{}, read target addr: unexpected eof", peer);
-            return;
+/// peers forwarded by a subprocess plugin all have its address, so none is banned
+fn behind_plugin(cfg: &Config) -> bool {
+    cfg.plugin.as_ref().is_some_and(|p| p.builtin().is_none())
+}
+
+fn record_auth_failure(state: &ServerState, cfg: &Config, peer: SocketAddr) {
+    if behind_plugin(cfg) {
+        return;
+    }
+    if let Some(ban) = state.auth_failures.record_failure(peer.ip(), &cfg.ban) {
+        // stable format for fail2ban: `auth failure ban: source <HOST>`
+        warn!(
+            "auth failure ban: source {} for {}s after {} failures",
+            ban.source,
+            ban.remaining.as_secs(),
+            ban.failures
+        );
+    }
+}
+
+async fn process<S: ProbeStream + Send + 'static>(
+    socket: S,
+    peer: SocketAddr,
+    transport: Transport,
+    cfg: Arc<Config>,
+    state: Arc<ServerState>,
+    conn: ConnectionGuard,
+) {
+    let start = Instant::now();
+    if !behind_plugin(&cfg) && state.auth_failures.is_banned(peer.ip()) {
+        trace!("proxy peer tcp:{} is banned, {:?}", peer, cfg.ban.action);
+        if cfg.ban.action == BanAction::ProbeDefense {
+            let res = cfg
+                .probe_defense
+                .respond(socket, &[], cfg.get_timeout(), &cfg.relay)
+                .await;
+            trace!("probe defense peer: {}, closing with {:?}", peer, res);
+        }
+        return;
+    }
+
+    #[cfg(feature = "tls")]
+    if let Some(ref tls) = transport.tls {
+        let socket = match tls.accept(socket, cfg.get_handshake_timeout()).await {
+            Ok(s) => s,
+            Err(AcceptError {
+                stream,
+                received,
+                error,
+            }) if error.kind() == ErrorKind::TimedOut => {
+                debug!(
+                    "proxy peer tcp:{}, tls handshake timeout, respond with {:?}",
+                    peer, cfg.probe_defense
+                );
+                let res = cfg
+                    .probe_defense
+                    .respond(stream, &received, cfg.get_timeout(), &cfg.relay)
+                    .await;
+                trace!("probe defense peer: {}, closing with {:?}", peer, res);
+                return;
+            }
+            Err(e) => {
+                debug!("proxy peer tcp:{}, tls handshake error: {}", peer, e.error);
+                return;
+            }
+        };
+        return process_stream(socket, start, peer, transport.builtin, cfg, state, conn).await;
+    }
+    process_stream(socket, start, peer, transport.builtin, cfg, state, conn).await
+}
+
+/// the shadowsocks stream from a socket, or from tls over it
+async fn process_stream<S: ProbeStream + Send + 'static>(
+    socket: S,
+    start: Instant,
+    peer: SocketAddr,
+    builtin: Option<Arc<Builtin>>,
+    cfg: Arc<Config>,
+    state: Arc<ServerState>,
+    _conn: ConnectionGuard,
+) {
+    let socket = match builtin.as_deref() {
+        Some(builtin) => {
+            match PluginStream::accept(socket, builtin, cfg.get_handshake_timeout()).await {
+                Ok(s) => s,
+                Err(AcceptError {
+                    stream,
+                    received,
+                    error,
+                }) => {
+                    match error.kind() {
+                        ErrorKind::UnexpectedEof => {
+                            debug!("proxy peer tcp:{}, plugin handshake: unexpected eof", peer);
+                            return;
+                        }
+                        ErrorKind::TimedOut => debug!(
+                            "proxy peer tcp:{}, plugin handshake timeout, respond with {:?}",
+                            peer, cfg.probe_defense
+                        ),
+                        _ => {
+                            warn!(
+                                "proxy peer tcp:{}, plugin handshake error: {}, respond with {:?}",
+                                peer, error, cfg.probe_defense
+                            );
+                            record_auth_failure(&state, &cfg, peer);
+                        }
+                    }
+                    let res = cfg
+                        .probe_defense
+                        .respond(stream, &received, cfg.get_timeout(), &cfg.relay)
+                        .await;
+                    trace!("probe defense peer: {}, closing with {:?}", peer, res);
+                    return;
+                }
+            }
+        }
+        None => PluginStream::Plain(socket),
+    };
+
+    let mut ss = ss_light::crypto::Stream::new_from_stream_with_keys(
+        socket,
+        cfg.get_method(),
+        cfg.get_keys(),
+    );
+
+    let target_addr =
+        match ss_light::Address::read_from_with_timeout(&mut ss, cfg.get_handshake_timeout()).await
+        {
+            Ok(addr) => addr,
+            Err(ss_light::Error::IoError(ref err)) if err.kind() == ErrorKind::UnexpectedEof => {
+                debug!("proxy peer tcp:{}, read target addr: unexpected eof", peer);
+                return;
+            }
+            Err(ss_light::Error::HandshakeTimeout) if ss.user().is_some() => {
+                debug!(
+                    "proxy peer tcp:{}, read target addr: handshake timeout",
+                    peer
+                );
+                return;
+            }
+            Err(e) => {
+                if matches!(e, ss_light::Error::HandshakeTimeout) {
+                    // shorter than salt, length and tag, like a plaintext probe
+                    debug!(
+                        "proxy peer tcp:{}, read target addr: handshake timeout, respond with {:?}",
+                        peer, cfg.probe_defense
+                    );
+                } else {
+                    warn!(
+                        "proxy peer tcp:{}, reading target addr error: {}, respond with {:?}",
+                        peer, e, cfg.probe_defense
+                    );
+                    if let Some(Builtin::WebSocket(_)) = builtin.as_deref() {
+                        debug!("v2ray-plugin clients have to set mux=0 for the built-in websocket");
+                    }
+                    record_auth_failure(&state, &cfg, peer);
+                }
+                let (socket, replay) = ss.into_inner_with_replay();
+                // behind a built-in plugin the raw bytes are what a prober sent
+                let (socket, raw) = socket.into_inner();
+                let received = raw.unwrap_or(replay);
+                let res = cfg
+                    .probe_defense
+                    .respond(socket, &received, cfg.get_timeout(), &cfg.relay)
+                    .await;
+                trace!("probe defense peer: {}, closing with {:?}", peer, res);
+                return;
+            }
+        };
+
+    ss.get_mut().stop_recording();
+    let user = cfg.get_user_name(ss.user().expect("user identified after reading"));
+    Span::current()
+        .record("user", user)
+        .record("target", field::display(&target_addr));
+    trace!(
+        "proxy peer tcp:{}, user {}, read target_addr {}",
+        peer,
+        user,
+        target_addr
+    );
+
+    // logged and recorded on the span when dropped, on every way out from here
+    let mut access = TcpAccess::new(
+        state.access_log.clone(),
+        start,
+        cfg.get_listen_ip_port(),
+        user.to_string(),
+        peer,
+        target_addr.to_string(),
+    );
+
+    let limiters = state.limiters();
+    let flow = flow_limit(&cfg, &state, &limiters, user).with_usage(access.usage.clone());
+    if flow.is_blocked() {
+        debug!(
+            "proxy peer tcp:{} refused, user {} over quota or expired",
+            peer, user
+        );
+        access.close("blocked", None);
+        return;
+    }
+
+    // streams of a carrier are the connections of the user, not the carrier
+    if cfg.mux.enabled && mux::is_mux_address(&target_addr) {
+        debug!("established mux carrier from {}, user {}", peer, user);
+        let ss = RateLimited::from_flow(ss, flow.clone());
+        tokio::select! {
+            _ = serve_mux(ss, peer, user, cfg.clone(), state.clone()) => access.close("eof", None),
+            _ = flow.blocked() => {
+                debug!("close mux carrier from {}, user {} over quota or expired", peer, user);
+                access.close("blocked", None);
+            }
         }
-        Err(e) => {
-            // Defense active detection attack, https://gfw.report/talks/imc20/zh/
-            warn!("proxy peer tcp:{}, reading target addr error: {}, read forever to defense active detection attack", peer, e);
-            let res = ss_light::util::read_forever(&mut ss.into_inner()).await;
-            trace!("read forever peer: {}, closing with {:?}", peer, res);
+        return;
+    }
+
+    let _user_conn = match limiters.user(user).map(|l| l.try_connect()) {
+        Some(None) => {
+            debug!(
+                "proxy peer tcp:{} refused, user {} over connection limit",
+                peer, user
+            );
+            access.close("connection limit", None);
             return;
         }
+        Some(conn) => conn,
+        None => None,
     };
 
-    trace!("proxy peer tcp:{}, read target_addr {}", peer, target_addr);
+    // payload decrypted along with the target addr, with fast open it is sent in the SYN
+    let mut early_data = Vec::new();
+    if cfg.tcp.fast_open {
+        let mut buf = vec![0u8; cfg.get_method().max_package_size()];
+        match ss.read(&mut buf).now_or_never() {
+            Some(Ok(n)) => {
+                buf.truncate(n);
+                early_data = buf;
+            }
+            Some(Err(e)) => {
+                warn!("proxy peer tcp:{}, read early data error: {}", peer, e);
+                access.close("error", Some(e.to_string()));
+                return;
+            }
+            None => {}
+        }
+    }
+    // sent with the connect, so counted like relayed bytes before it
+    if !early_data.is_empty() && !flow.upload_packet(early_data.len()).await {
+        access.close("blocked", None);
+        return;
+    }
 
-    let mut target = match time::timeout(cfg.get_timeout(), target_addr.connect()).await {
+    let connect_start = Instant::now();
+    let connect = cfg.get_outbound().connect(&target_addr, &early_data);
+    let mut target = match time::timeout(cfg.get_timeout(), connect).await {
         Ok(ok) => match ok {
             Ok(s) => s,
             Err(e) => {
@@ -74,6 +832,7 @@ async fn process(socket: TcpStream, peer: SocketAddr, cfg: Arc<Config>) {
                     "proxy peer tcp:{}, connect target {} error: {}",
                     peer, target_addr, e
                 );
+                access.close("connect error", Some(e.to_string()));
                 return;
             }
         },
@@ -82,32 +841,254 @@ async fn process(socket: TcpStream, peer: SocketAddr, cfg: Arc<Config>) {
                 "proxy peer tcp:{}, connect target {} timeout",
                 peer, target_addr
             );
+            access.close("connect timeout", None);
             return;
         }
     };
+    Span::current().record("connect_ms", connect_start.elapsed().as_millis() as u64);
+    access.resolved = target.peer_addr().ok();
 
-    debug!("established new tcp proxy {} <-> {}", peer, target_addr);
-    let (a2b, b2a) = match tokio::io::copy_bidirectional(&mut ss, &mut target).await {
-        Ok(result) => result,
-        Err(e) => {
-            warn!("interrupt tcp proxy {} <-> {}: {}", peer, target_addr, e);
+    let via = cfg
+        .get_outbound()
+        .select(&target_addr)
+        .map_or(ss_light::outbound::DIRECT, |u| u.name());
+    debug!(
+        "established new tcp proxy {} <-> {} via {}, user {}",
+        peer, target_addr, via, user
+    );
+    let mut ss = RateLimited::from_flow(ss, flow.clone());
+    let result = tokio::select! {
+        result = ss_light::relay::relay(&mut ss, &mut target, &cfg.relay) => result,
+        _ = flow.blocked() => {
+            debug!("close tcp proxy {} <-> {}, user {} over quota or expired", peer, target_addr, user);
+            access.close("blocked", None);
             return;
         }
     };
+    if let CloseReason::Error(ref e) = result.reason {
+        warn!("interrupt tcp proxy {} <-> {}: {}", peer, target_addr, e);
+        access.close("error", Some(e.to_string()));
+        return;
+    }
+    access.close(&result.reason.to_string(), None);
     debug!(
-        "complete tcp proxy {} <-> {}, L2R {} bytes, R2L {} bytes",
-        peer, target_addr, a2b, b2a
+        "complete tcp proxy {} <-> {}, L2R {} bytes, R2L {} bytes, close with {}",
+        peer,
+        target_addr,
+        result.a2b + early_data.len() as u64,
+        result.b2a,
+        result.reason
     );
 }
 
-async fn run_udp(socket: UdpSocket, cfg: Arc<Config>) {
-    let udp_server = ss_light::UdpServer::new(
-        socket,
-        cfg.get_method(),
-        cfg.get_key(),
-        cfg.get_udp_capacity(),
-        cfg.get_udp_expiry_time(),
+/// streams of a mux carrier, each limited and logged like a connection and relayed to its
+/// own target until the carrier closes
+async fn serve_mux<S>(
+    carrier: S,
+    peer: SocketAddr,
+    user: &str,
+    cfg: Arc<Config>,
+    state: Arc<ServerState>,
+) where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let mut mux = MuxServer::new(carrier, cfg.mux.max_streams);
+    let mut relays = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = mux.accept() => match accepted {
+                Some((target, stream)) => {
+                    let span = info_span!(
+                        "mux",
+                        id = stream.id(),
+                        target = %target,
+                        connect_ms = field::Empty,
+                        upload = field::Empty,
+                        download = field::Empty,
+                        close_reason = field::Empty,
+                        error = field::Empty,
+                    );
+                    let relay = relay_mux_stream(stream, target, peer, user.into(), cfg.clone(), state.clone());
+                    relays.spawn(relay.instrument(span));
+                }
+                None => break,
+            },
+            Some(_) = relays.join_next() => {}
+        }
+    }
+    // streams are reset with the carrier gone
+    while relays.join_next().await.is_some() {}
+    debug!("mux carrier from {} closed", peer);
+}
+
+async fn relay_mux_stream(
+    stream: MuxStream,
+    target_addr: ss_light::Address,
+    peer: SocketAddr,
+    user: String,
+    cfg: Arc<Config>,
+    state: Arc<ServerState>,
+) {
+    let id = stream.id();
+    let mut access = TcpAccess::new(
+        state.access_log.clone(),
+        Instant::now(),
+        cfg.get_listen_ip_port(),
+        user,
+        peer,
+        target_addr.to_string(),
     );
+    // dropping a refused stream resets it
+    let limiters = state.limiters();
+    let _conn = match (
+        limiters.global.try_connect(),
+        limiters.user(&access.user).map(|l| l.try_connect()),
+    ) {
+        (Some(global), None) => (global, None),
+        (Some(global), Some(Some(user))) => (global, Some(user)),
+        _ => {
+            debug!(
+                "proxy peer tcp:{} mux stream {} refused, over connection limit",
+                peer, id
+            );
+            access.close("connection limit", None);
+            return;
+        }
+    };
 
-    udp_server.run().await
+    let connect_start = Instant::now();
+    let mut target = match time::timeout(
+        cfg.get_timeout(),
+        cfg.get_outbound().connect(&target_addr, &[]),
+    )
+    .await
+    {
+        Ok(Ok(s)) => s,
+        Ok(Err(e)) => {
+            error!(
+                "proxy peer tcp:{} mux stream {}, connect target {} error: {}",
+                peer, id, target_addr, e
+            );
+            access.close("connect error", Some(e.to_string()));
+            return;
+        }
+        Err(_) => {
+            debug!(
+                "proxy peer tcp:{} mux stream {}, connect target {} timeout",
+                peer, id, target_addr
+            );
+            access.close("connect timeout", None);
+            return;
+        }
+    };
+    Span::current().record("connect_ms", connect_start.elapsed().as_millis() as u64);
+    access.resolved = target.peer_addr().ok();
+    debug!("established mux stream {} {} <-> {}", id, peer, target_addr);
+
+    // limits, stats and quota are counted by the carrier, only bytes of the record here
+    let mut stream =
+        RateLimited::from_flow(stream, FlowLimit::new(&[]).with_usage(access.usage.clone()));
+    let result = ss_light::relay::relay(&mut stream, &mut target, &cfg.relay).await;
+    match result.reason {
+        CloseReason::Error(ref e) => access.close("error", Some(e.to_string())),
+        ref reason => access.close(&reason.to_string(), None),
+    }
+    debug!(
+        "complete mux stream {} {} <-> {}, L2R {} bytes, R2L {} bytes, close with {}",
+        id, peer, target_addr, result.a2b, result.b2a, result.reason
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    fn config(extra: &str) -> Arc<Config> {
+        Arc::new(Config::for_test(extra).unwrap())
+    }
+
+    /// a client connected to a socket served by `process`
+    async fn serve(cfg: Arc<Config>, state: Arc<ServerState>) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, peer) = listener.accept().await.unwrap();
+        let transport = Transport {
+            #[cfg(feature = "tls")]
+            tls: None,
+            builtin: None,
+        };
+        let conn = state.limiters().global.try_connect().unwrap();
+        tokio::spawn(process(socket, peer, transport, cfg, state, conn));
+        client
+    }
+
+    #[tokio::test]
+    async fn test_short_probe_fallback() {
+        let fallback = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let cfg = config(&format!(
+            "handshake_timeout = 100\n[probe_defense]\nstrategy = \"fallback\"\naddr = \"{}\"",
+            fallback.local_addr().unwrap()
+        ));
+        let state = ServerState::new(&cfg).unwrap();
+        let mut client = serve(cfg, state).await;
+
+        // shorter than salt, length and tag, so it times out instead of failing to decrypt
+        let probe = b"GET / HTTP/1.1\r\n\r\n";
+        client.write_all(probe).await.unwrap();
+        let (mut server, _) = time::timeout(Duration::from_secs(3), fallback.accept())
+            .await
+            .expect("probe replayed to fallback")
+            .unwrap();
+        let mut buf = vec![0u8; probe.len()];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, probe);
+
+        server.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await.unwrap();
+        let mut resp = [0u8; 15];
+        client.read_exact(&mut resp).await.unwrap();
+        assert_eq!(&resp, b"HTTP/1.1 200 OK");
+    }
+
+    #[tokio::test]
+    async fn test_loopback_not_banned() {
+        let fallback = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let cfg = config(&format!(
+            "[probe_defense]\nstrategy = \"fallback\"\naddr = \"{}\"\n[ban]\nthreshold = 1",
+            fallback.local_addr().unwrap()
+        ));
+        let state = ServerState::new(&cfg).unwrap();
+
+        // every failure is from loopback, like peers forwarded by a plugin
+        let probe = [7u8; 64];
+        for _ in 0..3 {
+            let mut client = serve(cfg.clone(), state.clone()).await;
+            client.write_all(&probe).await.unwrap();
+            let (mut server, _) = time::timeout(Duration::from_secs(3), fallback.accept())
+                .await
+                .expect("probe replayed to fallback")
+                .unwrap();
+            let mut buf = [0u8; 50];
+            server.read_exact(&mut buf).await.unwrap();
+        }
+        assert!(state.auth_failures.bans().is_empty());
+    }
+
+    #[test]
+    fn test_behind_plugin() {
+        let plugin = |name: &str, opts: &str| {
+            config(&format!(
+                "[plugin]\nname = \"{}\"\nopts = \"{}\"\nargs = []",
+                name, opts
+            ))
+        };
+        assert!(behind_plugin(&plugin("v2ray-plugin", "server;mode=quic")));
+        assert!(!behind_plugin(&plugin("obfs-server", "obfs=http")));
+        assert!(!behind_plugin(&config("")));
+    }
 }
@@ -0,0 +1,86 @@
+use std::{future::Future, sync::Arc, time::Duration};
+
+use tokio::{sync::watch, time};
+
+/// graceful shutdown, tracked tasks get a drain deadline then the rest are force closed
+pub struct Shutdown {
+    stop: watch::Sender<bool>,
+    force: watch::Sender<bool>,
+    active: watch::Sender<usize>,
+    force_closed: watch::Sender<usize>,
+}
+
+/// keep the task counted as active until dropped
+pub struct Guard(Arc<Shutdown>);
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        self.0.active.send_modify(|n| *n -= 1);
+    }
+}
+
+async fn wait_true(tx: &watch::Sender<bool>) {
+    let mut rx = tx.subscribe();
+    while !*rx.borrow_and_update() {
+        if rx.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Arc<Shutdown> {
+        Arc::new(Shutdown {
+            stop: watch::channel(false).0,
+            force: watch::channel(false).0,
+            active: watch::channel(0).0,
+            force_closed: watch::channel(0).0,
+        })
+    }
+
+    pub fn track(self: &Arc<Self>) -> Guard {
+        self.active.send_modify(|n| *n += 1);
+        Guard(self.clone())
+    }
+
+    /// resolves when shutdown begins
+    pub async fn stopped(&self) {
+        wait_true(&self.stop).await
+    }
+
+    /// run fut until it completes or the drain deadline is reached, counted as a force
+    /// closed task if reached
+    pub async fn run_until_forced<F: Future>(&self, fut: F) -> Option<F::Output> {
+        tokio::select! {
+            output = fut => Some(output),
+            _ = wait_true(&self.force) => {
+                self.force_closed.send_modify(|n| *n += 1);
+                None
+            }
+        }
+    }
+
+    async fn drained(&self) {
+        let mut rx = self.active.subscribe();
+        while *rx.borrow_and_update() != 0 {
+            if rx.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    pub fn active(&self) -> usize {
+        *self.active.borrow()
+    }
+
+    /// notify tracked tasks to stop, wait them at most `drain` then force close the rest,
+    /// returns number of force-closed tasks
+    pub async fn shutdown(&self, drain: Duration) -> usize {
+        self.stop.send_replace(true);
+        if time::timeout(drain, self.drained()).await.is_err() {
+            self.force.send_replace(true);
+            self.drained().await;
+        }
+        *self.force_closed.borrow()
+    }
+}
@@ -0,0 +1,186 @@
+use std::{
+    collections::HashMap,
+    fs,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Context;
+use serde::Serialize;
+
+use time::OffsetDateTime;
+use tokio::{sync::watch, time as tokio_time};
+use tracing::error;
+use tracing_appender::non_blocking::WorkerGuard;
+
+use ss_light::{
+    defense::AuthFailures,
+    limit::{FlowLimit, LimitConfig, Limiter},
+};
+
+use crate::{access_log::AccessLog, config::Config, quota::Quotas, stats::Stats};
+
+/// state shared by listeners and the manager, kept across config reloads
+pub struct ServerState {
+    pub auth_failures: AuthFailures,
+    limiters: Mutex<Arc<Limiters>>,
+    pub quotas: Quotas,
+    pub stats: Stats,
+    pub access_log: Option<Arc<AccessLog>>,
+    /// flushes the access log when dropped
+    access_log_guard: Mutex<Option<WorkerGuard>>,
+}
+
+/// write to a temp file then rename, so the file is never half written
+pub fn save_json<T: Serialize>(path: &str, value: &T) -> anyhow::Result<()> {
+    let tmp = format!("{}.tmp", path);
+    fs::write(&tmp, serde_json::to_vec_pretty(value)?).with_context(|| format!("write {}", tmp))?;
+    fs::rename(&tmp, path).with_context(|| format!("rename {} to {}", tmp, path))?;
+    Ok(())
+}
+
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// limiters of the whole server and of users by name, the server has a single listener so
+/// the global limiter is also the listener one
+pub struct Limiters {
+    pub global: Arc<Limiter>,
+    users: HashMap<String, Arc<Limiter>>,
+}
+
+impl Default for Limiters {
+    fn default() -> Self {
+        Limiters {
+            global: Arc::new(Limiter::new(&LimitConfig::default())),
+            users: HashMap::new(),
+        }
+    }
+}
+
+impl Limiters {
+    pub fn user(&self, name: &str) -> Option<&Arc<Limiter>> {
+        self.users.get(name)
+    }
+
+    /// bandwidth limit of a flow of user
+    pub fn flow_limit(&self, name: &str) -> FlowLimit {
+        match self.user(name) {
+            Some(user) => FlowLimit::new(&[&self.global, user]),
+            None => FlowLimit::new(&[&self.global]),
+        }
+    }
+}
+
+/// keep the limiter if config unchanged, so its buckets stay shared by existing connections
+fn update_limiter(old: Option<&Arc<Limiter>>, cfg: &LimitConfig) -> Arc<Limiter> {
+    match old {
+        Some(l) if l.config() == cfg => l.clone(),
+        Some(l) => Arc::new(l.with_config(cfg)),
+        None => Arc::new(Limiter::new(cfg)),
+    }
+}
+
+impl ServerState {
+    pub fn new(cfg: &Config) -> anyhow::Result<Arc<Self>> {
+        let quotas = match cfg.quota_state_file {
+            Some(ref path) => Quotas::load(path)?,
+            None => Quotas::default(),
+        };
+        let stats = match cfg.stats_file {
+            Some(ref path) => Stats::load(path)?,
+            None => Stats::default(),
+        };
+        let (access_log, access_log_guard) = match cfg.access_log {
+            Some(ref c) => {
+                let (log, guard) = AccessLog::new(c)?;
+                (Some(Arc::new(log)), Some(guard))
+            }
+            None => (None, None),
+        };
+        let state = ServerState {
+            auth_failures: AuthFailures::new(),
+            limiters: Mutex::default(),
+            quotas,
+            stats,
+            access_log,
+            access_log_guard: Mutex::new(access_log_guard),
+        };
+        state.update(cfg);
+        Ok(Arc::new(state))
+    }
+
+    /// apply limits and quotas of a new config
+    pub fn update(&self, cfg: &Config) {
+        self.update_limiters(cfg);
+        self.quotas.update(cfg, OffsetDateTime::now_utc());
+    }
+
+    /// save quota usage and stats
+    pub fn save(&self, cfg: &Config) {
+        if let Some(ref path) = cfg.quota_state_file {
+            if let Err(e) = self.quotas.save(path) {
+                error!("save quota state error: {:#}", e);
+            }
+        }
+        if let Some(ref path) = cfg.stats_file {
+            if let Err(e) = self.stats.save(path) {
+                error!("save stats error: {:#}", e);
+            }
+        }
+    }
+
+    /// write out pending access records, later records are dropped
+    pub fn flush_access_log(&self) {
+        self.access_log_guard.lock().unwrap().take();
+    }
+
+    pub fn limiters(&self) -> Arc<Limiters> {
+        self.limiters.lock().unwrap().clone()
+    }
+
+    fn update_limiters(&self, cfg: &Config) {
+        let mut limiters = self.limiters.lock().unwrap();
+        let users = cfg
+            .users
+            .iter()
+            .map(|u| {
+                (
+                    u.name.clone(),
+                    update_limiter(limiters.users.get(&u.name), &u.limit),
+                )
+            })
+            .collect();
+        *limiters = Arc::new(Limiters {
+            global: update_limiter(Some(&limiters.global), &cfg.limit),
+            users,
+        });
+    }
+
+    /// periodically drop expired entries
+    pub async fn purge(self: Arc<Self>, cfg_rx: watch::Receiver<Arc<Config>>) {
+        let mut interval = tokio_time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            let cfg = cfg_rx.borrow().clone();
+            self.auth_failures.purge(&cfg.ban);
+        }
+    }
+
+    /// check quota periods and expiry every second, and save usage and stats periodically
+    pub async fn maintain(self: Arc<Self>, cfg_rx: watch::Receiver<Arc<Config>>) {
+        let mut check = tokio_time::interval(Duration::from_secs(1));
+        let mut save = tokio_time::interval(SAVE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = check.tick() => {
+                    let cfg = cfg_rx.borrow().clone();
+                    self.quotas.update(&cfg, OffsetDateTime::now_utc());
+                }
+                _ = save.tick() => {
+                    let cfg = cfg_rx.borrow().clone();
+                    self.save(&cfg);
+                }
+            }
+        }
+    }
+}
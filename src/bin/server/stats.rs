@@ -0,0 +1,116 @@
+//! traffic totals of users and listeners, saved to `stats_file` and kept across restarts
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use ss_light::limit::Usage;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::state::save_json;
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct Traffic {
+    pub upload: u64,
+    pub download: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SavedStats {
+    /// unix timestamp
+    pub saved_at: i64,
+    pub users: BTreeMap<String, Traffic>,
+    pub listeners: BTreeMap<String, Traffic>,
+}
+
+impl SavedStats {
+    /// no file means nothing counted yet
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(s) => serde_json::from_str(&s).with_context(|| format!("parse stats {}", path)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("read stats {}", path)),
+        }
+    }
+
+    /// print as the `stats` subcommand output
+    pub fn print(&self) {
+        let saved_at = OffsetDateTime::from_unix_timestamp(self.saved_at)
+            .ok()
+            .and_then(|t| t.format(&Rfc3339).ok())
+            .unwrap_or_default();
+        println!("saved at {}", saved_at);
+        for (title, traffic) in [("user", &self.users), ("listener", &self.listeners)] {
+            println!();
+            println!("{:<24} {:>16} {:>16}", title, "upload", "download");
+            for (name, t) in traffic {
+                println!("{:<24} {:>16} {:>16}", name, t.upload, t.download);
+            }
+        }
+    }
+}
+
+/// live counters, totals only grow, users and listeners removed from config keep theirs
+#[derive(Default)]
+pub struct Stats {
+    users: Mutex<HashMap<String, Arc<Usage>>>,
+    listeners: Mutex<HashMap<String, Arc<Usage>>>,
+}
+
+fn counters(saved: BTreeMap<String, Traffic>) -> Mutex<HashMap<String, Arc<Usage>>> {
+    Mutex::new(
+        saved
+            .into_iter()
+            .map(|(name, t)| (name, Arc::new(Usage::new(t.upload, t.download))))
+            .collect(),
+    )
+}
+
+fn snapshot(counters: &Mutex<HashMap<String, Arc<Usage>>>) -> BTreeMap<String, Traffic> {
+    counters
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, u)| {
+            (
+                name.clone(),
+                Traffic {
+                    upload: u.upload(),
+                    download: u.download(),
+                },
+            )
+        })
+        .collect()
+}
+
+impl Stats {
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let saved = SavedStats::load(path)?;
+        Ok(Stats {
+            users: counters(saved.users),
+            listeners: counters(saved.listeners),
+        })
+    }
+
+    pub fn save(&self, path: &str) -> anyhow::Result<()> {
+        let saved = SavedStats {
+            saved_at: OffsetDateTime::now_utc().unix_timestamp(),
+            users: snapshot(&self.users),
+            listeners: snapshot(&self.listeners),
+        };
+        save_json(path, &saved)
+    }
+
+    pub fn user(&self, name: &str) -> Arc<Usage> {
+        let mut users = self.users.lock().unwrap();
+        users.entry(name.to_string()).or_default().clone()
+    }
+
+    pub fn listener(&self, addr: &str) -> Arc<Usage> {
+        let mut listeners = self.listeners.lock().unwrap();
+        listeners.entry(addr.to_string()).or_default().clone()
+    }
+}
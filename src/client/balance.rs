@@ -0,0 +1,472 @@
+//! spread connections over several servers: round robin, least latency or consistent hash of
+//! the target. servers failing a connect or a health probe are marked down for a while, and
+//! connects fail over to the next server.
+use std::{
+    cmp::Reverse,
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+use futures::future;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time,
+};
+use tracing::{debug, info, warn};
+
+use super::{Connector, ServerConfig};
+use crate::{plugin::Supervisor, Address, Stream};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    #[default]
+    RoundRobin,
+    /// lowest latency of the last health probe first, unprobed servers last
+    LeastLatency,
+    /// the same target goes to the same server while it is up
+    ConsistentHash,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct BalanceConfig {
+    pub strategy: Strategy,
+    /// `host:port` requested by health probes, empty means no probes
+    pub probe_addr: String,
+    /// sent to the probe address, a probe passes when the response starts in time. empty for
+    /// targets which speak first
+    pub probe_data: String,
+    /// sec, between health probes
+    pub probe_interval: u64,
+    /// ms, of health probes and connects to servers
+    pub timeout: u64,
+    /// sec, a failed server is skipped for
+    pub down_time: u64,
+}
+
+impl Default for BalanceConfig {
+    fn default() -> Self {
+        BalanceConfig {
+            strategy: Strategy::default(),
+            probe_addr: String::new(),
+            probe_data: String::new(),
+            probe_interval: 30,
+            timeout: 5000,
+            down_time: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerStatus {
+    pub addr: String,
+    pub up: bool,
+    /// of the last passed health probe
+    pub latency: Option<Duration>,
+}
+
+#[derive(Default)]
+struct Health {
+    down_until: Option<Instant>,
+    latency: Option<Duration>,
+}
+
+struct Server {
+    /// configured address, the connector may point at a client plugin
+    addr: String,
+    connector: Connector,
+    health: Mutex<Health>,
+}
+
+impl Server {
+    fn health(&self) -> std::sync::MutexGuard<'_, Health> {
+        self.health.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn is_up(&self) -> bool {
+        self.health()
+            .down_until
+            .is_none_or(|until| Instant::now() >= until)
+    }
+
+    /// return whether it was up
+    fn mark_down(&self, down_time: Duration) -> bool {
+        let up = self.is_up();
+        self.health().down_until = Some(Instant::now() + down_time);
+        up
+    }
+
+    /// return whether it was down
+    fn mark_up(&self) -> bool {
+        self.health().down_until.take().is_some()
+    }
+
+    fn status(&self) -> ServerStatus {
+        ServerStatus {
+            addr: self.addr.clone(),
+            up: self.is_up(),
+            latency: self.health().latency,
+        }
+    }
+}
+
+/// connects targets through one of several servers
+pub struct Balancer {
+    cfg: BalanceConfig,
+    probe: Option<Address>,
+    servers: Vec<Server>,
+    next: AtomicUsize,
+}
+
+impl Balancer {
+    /// balance over `connectors`, health probes run once [`Balancer::watch`] is called
+    pub fn new(connectors: Vec<Connector>, cfg: &BalanceConfig) -> io::Result<Self> {
+        let servers = connectors
+            .into_iter()
+            .map(|c| (c.addr().to_string(), c))
+            .collect();
+        Self::with_servers(servers, cfg)
+    }
+
+    fn with_servers(servers: Vec<(String, Connector)>, cfg: &BalanceConfig) -> io::Result<Self> {
+        if servers.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no servers to balance",
+            ));
+        }
+        let probe = match cfg.probe_addr.as_str() {
+            "" => None,
+            addr => Some(addr.parse()?),
+        };
+        let servers = servers
+            .into_iter()
+            .map(|(addr, connector)| Server {
+                addr,
+                connector,
+                health: Mutex::default(),
+            })
+            .collect();
+        Ok(Balancer {
+            cfg: cfg.clone(),
+            probe,
+            servers,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// start the plugins of `servers` like [`super::start`] and probe them in the background.
+    /// every returned plugin is supervised by the caller
+    pub async fn start(
+        servers: &[ServerConfig],
+        cfg: &BalanceConfig,
+    ) -> io::Result<(Arc<Self>, Vec<Supervisor>)> {
+        let mut connectors = Vec::with_capacity(servers.len());
+        let mut plugins = Vec::new();
+        for server in servers {
+            let (connector, plugin) = super::start(server).await?;
+            connectors.push((server.addr.clone(), connector));
+            plugins.extend(plugin);
+        }
+        let balancer = Arc::new(Self::with_servers(connectors, cfg)?);
+        Self::watch(&balancer);
+        Ok((balancer, plugins))
+    }
+
+    pub fn status(&self) -> Vec<ServerStatus> {
+        self.servers.iter().map(Server::status).collect()
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_millis(self.cfg.timeout)
+    }
+
+    fn down_time(&self) -> Duration {
+        Duration::from_secs(self.cfg.down_time)
+    }
+
+    /// indexes of servers in the order they are tried for `target`
+    fn order(&self, target: &Address) -> Vec<usize> {
+        let n = self.servers.len();
+        let mut order: Vec<usize> = (0..n).collect();
+        match self.cfg.strategy {
+            Strategy::RoundRobin => {
+                order.rotate_left(self.next.fetch_add(1, Ordering::Relaxed) % n);
+            }
+            Strategy::LeastLatency => {
+                order.sort_by_key(|i| self.servers[*i].health().latency.unwrap_or(Duration::MAX));
+            }
+            Strategy::ConsistentHash => {
+                // rendezvous hashing, a server going down only moves its own targets
+                order.sort_by_key(|i| {
+                    let mut hasher = DefaultHasher::new();
+                    (target.host(), target.port(), &self.servers[*i].addr).hash(&mut hasher);
+                    Reverse(hasher.finish())
+                });
+            }
+        }
+        order
+    }
+
+    /// connect `target` through the first server of the strategy which accepts, servers down
+    /// are only tried when all others failed
+    pub async fn connect(&self, target: &Address) -> io::Result<Stream<TcpStream>> {
+        let (up, down): (Vec<_>, Vec<_>) = self
+            .order(target)
+            .into_iter()
+            .partition(|i| self.servers[*i].is_up());
+        let mut last_err = None;
+        for i in up.into_iter().chain(down) {
+            let server = &self.servers[i];
+            let err = match time::timeout(self.timeout(), server.connector.connect(target)).await {
+                Ok(Ok(stream)) => {
+                    if server.mark_up() {
+                        info!("server {} up again", server.addr);
+                    }
+                    return Ok(stream);
+                }
+                // not the fault of the server
+                Ok(Err(e)) if e.kind() == io::ErrorKind::InvalidInput => return Err(e),
+                Ok(Err(e)) => e,
+                Err(_) => io::Error::new(io::ErrorKind::TimedOut, "connect timeout"),
+            };
+            warn!(
+                "connect server {} for {} error: {}, down for {:?}",
+                server.addr,
+                target,
+                err,
+                self.down_time()
+            );
+            server.mark_down(self.down_time());
+            last_err = Some(err);
+        }
+        Err(last_err.expect("at least one server"))
+    }
+
+    /// probe every server once, concurrently
+    pub async fn probe(&self) {
+        let Some(ref target) = self.probe else {
+            return;
+        };
+        let probes = self.servers.iter().map(|server| async move {
+            let start = Instant::now();
+            let result =
+                match time::timeout(self.timeout(), self.probe_server(server, target)).await {
+                    Ok(result) => result,
+                    Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "probe timeout")),
+                };
+            match result {
+                Ok(()) => {
+                    let latency = start.elapsed();
+                    server.health().latency = Some(latency);
+                    if server.mark_up() {
+                        info!("server {} up again, latency {:?}", server.addr, latency);
+                    } else {
+                        debug!("server {} probed, latency {:?}", server.addr, latency);
+                    }
+                }
+                Err(e) => {
+                    if server.mark_down(self.down_time()) {
+                        warn!("server {} probe error: {}, down", server.addr, e);
+                    } else {
+                        debug!("server {} probe error: {}", server.addr, e);
+                    }
+                }
+            }
+        });
+        future::join_all(probes).await;
+    }
+
+    async fn probe_server(&self, server: &Server, target: &Address) -> io::Result<()> {
+        let mut ss = server.connector.connect(target).await?;
+        if !self.cfg.probe_data.is_empty() {
+            ss.write_all(self.cfg.probe_data.as_bytes()).await?;
+            ss.flush().await?;
+        }
+        let mut buf = [0u8; 1];
+        match ss.read(&mut buf).await? {
+            0 => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "closed without response",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// probe servers every `probe_interval` until the balancer is dropped
+    pub fn watch(balancer: &Arc<Self>) {
+        if balancer.probe.is_none() || balancer.cfg.probe_interval == 0 {
+            return;
+        }
+        let interval = Duration::from_secs(balancer.cfg.probe_interval);
+        let weak: Weak<Self> = Arc::downgrade(balancer);
+        tokio::spawn(async move {
+            loop {
+                let Some(balancer) = weak.upgrade() else {
+                    return;
+                };
+                balancer.probe().await;
+                drop(balancer);
+                time::sleep(interval).await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{util, CipherKind};
+    use tokio::net::TcpListener;
+
+    const PASSWD: &str = "passwd";
+
+    /// server replying the target it was asked for, or closing after reading it
+    async fn server(reply: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let kind = CipherKind::AES_256_GCM;
+            let key = util::evp_bytes_to_key(PASSWD.as_bytes(), kind.key_len());
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                let mut ss = Stream::new_from_stream(socket, kind, &key);
+                let target = Address::read_from(&mut ss).await.unwrap();
+                if reply {
+                    ss.write_all(target.to_string().as_bytes()).await.unwrap();
+                    tokio::spawn(async move { util::read_forever(&mut ss).await });
+                }
+            }
+        });
+        addr
+    }
+
+    /// address nothing listens on
+    async fn dead() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    fn balancer(addrs: &[&String], cfg: &BalanceConfig) -> Balancer {
+        let connectors = addrs
+            .iter()
+            .map(|addr| Connector::new(addr.as_str(), CipherKind::AES_256_GCM, PASSWD))
+            .collect();
+        Balancer::new(connectors, cfg).unwrap()
+    }
+
+    /// connect and return the server which replied
+    async fn connect(balancer: &Balancer, target: &Address) -> String {
+        let mut ss = balancer.connect(target).await.unwrap();
+        let mut buf = vec![0u8; target.to_string().len()];
+        ss.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, target.to_string().as_bytes());
+        ss.get_ref().peer_addr().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_order() {
+        let addrs: Vec<String> = (1..=3).map(|i| format!("127.0.0.1:{}", i)).collect();
+        let refs: Vec<_> = addrs.iter().collect();
+        let target = |i: usize| Address::DomainNameAddress(format!("{}.test", i), 443);
+
+        let b = balancer(&refs, &BalanceConfig::default());
+        assert_eq!(b.order(&target(0)), [0, 1, 2]);
+        assert_eq!(b.order(&target(0)), [1, 2, 0]);
+        assert_eq!(b.order(&target(0)), [2, 0, 1]);
+
+        let cfg = BalanceConfig {
+            strategy: Strategy::LeastLatency,
+            ..Default::default()
+        };
+        let b = balancer(&refs, &cfg);
+        b.servers[2].health().latency = Some(Duration::from_millis(5));
+        b.servers[1].health().latency = Some(Duration::from_millis(10));
+        assert_eq!(b.order(&target(0)), [2, 1, 0]);
+
+        let cfg = BalanceConfig {
+            strategy: Strategy::ConsistentHash,
+            ..Default::default()
+        };
+        let b = balancer(&refs, &cfg);
+        let mut picked = [0; 3];
+        for i in 0..100 {
+            let order = b.order(&target(i));
+            assert_eq!(order, b.order(&target(i)));
+            picked[order[0]] += 1;
+        }
+        assert!(picked.iter().all(|n| *n > 10), "{:?}", picked);
+
+        assert!(Balancer::new(vec![], &BalanceConfig::default()).is_err());
+        let cfg = BalanceConfig {
+            probe_addr: "example.com".into(),
+            ..Default::default()
+        };
+        assert!(
+            Balancer::new(vec![Connector::new("", CipherKind::AES_256_GCM, "")], &cfg).is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failover() {
+        let (a, b, down) = (server(true).await, server(true).await, dead().await);
+        let balancer = balancer(&[&a, &down, &b], &BalanceConfig::default());
+        let target = Address::DomainNameAddress("example.com".into(), 80);
+
+        assert_eq!(connect(&balancer, &target).await, a);
+        // fails over to the next one and is skipped from now on
+        assert_eq!(connect(&balancer, &target).await, b);
+        assert!(!balancer.status()[1].up);
+        assert_eq!(connect(&balancer, &target).await, b);
+        assert_eq!(connect(&balancer, &target).await, a);
+        assert_eq!(connect(&balancer, &target).await, b);
+
+        let long = Address::DomainNameAddress("x".repeat(256), 443);
+        let err = balancer.connect(&long).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(balancer.status()[0].up);
+
+        // servers down are still tried as the last resort
+        let balancer = self::balancer(&[&down, &dead().await], &BalanceConfig::default());
+        assert!(balancer.connect(&target).await.is_err());
+        assert!(balancer.status().iter().all(|s| !s.up));
+        assert!(balancer.connect(&target).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_probe() {
+        let (a, closing, down) = (server(true).await, server(false).await, dead().await);
+        let cfg = BalanceConfig {
+            strategy: Strategy::LeastLatency,
+            probe_addr: "probe.test:80".into(),
+            ..Default::default()
+        };
+        let balancer = Arc::new(balancer(&[&down, &closing, &a], &cfg));
+        balancer.probe().await;
+        let status = balancer.status();
+        assert!(
+            !status[0].up && !status[1].up && status[2].up,
+            "{:?}",
+            status
+        );
+        assert!(status[0].latency.is_none() && status[2].latency.is_some());
+        let target = Address::DomainNameAddress("example.com".into(), 80);
+        assert_eq!(connect(&balancer, &target).await, a);
+
+        // a down server passing the probe is up again
+        balancer.servers[2].mark_down(Duration::from_secs(60));
+        assert!(!balancer.status()[2].up);
+        Balancer::watch(&balancer);
+        time::sleep(Duration::from_millis(100)).await;
+        assert!(balancer.status()[2].up);
+    }
+}
@@ -0,0 +1,225 @@
+//! client side: shadowsocks streams to a server, directly or through a SIP003 plugin which
+//! listens on loopback and forwards to the server. connections are spread over several
+//! servers by a [`Balancer`].
+//!
+//! ```no_run
+//! use ss_light::{client, Address};
+//! use tokio::io::AsyncWriteExt;
+//!
+//! #[tokio::main]
+//! async fn main() -> std::io::Result<()> {
+//!     let cfg: client::ServerConfig = toml::from_str(
+//!         r#"
+//!         addr = "example.com:8388"
+//!         passwd = "123456"
+//!         method = "aes-256-gcm"
+//!         [plugin]
+//!         name = "v2ray-plugin"
+//!         args = []
+//!         "#,
+//!     )
+//!     .unwrap();
+//!     let (connector, mut plugin) = client::start(&cfg).await?;
+//!
+//!     let target = Address::DomainNameAddress("example.org".into(), 80);
+//!     let mut ss = connector.connect(&target).await?;
+//!     ss.write_all(b"GET / HTTP/1.0\r\n\r\n").await?;
+//!
+//!     // exits like the server when restarts of the plugin run out
+//!     if let Some(ref mut p) = plugin {
+//!         let e = p.supervise().await;
+//!         eprintln!("plugin stopped: {}", e);
+//!     }
+//!     Ok(())
+//! }
+//! ```
+use std::{io, sync::Arc};
+
+use bytes::BytesMut;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{lookup_host, TcpStream},
+};
+use tracing::info;
+
+use crate::{
+    mux::{self, MuxClient},
+    net::{self, TcpConfig},
+    plugin::{LocalAddr, PluginConfig, Supervisor},
+    util, Address, CipherKind, Stream,
+};
+
+mod balance;
+
+pub use balance::{BalanceConfig, Balancer, ServerStatus, Strategy};
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ServerConfig {
+    /// `host:port` of the server
+    pub addr: String,
+    pub passwd: String,
+    pub method: CipherKind,
+    /// client plugin, like `v2ray-plugin` or `obfs-local`
+    pub plugin: Option<PluginConfig>,
+}
+
+/// opens shadowsocks streams to one server, or to the client plugin in front of it
+#[derive(Clone)]
+pub struct Connector {
+    addr: String,
+    kind: CipherKind,
+    key: Arc<[u8]>,
+    tcp: TcpConfig,
+}
+
+impl Connector {
+    pub fn new(addr: impl Into<String>, kind: CipherKind, passwd: &str) -> Self {
+        Connector {
+            addr: addr.into(),
+            kind,
+            key: util::evp_bytes_to_key(passwd.as_bytes(), kind.key_len()).into(),
+            tcp: TcpConfig::default(),
+        }
+    }
+
+    /// socket options of outbound connections
+    pub fn with_tcp(mut self, tcp: TcpConfig) -> Self {
+        self.tcp = tcp;
+        self
+    }
+
+    /// where streams are connected, the loopback address of the plugin if any
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// connect and send `target`, which the server connects for data written to the stream
+    pub async fn connect(&self, target: &Address) -> io::Result<Stream<TcpStream>> {
+        let mut header = BytesMut::new();
+        target.write_to_buf(&mut header)?;
+        let mut last_err = None;
+        for addr in lookup_host(&self.addr).await? {
+            let socket = match net::connect(addr, &self.tcp).await {
+                Ok(socket) => socket,
+                Err(e) => {
+                    last_err = Some(e);
+                    continue;
+                }
+            };
+            // with fast open the header goes out in the SYN, the next address is tried
+            // if that handshake fails
+            let mut ss = Stream::new_from_stream(socket, self.kind, &self.key);
+            ss.write_all(&header).await?;
+            match net::wait_established(ss.get_ref()).await {
+                Ok(()) => return Ok(ss),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err
+            .unwrap_or_else(|| io::Error::other(format!("dns resolve empty: {}", self.addr))))
+    }
+
+    /// one connection carrying many streams, the server needs `[mux]` enabled
+    pub async fn connect_mux(&self) -> io::Result<MuxClient> {
+        let carrier = self.connect(&mux::mux_address()).await?;
+        Ok(MuxClient::new(carrier))
+    }
+}
+
+/// start the plugin of `cfg` if any, with `SS_REMOTE_*` set to the server, and return the
+/// connector pointing at it. the plugin is supervised by the caller like on the server
+pub async fn start(cfg: &ServerConfig) -> io::Result<(Connector, Option<Supervisor>)> {
+    let Some(ref plugin_cfg) = cfg.plugin else {
+        return Ok((Connector::new(&cfg.addr, cfg.method, &cfg.passwd), None));
+    };
+    let (host, port) = cfg.addr.rsplit_once(':').ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("server addr without port: {}", cfg.addr),
+        )
+    })?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let plugin = Supervisor::start_client(plugin_cfg, host, port).await?;
+    let local_addr = match plugin.local_addr() {
+        LocalAddr::Tcp(addr) => addr.to_string(),
+        LocalAddr::Unix(path) => unreachable!("client plugin on unix socket {}", path.display()),
+    };
+    info!(
+        "client plugin {} listening on {} for server {}",
+        plugin_cfg.name, local_addr, cfg.addr
+    );
+    let connector = Connector::new(local_addr, cfg.method, &cfg.passwd);
+    Ok((connector, Some(plugin)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{io::AsyncReadExt, net::TcpListener};
+
+    #[tokio::test]
+    async fn test_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let cfg = ServerConfig {
+            addr: listener.local_addr().unwrap().to_string(),
+            passwd: "passwd".into(),
+            method: CipherKind::AES_256_GCM,
+            plugin: None,
+        };
+        let (connector, plugin) = start(&cfg).await.unwrap();
+        assert!(plugin.is_none());
+        assert_eq!(connector.addr(), cfg.addr);
+
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let key = util::evp_bytes_to_key(b"passwd", CipherKind::AES_256_GCM.key_len());
+            let mut ss = Stream::new_from_stream(socket, CipherKind::AES_256_GCM, &key);
+            let target = Address::read_from(&mut ss).await.unwrap();
+            let mut buf = [0u8; 4];
+            ss.read_exact(&mut buf).await.unwrap();
+            ss.write_all(&buf).await.unwrap();
+            target
+        });
+        let target = Address::DomainNameAddress("example.org".into(), 443);
+        let mut ss = connector.connect(&target).await.unwrap();
+        ss.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        ss.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        assert_eq!(server.await.unwrap(), target);
+
+        let long = Address::DomainNameAddress("x".repeat(256), 443);
+        let err = connector.connect(&long).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_client_plugin() {
+        let dir = std::env::temp_dir().join(format!("ss-light-client-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let env = dir.join("env");
+        let plugin = |script: String| PluginConfig {
+            name: "sh".into(),
+            args: vec!["-c".into(), script],
+            ready_timeout: 1,
+            ..Default::default()
+        };
+        let cfg = ServerConfig {
+            addr: "[::1]:8388".into(),
+            passwd: "passwd".into(),
+            method: CipherKind::AES_256_GCM,
+            plugin: Some(plugin(format!(
+                "echo $SS_REMOTE_HOST $SS_REMOTE_PORT $SS_LOCAL_HOST > {}; exit 1",
+                env.display()
+            ))),
+        };
+        // never listens on the local port
+        let err = start(&cfg).await.err().unwrap();
+        assert!(err.to_string().contains("exited before ready"), "{}", err);
+        let env = std::fs::read_to_string(&env).unwrap();
+        assert_eq!(env.trim(), "::1 8388 127.0.0.1");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
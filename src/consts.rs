@@ -34,6 +34,8 @@ pub enum Error {
     InvalidPackage,
     #[error("cipher: {0}")]
     CipherError(ring::error::Unspecified),
+    #[error("handshake timeout")]
+    HandshakeTimeout,
 }
 
 pub const MAXIMUM_UDP_PAYLOAD_SIZE: usize = 65536; // 64k, udp support max size is: 65535- IP_HEAD(20) - UDP_HEAD(8) = 65507
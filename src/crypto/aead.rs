@@ -6,6 +6,7 @@ use ring::aead::{Aad, BoundKey, OpeningKey, SealingKey, UnboundKey, AES_256_GCM}
 use core::slice;
 use std::io::{self, ErrorKind};
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tracing::trace;
@@ -132,11 +133,19 @@ pub struct DecryptedReader {
     state: DecryptReadState,
     kind: CipherKind,
     salt: Option<Bytes>,
-    key: Bytes,
+    keys: Arc<[Bytes]>,
+    user: Option<usize>,      // index of the key that opened the stream
+    replay: Option<BytesMut>, // raw bytes received before the first chunk is authenticated
 }
 
 impl DecryptedReader {
     pub fn new(kind: CipherKind, key: &[u8]) -> Self {
+        Self::new_with_keys(kind, Arc::from(vec![Bytes::copy_from_slice(key)]))
+    }
+
+    /// multi-user reader, the user is identified by the key which opens the first chunk
+    pub fn new_with_keys(kind: CipherKind, keys: Arc<[Bytes]>) -> Self {
+        assert!(!keys.is_empty(), "at least one key");
         match kind {
             CipherKind::AES_256_GCM => Self {
                 opening_key: None,
@@ -144,12 +153,58 @@ impl DecryptedReader {
                 state: DecryptReadState::WaitSalt,
                 kind,
                 salt: None,
-                key: Bytes::copy_from_slice(key),
+                keys,
+                user: None,
+                replay: Some(BytesMut::new()),
             },
             _ => panic!("unsupport chipher kind"),
         }
     }
 
+    /// index of the identified user key, available after the first chunk is read
+    pub fn user(&self) -> Option<usize> {
+        self.user
+    }
+
+    /// key of the identified user
+    pub fn key(&self) -> Option<&Bytes> {
+        self.user.map(|i| &self.keys[i])
+    }
+
+    /// raw bytes received from peer while the first chunk is not authenticated yet,
+    /// so a failed connection can be handed to a fallback server as is.
+    /// empty once the first chunk is authenticated
+    pub fn take_replay(&mut self) -> Bytes {
+        self.replay.take().unwrap_or_default().freeze()
+    }
+
+    fn new_opening_key(key: &[u8], salt: &[u8]) -> OpeningKey<util::NonceSequence> {
+        let sub_key = util::hkdf_sha1(key, salt);
+        trace!("peer sub_key is {:?}", sub_key);
+        let unbound =
+            UnboundKey::new(&AES_256_GCM, &sub_key).expect("key.len != algorithm.key_len");
+        OpeningKey::new(unbound, util::NonceSequence::new())
+    }
+
+    /// try every key on the encrypted length chunk in buf, keep the one which opens it
+    fn identify_user(&mut self) -> io::Result<()> {
+        let salt = self.salt.as_ref().unwrap();
+        for (i, key) in self.keys.iter().enumerate() {
+            let mut opening_key = Self::new_opening_key(key, salt);
+            let mut chunk = self.buf.clone();
+            if opening_key
+                .open_in_place(Aad::<[u8; 0]>::empty(), &mut chunk)
+                .is_ok()
+            {
+                self.buf = chunk;
+                self.opening_key = Some(opening_key);
+                self.user = Some(i);
+                return Ok(());
+            }
+        }
+        Err(io::Error::other("ReadLength invalid tag-in"))
+    }
+
     pub fn poll_read<S>(
         &mut self,
         cx: &mut Context,
@@ -170,18 +225,15 @@ impl DecryptedReader {
                     debug_assert!(self.buf.len() == salt_len);
                     self.salt = Some(Bytes::copy_from_slice(&self.buf));
 
-                    // cacl sub_key
-                    let sub_key = util::hkdf_sha1(&self.key, self.salt.as_ref().unwrap());
-                    trace!("peer sub_key is {:?}", sub_key);
-
-                    let unbound = UnboundKey::new(&AES_256_GCM, &sub_key)
-                        .expect("key.len != algorithm.key_len");
-                    let opening_key = OpeningKey::new(unbound, util::NonceSequence::new());
+                    // with multiple keys, the opening key is chosen by the first length chunk
+                    if self.keys.len() == 1 {
+                        self.opening_key = Some(Self::new_opening_key(&self.keys[0], &self.buf));
+                        self.user = Some(0);
+                    }
 
                     self.buf.clear();
                     self.state = DecryptReadState::ReadLength;
                     self.buf.reserve(2 + self.kind.tag_len());
-                    self.opening_key = Some(opening_key);
                 }
                 DecryptReadState::ReadLength => {
                     let usize =
@@ -189,15 +241,18 @@ impl DecryptedReader {
                     if usize == 0 {
                         return Ok(()).into();
                     } else {
-                        let result = self
-                            .opening_key
-                            .as_mut()
-                            .unwrap()
-                            .open_in_place(Aad::<[u8; 0]>::empty(), &mut self.buf)
-                            .map_err(|_| {
-                                io::Error::new(ErrorKind::Other, "ReadLength invalid tag-in")
-                            })?;
-                        let plen = u16::from_be_bytes([result[0], result[1]]) as usize;
+                        let plen = match self.opening_key {
+                            Some(ref mut opening_key) => {
+                                let result = opening_key
+                                    .open_in_place(Aad::<[u8; 0]>::empty(), &mut self.buf)
+                                    .map_err(|_| io::Error::other("ReadLength invalid tag-in"))?;
+                                u16::from_be_bytes([result[0], result[1]]) as usize
+                            }
+                            None => {
+                                self.identify_user()?;
+                                u16::from_be_bytes([self.buf[0], self.buf[1]]) as usize
+                            }
+                        };
                         if plen > self.kind.max_package_size() {
                             let  err = io::Error::new(
                                 ErrorKind::InvalidData,
@@ -226,7 +281,10 @@ impl DecryptedReader {
                         .as_mut()
                         .unwrap()
                         .open_in_place(Aad::<[u8; 0]>::empty(), &mut self.buf)
-                        .map_err(|_| io::Error::new(ErrorKind::Other, "ReadData invalid tag-in"))?;
+                        .map_err(|_| io::Error::other("ReadData invalid tag-in"))?;
+
+                    // authenticated, nothing to replay anymore
+                    self.replay = None;
 
                     // remove tag
                     self.buf.truncate(length);
@@ -261,6 +319,7 @@ impl DecryptedReader {
         assert!(size != 0);
         while self.buf.len() < size {
             let remaing = size - self.buf.len();
+            let filled = self.buf.len();
 
             let view = &mut self.buf.chunk_mut()[..remaing];
             debug_assert_eq!(view.len(), remaing);
@@ -272,6 +331,9 @@ impl DecryptedReader {
             let n = read_buf.filled().len();
 
             unsafe { self.buf.advance_mut(n) }
+            if let Some(ref mut replay) = self.replay {
+                replay.extend_from_slice(&self.buf[filled..]);
+            }
 
             if n == 0 {
                 if !self.buf.is_empty() {
@@ -1,3 +1,5 @@
+use std::sync::Arc;
+
 use bytes::{BufMut, Bytes, BytesMut};
 use rand::Fill;
 use ring::aead::{Aad, BoundKey, OpeningKey, SealingKey, UnboundKey, AES_256_GCM};
@@ -7,22 +9,41 @@ use crate::{util, CipherKind, Error};
 /// An AEAD encrypted UDP packet has the following structure
 ///
 /// [salt][encrypted payload][tag]
+///
+/// With multiple keys, packets are decrypted by whichever key opens them and encrypted
+/// with the first one, use [`PacketCipher::for_user`] to reply to an identified user.
 pub struct PacketCipher {
     kind: CipherKind,
-    key: Bytes,
+    keys: Arc<[Bytes]>,
 }
 
 impl PacketCipher {
     pub fn new(kind: CipherKind, key: &[u8]) -> Self {
+        Self::new_with_keys(kind, Arc::from(vec![Bytes::copy_from_slice(key)]))
+    }
+
+    pub fn new_with_keys(kind: CipherKind, keys: Arc<[Bytes]>) -> Self {
+        assert!(!keys.is_empty(), "at least one key");
         match kind {
-            CipherKind::AES_256_GCM => Self {
-                kind,
-                key: Bytes::copy_from_slice(key),
-            },
+            CipherKind::AES_256_GCM => Self { kind, keys },
             _ => panic!("unsupport chipher kind"),
         }
     }
 
+    /// cipher with only the key of user
+    pub fn for_user(&self, user: usize) -> Self {
+        Self::new_with_keys(self.kind, Arc::from(vec![self.keys[user].clone()]))
+    }
+
+    /// key of user, which stays the same when users are reordered on key change
+    pub fn key(&self, user: usize) -> &Bytes {
+        &self.keys[user]
+    }
+
+    pub fn kind(&self) -> CipherKind {
+        self.kind
+    }
+
     pub fn encrypt_to(&self, buf: &[u8]) -> Result<BytesMut, Error> {
         self.encrypt_vec_slice_to(vec![buf])
     }
@@ -32,7 +53,7 @@ impl PacketCipher {
         unsafe { send_buf.advance_mut(self.kind.salt_len()) }
         send_buf.try_fill(&mut rand::thread_rng()).unwrap();
 
-        let sub_key = util::hkdf_sha1(&self.key, &send_buf);
+        let sub_key = util::hkdf_sha1(&self.keys[0], &send_buf);
         let unbound =
             UnboundKey::new(&AES_256_GCM, &sub_key).expect("key.len != algorithm.key_len");
         let mut sealing_key = SealingKey::new(unbound, util::NonceZeroSequence {});
@@ -54,31 +75,55 @@ impl PacketCipher {
     }
 
     pub fn decrypt_from(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.decrypt_user_from(buf).map(|(n, _)| n)
+    }
+
+    /// decrypt in place, return data size and index of the key which opened the packet
+    pub fn decrypt_user_from(&self, buf: &mut [u8]) -> Result<(usize, usize), Error> {
         if buf.len() <= self.kind.salt_len() + self.kind.tag_len() {
             return Err(Error::InvalidPackage);
         }
-        let salt = &buf[..self.kind.salt_len()];
-        let sub_key = util::hkdf_sha1(&self.key, salt);
-
-        let unbound =
-            UnboundKey::new(&AES_256_GCM, &sub_key).expect("key.len != algorithm.key_len");
-        let mut opening_key = OpeningKey::new(unbound, util::NonceZeroSequence {});
-
-        let data = opening_key
-            .open_in_place(Aad::<[u8; 0]>::empty(), &mut buf[self.kind.salt_len()..])
-            .map_err(Error::CipherError)?;
-
-        let data_len = data.len();
-        for i in 0..data_len {
-            buf[i] = buf[i + self.kind.salt_len()]
+        let (salt, data) = buf.split_at_mut(self.kind.salt_len());
+
+        let mut result = Err(Error::InvalidPackage);
+        for (user, key) in self.keys.iter().enumerate() {
+            let sub_key = util::hkdf_sha1(key, salt);
+            let unbound =
+                UnboundKey::new(&AES_256_GCM, &sub_key).expect("key.len != algorithm.key_len");
+            let mut opening_key = OpeningKey::new(unbound, util::NonceZeroSequence {});
+
+            // a failed open may clobber data, only keep a copy when other keys remain
+            let is_last = user + 1 == self.keys.len();
+            let mut copy = if is_last { None } else { Some(data.to_vec()) };
+            let target = match copy {
+                Some(ref mut c) => c.as_mut_slice(),
+                None => &mut *data,
+            };
+            match opening_key.open_in_place(Aad::<[u8; 0]>::empty(), target) {
+                Ok(opened) => {
+                    let data_len = opened.len();
+                    if let Some(c) = copy {
+                        data[..data_len].copy_from_slice(&c[..data_len]);
+                    }
+                    result = Ok((data_len, user));
+                    break;
+                }
+                Err(e) => result = Err(Error::CipherError(e)),
+            }
         }
+        let (data_len, user) = result?;
 
-        Ok(data_len)
+        buf.copy_within(self.kind.salt_len()..self.kind.salt_len() + data_len, 0);
+
+        Ok((data_len, user))
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+
+    use bytes::Bytes;
 
     use crate::{util, CipherKind};
 
@@ -101,4 +146,33 @@ mod tests {
 
         assert_eq!(data, &m[..d])
     }
+
+    #[tokio::test]
+    async fn test_packet_multi_user() {
+        let kind = CipherKind::AES_256_GCM;
+        let keys: Vec<Bytes> = ["alice", "bob", "carol"]
+            .iter()
+            .map(|pwd| Bytes::from(util::evp_bytes_to_key(pwd.as_bytes(), kind.key_len())))
+            .collect();
+        let server = PacketCipher::new_with_keys(kind, Arc::from(keys.clone()));
+
+        let data = &b"hello world!"[..];
+        for (i, key) in keys.iter().enumerate() {
+            let mut m = PacketCipher::new(kind, key).encrypt_to(data).unwrap();
+            let (d, user) = server.decrypt_user_from(&mut m).unwrap();
+            assert_eq!(user, i);
+            assert_eq!(data, &m[..d]);
+
+            // reply is readable by the user
+            let mut reply = server.for_user(user).encrypt_to(data).unwrap();
+            let d = PacketCipher::new(kind, key)
+                .decrypt_from(&mut reply)
+                .unwrap();
+            assert_eq!(data, &reply[..d]);
+        }
+
+        let other = util::evp_bytes_to_key(b"mallory", kind.key_len());
+        let mut m = PacketCipher::new(kind, &other).encrypt_to(data).unwrap();
+        assert!(server.decrypt_user_from(&mut m).is_err());
+    }
 }
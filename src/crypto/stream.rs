@@ -1,5 +1,6 @@
-use std::{ops::DerefMut, pin::Pin};
+use std::{io, ops::DerefMut, pin::Pin, sync::Arc, task::Poll};
 
+use bytes::Bytes;
 use rand::Fill;
 use tokio::io::{AsyncRead, AsyncWrite};
 
@@ -11,19 +12,34 @@ use super::{
 pub struct Stream<S> {
     stream: S,
     dec: DecryptedReader,
-    enc: EncryptedWriter,
+    enc: Option<EncryptedWriter>, // created after user identified for multi-user stream
     kind: CipherKind,
 }
 
+fn new_writer(kind: CipherKind, key: &[u8]) -> EncryptedWriter {
+    let mut salt = vec![0u8; kind.salt_len()];
+    salt.try_fill(&mut rand::thread_rng()).unwrap();
+    EncryptedWriter::new(kind, key, &salt)
+}
+
 impl<S> Stream<S> {
     pub fn new_from_stream(stream: S, kind: CipherKind, key: &[u8]) -> Stream<S> {
-        let mut salt = vec![0u8; kind.salt_len()];
-        salt.try_fill(&mut rand::thread_rng()).unwrap();
         Stream {
             stream,
             kind,
             dec: DecryptedReader::new(kind, key),
-            enc: EncryptedWriter::new(kind, key, &salt),
+            enc: Some(new_writer(kind, key)),
+        }
+    }
+
+    /// server side multi-user stream, the user is identified by the key of the first chunk
+    /// received, so it must be read before anything is written
+    pub fn new_from_stream_with_keys(stream: S, kind: CipherKind, keys: Arc<[Bytes]>) -> Stream<S> {
+        Stream {
+            stream,
+            kind,
+            dec: DecryptedReader::new_with_keys(kind, keys),
+            enc: None,
         }
     }
 
@@ -31,9 +47,29 @@ impl<S> Stream<S> {
         self.kind
     }
 
+    /// index of the key used by peer
+    pub fn user(&self) -> Option<usize> {
+        self.dec.user()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
     pub fn into_inner(self) -> S {
         self.stream
     }
+
+    /// inner stream and the raw bytes already read from it if the first chunk failed
+    /// authentication, see [`DecryptedReader::take_replay`]
+    pub fn into_inner_with_replay(mut self) -> (S, Bytes) {
+        let replay = self.dec.take_replay();
+        (self.stream, replay)
+    }
 }
 
 impl<S> AsyncRead for Stream<S>
@@ -62,7 +98,15 @@ where
         buf: &[u8],
     ) -> std::task::Poll<Result<usize, std::io::Error>> {
         let p = self.deref_mut();
-        let w = &mut p.enc;
+        if p.enc.is_none() {
+            match p.dec.key() {
+                Some(key) => p.enc = Some(new_writer(p.kind, key)),
+                None => {
+                    return Poll::Ready(Err(io::Error::other("write before peer key identified")))
+                }
+            }
+        }
+        let w = p.enc.as_mut().unwrap();
         let stream = &mut p.stream;
         w.poll_write(cx, stream, buf)
     }
@@ -81,3 +125,56 @@ where
         Pin::new(&mut self.stream).poll_shutdown(cx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use crate::{util, CipherKind, Stream};
+
+    #[tokio::test]
+    async fn test_multi_user_stream() {
+        let kind = CipherKind::AES_256_GCM;
+        let keys: Vec<Bytes> = ["alice", "bob"]
+            .iter()
+            .map(|pwd| Bytes::from(util::evp_bytes_to_key(pwd.as_bytes(), kind.key_len())))
+            .collect();
+
+        let (client, server) = duplex(1024);
+        let mut client = Stream::new_from_stream(client, kind, &keys[1]);
+        let mut server = Stream::new_from_stream_with_keys(server, kind, Arc::from(keys));
+
+        // server can't write before knowing the user
+        assert!(server.write_all(b"too early").await.is_err());
+
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        assert_eq!(server.user(), Some(1));
+
+        server.write_all(b"world").await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+    }
+
+    #[tokio::test]
+    async fn test_replay_unauthenticated() {
+        let kind = CipherKind::AES_256_GCM;
+        let key = util::evp_bytes_to_key(b"passwd", kind.key_len());
+
+        let (mut client, server) = duplex(1024);
+        let mut server = Stream::new_from_stream(server, kind, &key);
+
+        let probe = b"GET / HTTP/1.1\r\nHost: www.example.com\r\nAccept: */*\r\n\r\n";
+        client.write_all(probe).await.unwrap();
+        let mut buf = [0u8; 1];
+        assert!(server.read(&mut buf).await.is_err());
+
+        let (_, replay) = server.into_inner_with_replay();
+        assert_eq!(&replay[..], &probe[..kind.salt_len() + 2 + kind.tag_len()]);
+    }
+}
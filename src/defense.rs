@@ -0,0 +1,507 @@
+//! Defense active detection attack, [https://gfw.report/talks/imc20/zh/](https://gfw.report/talks/imc20/zh/)
+//!
+//! When a connection fails authentication, how the server reacts tells a prober whether
+//! it is talking to a shadowsocks server. [`ProbeDefense`] decides that reaction, and
+//! [`AuthFailures`] bans sources which keep failing.
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::{self, Formatter},
+    io,
+    net::{IpAddr, Ipv6Addr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use socket2::SockRef;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    time,
+};
+use tracing::trace;
+
+use crate::{relay, util};
+
+/// sec, idle timeout of a fallback relay when `idle_timeout` of relay is not set, like
+/// `keepalive_timeout` of nginx
+const FALLBACK_IDLE_TIMEOUT: u64 = 75;
+
+/// stream a probe defense responds on, the tcp socket under it sends the RST of
+/// [`ProbeDefense::Reset`]
+pub trait ProbeStream: AsyncRead + AsyncWrite + Unpin {
+    fn tcp(&self) -> Option<&TcpStream>;
+}
+
+impl ProbeStream for TcpStream {
+    fn tcp(&self) -> Option<&TcpStream> {
+        Some(self)
+    }
+}
+
+/// forwarded by a plugin over a unix socket, reset is a plain close
+#[cfg(unix)]
+impl ProbeStream for tokio::net::UnixStream {
+    fn tcp(&self) -> Option<&TcpStream> {
+        None
+    }
+}
+
+/// a prober which completed the tls handshake gets the response inside tls
+#[cfg(feature = "tls")]
+impl<S: ProbeStream> ProbeStream for tokio_rustls::server::TlsStream<S> {
+    fn tcp(&self) -> Option<&TcpStream> {
+        self.get_ref().0.tcp()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "strategy", rename_all = "snake_case")]
+pub enum ProbeDefense {
+    /// read until peer closes
+    #[default]
+    ReadForever,
+    /// read a random number of bytes in `[min, max]` then close
+    ReadThenClose { min: usize, max: usize },
+    /// wait a random delay in `[min_delay, max_delay]` ms then close with RST
+    Reset { min_delay: u64, max_delay: u64 },
+    /// replay bytes received so far and relay the rest of the stream to a fallback server,
+    /// like a web server, so the port behaves like that server to a prober
+    Fallback { addr: String },
+}
+
+impl ProbeDefense {
+    /// respond to a peer which failed authentication, `received` is what was already read from
+    /// the stream. returns when the connection should be closed. a fallback is connected
+    /// within `connect_timeout` and relayed with `relay_cfg`, always with an idle timeout
+    pub async fn respond<S: ProbeStream>(
+        &self,
+        mut stream: S,
+        received: &[u8],
+        connect_timeout: Duration,
+        relay_cfg: &relay::RelayConfig,
+    ) -> io::Result<()> {
+        match *self {
+            ProbeDefense::ReadForever => util::read_forever(&mut stream).await,
+            ProbeDefense::ReadThenClose { min, max } => {
+                let limit = random_between(min, max).saturating_sub(received.len());
+                trace!("probe defense: read {} bytes then close", limit);
+                read_at_most(&mut stream, limit).await
+            }
+            ProbeDefense::Reset {
+                min_delay,
+                max_delay,
+            } => {
+                let delay = random_between(min_delay, max_delay);
+                trace!("probe defense: reset after {} ms", delay);
+                let _ = time::timeout(
+                    Duration::from_millis(delay),
+                    util::read_forever(&mut stream),
+                )
+                .await;
+                // zero linger makes close send RST instead of FIN
+                match stream.tcp() {
+                    Some(tcp) => SockRef::from(tcp).set_linger(Some(Duration::ZERO)),
+                    None => Ok(()),
+                }
+            }
+            ProbeDefense::Fallback { ref addr } => {
+                trace!("probe defense: fallback to {}", addr);
+                let mut fallback =
+                    time::timeout(connect_timeout, TcpStream::connect(addr.as_str()))
+                        .await
+                        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+                fallback.write_all(received).await?;
+                let mut relay_cfg = relay_cfg.clone();
+                if relay_cfg.idle_timeout == 0 {
+                    relay_cfg.idle_timeout = FALLBACK_IDLE_TIMEOUT;
+                }
+                let result = relay::relay(&mut stream, &mut fallback, &relay_cfg).await;
+                match result.reason {
+                    relay::CloseReason::Error(e) => Err(e),
+                    _ => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BanAction {
+    /// close banned connections right after accept
+    #[default]
+    Refuse,
+    /// respond with the probe defense strategy without reading anything
+    ProbeDefense,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct BanConfig {
+    /// auth failures within `window` to ban a source, 0 means disabled
+    pub threshold: usize,
+    /// sec, sliding window to count failures
+    pub window: u64,
+    /// sec, how long a source stays banned
+    pub duration: u64,
+    pub action: BanAction,
+}
+
+impl Default for BanConfig {
+    fn default() -> Self {
+        BanConfig {
+            threshold: 0,
+            window: 60,
+            duration: 600,
+            action: BanAction::Refuse,
+        }
+    }
+}
+
+/// ipv4 address, or the /64 network of an ipv6 address since a host usually owns the whole /64
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Source(IpAddr);
+
+impl From<IpAddr> for Source {
+    fn from(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(_) => Source(ip),
+            IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+                Some(v4) => Source(v4.into()),
+                None => {
+                    let mut segments = v6.segments();
+                    segments[4..].fill(0);
+                    Source(Ipv6Addr::from(segments).into())
+                }
+            },
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.0 {
+            IpAddr::V4(ip) => write!(f, "{}", ip),
+            IpAddr::V6(ip) => write!(f, "{}/64", ip),
+        }
+    }
+}
+
+fn is_loopback(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => v4.is_loopback(),
+        IpAddr::V6(v6) => {
+            v6.is_loopback() || v6.to_ipv4_mapped().is_some_and(|v4| v4.is_loopback())
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Ban {
+    pub source: Source,
+    /// failures counted when banned
+    pub failures: usize,
+    pub remaining: Duration,
+}
+
+#[derive(Default)]
+struct SourceState {
+    failures: VecDeque<Instant>,
+    banned_until: Option<Instant>,
+}
+
+/// counts auth failures per [`Source`] over a sliding window and bans sources past the threshold
+#[derive(Default)]
+pub struct AuthFailures {
+    sources: Mutex<HashMap<Source, SourceState>>,
+}
+
+impl AuthFailures {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let sources = self.sources.lock().unwrap();
+        matches!(
+            sources.get(&Source::from(ip)).and_then(|s| s.banned_until),
+            Some(until) if until > now
+        )
+    }
+
+    /// record a failure, returns the ban if the source gets banned by this one. loopback is
+    /// never counted, it is a local plugin or tool forwarding for everyone
+    pub fn record_failure(&self, ip: IpAddr, cfg: &BanConfig) -> Option<Ban> {
+        if cfg.threshold == 0 || is_loopback(ip) {
+            return None;
+        }
+        let now = Instant::now();
+        let source = Source::from(ip);
+        let mut sources = self.sources.lock().unwrap();
+        let state = sources.entry(source).or_default();
+        if matches!(state.banned_until, Some(until) if until > now) {
+            return None;
+        }
+        if state.banned_until.take().is_some() {
+            // ban expired, start over
+            state.failures.clear();
+        }
+        let window = Duration::from_secs(cfg.window);
+        while matches!(state.failures.front(), Some(t) if now.duration_since(*t) > window) {
+            state.failures.pop_front();
+        }
+        state.failures.push_back(now);
+        if state.failures.len() < cfg.threshold {
+            return None;
+        }
+
+        let duration = Duration::from_secs(cfg.duration);
+        let failures = state.failures.len();
+        state.banned_until = Some(now + duration);
+        Some(Ban {
+            source,
+            failures,
+            remaining: duration,
+        })
+    }
+
+    /// current bans
+    pub fn bans(&self) -> Vec<Ban> {
+        let now = Instant::now();
+        let sources = self.sources.lock().unwrap();
+        sources
+            .iter()
+            .filter_map(|(source, state)| match state.banned_until {
+                Some(until) if until > now => Some(Ban {
+                    source: *source,
+                    failures: state.failures.len(),
+                    remaining: until - now,
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// drop sources which are neither banned nor have failures in the window
+    pub fn purge(&self, cfg: &BanConfig) {
+        let now = Instant::now();
+        let window = Duration::from_secs(cfg.window);
+        let mut sources = self.sources.lock().unwrap();
+        sources.retain(|_, state| {
+            matches!(state.banned_until, Some(until) if until > now)
+                || matches!(state.failures.back(), Some(t) if now.duration_since(*t) <= window)
+        });
+    }
+}
+
+fn random_between<T>(min: T, max: T) -> T
+where
+    T: rand::distributions::uniform::SampleUniform + PartialOrd + Copy,
+{
+    if min >= max {
+        return min;
+    }
+    rand::thread_rng().gen_range(min..=max)
+}
+
+async fn read_at_most<R>(reader: &mut R, limit: usize) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buf = [0u8; 1024];
+    let mut remaining = limit;
+    while remaining > 0 {
+        let n = reader.read(&mut buf[..usize::min(remaining, 1024)]).await?;
+        if n == 0 {
+            break;
+        }
+        remaining -= n;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::{AuthFailures, BanConfig, ProbeDefense, Source};
+    use crate::relay::RelayConfig;
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    async fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn test_read_then_close() {
+        let (mut client, server) = pair().await;
+        let defense = ProbeDefense::ReadThenClose { min: 10, max: 10 };
+        let handle = tokio::spawn(async move {
+            defense
+                .respond(server, &[0u8; 4], TIMEOUT, &RelayConfig::default())
+                .await
+        });
+
+        client.write_all(&[0u8; 6]).await.unwrap();
+        handle.await.unwrap().unwrap();
+        let mut buf = [0u8; 1];
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_reset() {
+        let (mut client, server) = pair().await;
+        let defense = ProbeDefense::Reset {
+            min_delay: 10,
+            max_delay: 20,
+        };
+        defense
+            .respond(server, &[], TIMEOUT, &RelayConfig::default())
+            .await
+            .unwrap();
+        let mut buf = [0u8; 1];
+        let err = client.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+    }
+
+    #[tokio::test]
+    async fn test_fallback() {
+        let fallback = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let defense = ProbeDefense::Fallback {
+            addr: fallback.local_addr().unwrap().to_string(),
+        };
+        tokio::spawn(async move {
+            let (mut s, _) = fallback.accept().await.unwrap();
+            let mut buf = [0u8; 14];
+            s.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"GET / HTTP/1.1");
+            s.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n")
+                .await
+                .unwrap();
+        });
+
+        let (mut client, server) = pair().await;
+        tokio::spawn(async move {
+            defense
+                .respond(server, b"GET / ", TIMEOUT, &RelayConfig::default())
+                .await
+        });
+        client.write_all(b"HTTP/1.1").await.unwrap();
+        let mut buf = Vec::new();
+        tokio::time::timeout(Duration::from_secs(3), client.read_to_end(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(buf.starts_with(b"HTTP/1.1 400"));
+    }
+
+    #[tokio::test]
+    async fn test_fallback_bounded() {
+        // never answers, the relay ends when idle
+        let fallback = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let defense = ProbeDefense::Fallback {
+            addr: fallback.local_addr().unwrap().to_string(),
+        };
+        let accepted = tokio::spawn(async move { fallback.accept().await.unwrap() });
+        let (_client, server) = pair().await;
+        let relay_cfg = RelayConfig {
+            idle_timeout: 1,
+            ..Default::default()
+        };
+        let res = tokio::time::timeout(
+            Duration::from_secs(3),
+            defense.respond(server, b"GET / ", TIMEOUT, &relay_cfg),
+        )
+        .await
+        .expect("relay to a silent fallback not bounded");
+        assert!(res.is_ok());
+        drop(accepted.await.unwrap());
+
+        // never accepts, a full backlog leaves the SYN unanswered
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let fallback = socket.listen(0).unwrap();
+        let addr = fallback.local_addr().unwrap();
+        let mut backlog = Vec::new();
+        while let Ok(Ok(s)) =
+            tokio::time::timeout(Duration::from_millis(200), TcpStream::connect(addr)).await
+        {
+            backlog.push(s);
+        }
+        let defense = ProbeDefense::Fallback {
+            addr: addr.to_string(),
+        };
+        let (_client, server) = pair().await;
+        let err = tokio::time::timeout(
+            Duration::from_secs(3),
+            defense.respond(server, b"GET / ", TIMEOUT, &RelayConfig::default()),
+        )
+        .await
+        .expect("connect to a stalled fallback not bounded")
+        .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_source() {
+        let v4: std::net::IpAddr = "1.2.3.4".parse().unwrap();
+        assert_eq!(Source::from(v4).to_string(), "1.2.3.4");
+        let mapped: std::net::IpAddr = "::ffff:1.2.3.4".parse().unwrap();
+        assert_eq!(Source::from(mapped), Source::from(v4));
+        let a: std::net::IpAddr = "2001:db8:1:2:3:4:5:6".parse().unwrap();
+        let b: std::net::IpAddr = "2001:db8:1:2:ffff::1".parse().unwrap();
+        assert_eq!(Source::from(a), Source::from(b));
+        assert_eq!(Source::from(a).to_string(), "2001:db8:1:2::/64");
+    }
+
+    #[test]
+    fn test_ban() {
+        let cfg = BanConfig {
+            threshold: 3,
+            ..Default::default()
+        };
+        let failures = AuthFailures::new();
+        let ip = "1.2.3.4".parse().unwrap();
+        assert!(failures.record_failure(ip, &cfg).is_none());
+        assert!(failures.record_failure(ip, &cfg).is_none());
+        assert!(!failures.is_banned(ip));
+
+        let ban = failures.record_failure(ip, &cfg).unwrap();
+        assert_eq!(ban.failures, 3);
+        assert!(failures.is_banned(ip));
+        assert!(!failures.is_banned("1.2.3.5".parse().unwrap()));
+        assert_eq!(failures.bans().len(), 1);
+
+        // disabled
+        let ip = "5.6.7.8".parse().unwrap();
+        for _ in 0..5 {
+            failures.record_failure(ip, &BanConfig::default());
+        }
+        assert!(!failures.is_banned(ip));
+        failures.purge(&cfg);
+        assert_eq!(failures.bans().len(), 1);
+
+        // loopback, like every peer behind a plugin
+        for ip in ["127.0.0.1", "::1", "::ffff:127.0.0.1"] {
+            let ip = ip.parse().unwrap();
+            for _ in 0..5 {
+                assert!(failures.record_failure(ip, &cfg).is_none());
+            }
+            assert!(!failures.is_banned(ip));
+        }
+    }
+}
@@ -1,18 +1,22 @@
 use std::{
     fmt::{self, Formatter},
     io,
-    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    str::FromStr,
+    time::Duration,
 };
 
 use bytes::BufMut;
 use tokio::{
     io::{AsyncRead, AsyncReadExt},
-    net::TcpStream,
+    net::{lookup_host, TcpStream},
+    time,
 };
 
 use crate::consts::*;
+use crate::net::{self, TcpConfig};
 
-#[derive(PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Address {
     SocketAddress(SocketAddr),
     DomainNameAddress(String, u16), // domain name, port
@@ -67,6 +71,20 @@ impl Address {
         }
     }
 
+    /// read with a deadline, so a peer which sends nothing or trickles bytes can't hold the task
+    pub async fn read_from_with_timeout<R>(
+        stream: &mut R,
+        timeout: Duration,
+    ) -> Result<Address, Error>
+    where
+        R: AsyncRead + Unpin,
+    {
+        match time::timeout(timeout, Self::read_from(stream)).await {
+            Ok(result) => result,
+            Err(_) => Err(Error::HandshakeTimeout),
+        }
+    }
+
     pub fn write_socket_addr_to_buf<B: BufMut>(addr: &SocketAddr, buf: &mut B) {
         match *addr {
             SocketAddr::V4(ref addr) => {
@@ -84,6 +102,27 @@ impl Address {
         }
     }
 
+    /// socks5 style address as read by [`Address::read_from`], domain names longer than
+    /// 255 bytes can't be written
+    pub fn write_to_buf<B: BufMut>(&self, buf: &mut B) -> io::Result<()> {
+        match *self {
+            Address::SocketAddress(ref addr) => Self::write_socket_addr_to_buf(addr, buf),
+            Address::DomainNameAddress(ref dname, port) => {
+                let len = u8::try_from(dname.len()).map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("domain name too long: {}", dname),
+                    )
+                })?;
+                buf.put_u8(SOCKS5_ADDR_TYPE_DOMAIN_NAME);
+                buf.put_u8(len);
+                buf.put_slice(dname.as_bytes());
+                buf.put_u16(port);
+            }
+        }
+        Ok(())
+    }
+
     pub fn port(&self) -> u16 {
         match *self {
             Address::SocketAddress(addr) => addr.port(),
@@ -99,13 +138,36 @@ impl Address {
     }
 
     pub async fn connect(&self) -> io::Result<TcpStream> {
-        let stream = match *self {
-            Address::SocketAddress(ref sa) => TcpStream::connect(sa).await?,
+        self.connect_with(&TcpConfig::default()).await
+    }
+
+    /// connect with socket options, domain name will try every resolved address in order.
+    /// time of resolving is recorded as `resolve_ms` of the current span if it has the field
+    pub async fn connect_with(&self, cfg: &TcpConfig) -> io::Result<TcpStream> {
+        self.connect_with_data(cfg, &[]).await
+    }
+
+    /// like [`Address::connect_with`] and send `data` first, carried in the SYN with
+    /// `fast_open`. an address is only used once its handshake completed, see
+    /// [`net::connect_with_data`]
+    pub async fn connect_with_data(&self, cfg: &TcpConfig, data: &[u8]) -> io::Result<TcpStream> {
+        match *self {
+            Address::SocketAddress(sa) => net::connect_with_data(sa, cfg, data).await,
             Address::DomainNameAddress(ref dname, port) => {
-                TcpStream::connect((dname.as_str(), port)).await?
+                let start = std::time::Instant::now();
+                let addrs = lookup_host((dname.as_str(), port)).await?;
+                tracing::Span::current().record("resolve_ms", start.elapsed().as_millis() as u64);
+                let mut last_err = None;
+                for sa in addrs {
+                    match net::connect_with_data(sa, cfg, data).await {
+                        Ok(stream) => return Ok(stream),
+                        Err(e) => last_err = Some(e),
+                    }
+                }
+                Err(last_err
+                    .unwrap_or_else(|| io::Error::other(format!("dns resolve empty: {}", dname))))
             }
-        };
-        Ok(stream)
+        }
     }
 }
 
@@ -118,3 +180,29 @@ impl fmt::Display for Address {
         }
     }
 }
+
+/// `host:port` or `[ipv6]:port`
+impl FromStr for Address {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid address: {}", s),
+            )
+        };
+        let (host, port) = match s.strip_prefix('[') {
+            Some(rest) => rest.split_once("]:").ok_or_else(err)?,
+            None => s.rsplit_once(':').ok_or_else(err)?,
+        };
+        let port = port.parse::<u16>().map_err(|_| err())?;
+        match host.parse::<IpAddr>() {
+            Ok(ip) => Ok(Address::SocketAddress(SocketAddr::new(ip, port))),
+            Err(_) if !host.is_empty() && !host.contains(['[', ']', ':']) => {
+                Ok(Address::DomainNameAddress(host.to_string(), port))
+            }
+            Err(_) => Err(err()),
+        }
+    }
+}
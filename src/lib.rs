@@ -19,7 +19,7 @@
 //!     let target_addr = Address::read_from(&mut ss).await.unwrap();
 //!     let mut target = target_addr.connect().await.unwrap();
 //!
-//!     tokio::io::copy_bidirectional(&mut ss, &mut target).await;
+//!     relay::relay(&mut ss, &mut target, &relay::RelayConfig::default()).await;
 //!     Ok(())
 //! }
 //! ```
@@ -55,14 +55,25 @@
 
 pub mod consts;
 pub use consts::Error;
+pub mod client;
 pub mod crypto;
+pub mod defense;
+pub mod limit;
+pub mod mux;
 pub use crypto::kind::CipherKind;
 pub use crypto::Stream;
 mod handshake;
 pub use handshake::Address;
 mod udprelay;
-pub use udprelay::UdpServer;
+pub use udprelay::{UdpAssociation, UdpPolicy, UdpServer, UdpUsers};
+pub mod net;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod outbound;
 pub mod plugin;
+pub mod relay;
+#[cfg(feature = "tls")]
+pub mod tls;
 pub mod util;
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
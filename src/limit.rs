@@ -0,0 +1,595 @@
+//! token bucket bandwidth limits and connection limits
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{future, ready};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::watch,
+    time::{self, Instant, Sleep},
+};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LimitConfig {
+    /// bytes/s from client to target, 0 means no limit
+    pub upload: u64,
+    /// bytes/s from target to client, 0 means no limit
+    pub download: u64,
+    /// max concurrent tcp connections, 0 means no limit
+    pub max_connections: usize,
+    /// max new tcp connections per second, 0 means no limit
+    pub new_connections_per_sec: u64,
+}
+
+/// token bucket which can go into debt, so a large read or write is never split forever,
+/// the following ones wait until the debt is paid
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// `rate` tokens per second, at most one second of tokens saved
+    pub fn new(rate: u64) -> Self {
+        assert!(rate > 0, "rate must be positive");
+        TokenBucket {
+            rate: rate as f64,
+            burst: rate as f64,
+            state: Mutex::new(BucketState {
+                tokens: rate as f64,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    fn refill(&self, state: &mut BucketState, now: Instant) {
+        let elapsed = now.saturating_duration_since(state.last).as_secs_f64();
+        state.tokens = f64::min(self.burst, state.tokens + elapsed * self.rate);
+        state.last = now;
+    }
+
+    /// whole tokens available now, or how long to wait for one
+    pub fn available(&self) -> Result<u64, Duration> {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state, Instant::now());
+        if state.tokens >= 1.0 {
+            Ok(state.tokens as u64)
+        } else {
+            Err(Duration::from_secs_f64((1.0 - state.tokens) / self.rate))
+        }
+    }
+
+    pub fn take(&self, n: u64) {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state, Instant::now());
+        state.tokens -= n as f64;
+    }
+
+    /// take one token if available
+    pub fn try_take_one(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state, Instant::now());
+        if state.tokens >= 1.0 {
+            state.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// a flow direction limited by all of the buckets, e.g. server wide and user
+#[derive(Clone, Default)]
+pub struct Buckets(Vec<Arc<TokenBucket>>);
+
+impl Buckets {
+    pub fn new(buckets: Vec<Arc<TokenBucket>>) -> Self {
+        Buckets(buckets)
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// tokens available in every bucket, or how long to wait until they are
+    pub fn available(&self) -> Result<u64, Duration> {
+        let mut available = u64::MAX;
+        let mut wait = Duration::ZERO;
+        for b in &self.0 {
+            match b.available() {
+                Ok(n) => available = available.min(n),
+                Err(w) => wait = wait.max(w),
+            }
+        }
+        if wait.is_zero() {
+            Ok(available)
+        } else {
+            Err(wait)
+        }
+    }
+
+    pub fn take(&self, n: u64) {
+        for b in &self.0 {
+            b.take(n);
+        }
+    }
+
+    /// wait until tokens are available then take `n`
+    pub async fn acquire(&self, n: u64) {
+        while let Err(wait) = self.available() {
+            time::sleep(wait).await;
+        }
+        self.take(n);
+    }
+}
+
+/// traffic of a user counted by its flows, which stop once it is blocked by quota or expiry
+pub struct Usage {
+    upload: AtomicU64,
+    download: AtomicU64,
+    quota: AtomicU64, // upload + download, u64::MAX means no quota
+    expired: AtomicBool,
+    blocked: watch::Sender<bool>,
+}
+
+impl Default for Usage {
+    fn default() -> Self {
+        Usage {
+            upload: AtomicU64::new(0),
+            download: AtomicU64::new(0),
+            quota: AtomicU64::new(u64::MAX),
+            expired: AtomicBool::new(false),
+            blocked: watch::Sender::new(false),
+        }
+    }
+}
+
+impl Usage {
+    pub fn new(upload: u64, download: u64) -> Self {
+        let usage = Usage::default();
+        usage.upload.store(upload, Ordering::Relaxed);
+        usage.download.store(download, Ordering::Relaxed);
+        usage
+    }
+
+    pub fn upload(&self) -> u64 {
+        self.upload.load(Ordering::Relaxed)
+    }
+
+    pub fn download(&self) -> u64 {
+        self.download.load(Ordering::Relaxed)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.upload().saturating_add(self.download())
+    }
+
+    pub fn add_upload(&self, n: u64) {
+        self.upload.fetch_add(n, Ordering::Relaxed);
+        self.update();
+    }
+
+    pub fn add_download(&self, n: u64) {
+        self.download.fetch_add(n, Ordering::Relaxed);
+        self.update();
+    }
+
+    /// start a new quota period
+    pub fn reset(&self) {
+        self.upload.store(0, Ordering::Relaxed);
+        self.download.store(0, Ordering::Relaxed);
+        self.update();
+    }
+
+    /// None means no quota
+    pub fn set_quota(&self, quota: Option<u64>) {
+        self.quota
+            .store(quota.unwrap_or(u64::MAX), Ordering::Relaxed);
+        self.update();
+    }
+
+    pub fn set_expired(&self, expired: bool) {
+        self.expired.store(expired, Ordering::Relaxed);
+        self.update();
+    }
+
+    fn update(&self) {
+        let blocked = self.expired.load(Ordering::Relaxed)
+            || self.total() >= self.quota.load(Ordering::Relaxed);
+        self.blocked.send_if_modified(|b| {
+            let modified = *b != blocked;
+            *b = blocked;
+            modified
+        });
+    }
+
+    /// over quota or expired
+    pub fn is_blocked(&self) -> bool {
+        *self.blocked.borrow()
+    }
+
+    /// resolves once blocked
+    pub async fn blocked(&self) {
+        let mut rx = self.blocked.subscribe();
+        let _ = rx.wait_for(|b| *b).await;
+    }
+}
+
+/// bandwidth limits of a flow, upload is client to target, traffic is counted into every
+/// one of `usage`, e.g. quota and statistics
+#[derive(Clone, Default)]
+pub struct FlowLimit {
+    pub upload: Buckets,
+    pub download: Buckets,
+    pub usage: Vec<Arc<Usage>>,
+}
+
+impl FlowLimit {
+    /// limited by all of the limiters
+    pub fn new(limiters: &[&Limiter]) -> Self {
+        FlowLimit {
+            upload: Buckets(limiters.iter().filter_map(|l| l.upload.clone()).collect()),
+            download: Buckets(limiters.iter().filter_map(|l| l.download.clone()).collect()),
+            usage: Vec::new(),
+        }
+    }
+
+    pub fn with_usage(mut self, usage: Arc<Usage>) -> Self {
+        self.usage.push(usage);
+        self
+    }
+
+    pub fn is_blocked(&self) -> bool {
+        self.usage.iter().any(|u| u.is_blocked())
+    }
+
+    /// resolves once any usage is blocked
+    pub async fn blocked(&self) {
+        if self.usage.is_empty() {
+            return future::pending().await;
+        }
+        future::select_all(self.usage.iter().map(|u| Box::pin(u.blocked()))).await;
+    }
+
+    /// wait for bandwidth of a packet from client, false if blocked
+    pub async fn upload_packet(&self, n: usize) -> bool {
+        self.upload.acquire(n as u64).await;
+        if self.is_blocked() {
+            return false;
+        }
+        self.usage.iter().for_each(|u| u.add_upload(n as u64));
+        true
+    }
+
+    /// wait for bandwidth of a packet to client, false if blocked
+    pub async fn download_packet(&self, n: usize) -> bool {
+        self.download.acquire(n as u64).await;
+        if self.is_blocked() {
+            return false;
+        }
+        self.usage.iter().for_each(|u| u.add_download(n as u64));
+        true
+    }
+}
+
+/// limits of a scope, such as the whole server or a user
+pub struct Limiter {
+    cfg: LimitConfig,
+    upload: Option<Arc<TokenBucket>>,
+    download: Option<Arc<TokenBucket>>,
+    new_connections: Option<TokenBucket>,
+    connections: Arc<AtomicUsize>,
+}
+
+fn bucket(rate: u64) -> Option<TokenBucket> {
+    if rate == 0 {
+        None
+    } else {
+        Some(TokenBucket::new(rate))
+    }
+}
+
+impl Limiter {
+    pub fn new(cfg: &LimitConfig) -> Self {
+        Limiter {
+            cfg: cfg.clone(),
+            upload: bucket(cfg.upload).map(Arc::new),
+            download: bucket(cfg.download).map(Arc::new),
+            new_connections: bucket(cfg.new_connections_per_sec),
+            connections: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// limiter of a new config, connections of this one keep being counted
+    pub fn with_config(&self, cfg: &LimitConfig) -> Self {
+        Limiter {
+            connections: self.connections.clone(),
+            ..Self::new(cfg)
+        }
+    }
+
+    pub fn config(&self) -> &LimitConfig {
+        &self.cfg
+    }
+
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    /// count a new connection until the guard is dropped, None when over the limits
+    pub fn try_connect(&self) -> Option<ConnectionGuard> {
+        if let Some(ref b) = self.new_connections {
+            if !b.try_take_one() {
+                return None;
+            }
+        }
+        let max = self.cfg.max_connections;
+        let counted = self
+            .connections
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                if max != 0 && n >= max {
+                    None
+                } else {
+                    Some(n + 1)
+                }
+            });
+        counted
+            .ok()
+            .map(|_| ConnectionGuard(self.connections.clone()))
+    }
+}
+
+pub struct ConnectionGuard(Arc<AtomicUsize>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// stream with reads limited by `read` and writes by `write`, waits on a timer when
+/// out of tokens
+pub struct RateLimited<S> {
+    inner: S,
+    read: Buckets,
+    write: Buckets,
+    usage: Vec<Arc<Usage>>,
+    read_sleep: Option<Pin<Box<Sleep>>>,
+    write_sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> RateLimited<S> {
+    pub fn new(inner: S, read: Buckets, write: Buckets) -> Self {
+        RateLimited {
+            inner,
+            read,
+            write,
+            usage: Vec::new(),
+            read_sleep: None,
+            write_sleep: None,
+        }
+    }
+
+    /// stream to client of a flow, reads are upload and writes are download
+    pub fn from_flow(inner: S, flow: FlowLimit) -> Self {
+        RateLimited {
+            usage: flow.usage,
+            ..Self::new(inner, flow.upload, flow.download)
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+/// tokens available now, or wait on the timer until they are
+fn poll_tokens(
+    cx: &mut Context,
+    buckets: &Buckets,
+    sleep: &mut Option<Pin<Box<Sleep>>>,
+) -> Poll<u64> {
+    loop {
+        if let Some(s) = sleep {
+            ready!(s.as_mut().poll(cx));
+            *sleep = None;
+        }
+        match buckets.available() {
+            Ok(n) => return Poll::Ready(n),
+            Err(wait) => *sleep = Some(Box::pin(time::sleep(wait))),
+        }
+    }
+}
+
+impl<S> AsyncRead for RateLimited<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let n = if this.read.is_unlimited() {
+            let filled = buf.filled().len();
+            ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+            buf.filled().len() - filled
+        } else {
+            let tokens = ready!(poll_tokens(cx, &this.read, &mut this.read_sleep));
+            let max = usize::try_from(tokens)
+                .unwrap_or(usize::MAX)
+                .min(buf.remaining());
+            let mut limited = buf.take(max);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+            let n = limited.filled().len();
+            // the limited buf shares memory with buf, n bytes were written into it
+            unsafe { buf.assume_init(n) };
+            buf.advance(n);
+            this.read.take(n as u64);
+            n
+        };
+        this.usage.iter().for_each(|u| u.add_upload(n as u64));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for RateLimited<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let n = if this.write.is_unlimited() {
+            ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?
+        } else {
+            let tokens = ready!(poll_tokens(cx, &this.write, &mut this.write_sleep));
+            let max = usize::try_from(tokens).unwrap_or(usize::MAX).min(buf.len());
+            let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..max]))?;
+            this.write.take(n as u64);
+            n
+        };
+        this.usage.iter().for_each(|u| u.add_download(n as u64));
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use tokio::{
+        io::{duplex, AsyncReadExt, AsyncWriteExt},
+        time::Instant,
+    };
+
+    use super::{Buckets, FlowLimit, LimitConfig, Limiter, RateLimited, TokenBucket, Usage};
+
+    #[tokio::test]
+    async fn test_rate_limited_read() {
+        let (mut client, server) = duplex(64 * 1024);
+        let read = Buckets::new(vec![Arc::new(TokenBucket::new(1000))]);
+        let mut server = RateLimited::new(server, read, Buckets::default());
+
+        client.write_all(&[0u8; 1500]).await.unwrap();
+        let start = Instant::now();
+        let mut buf = [0u8; 1500];
+        server.read_exact(&mut buf).await.unwrap();
+        // one second of burst, then 1000 bytes/s
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(450), "{:?}", elapsed);
+        assert!(elapsed <= Duration::from_millis(1000), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_write_shared() {
+        let bucket = Arc::new(TokenBucket::new(1000));
+        let (a, mut a_peer) = duplex(64 * 1024);
+        let (b, mut b_peer) = duplex(64 * 1024);
+        let mut a = RateLimited::new(a, Buckets::default(), Buckets::new(vec![bucket.clone()]));
+        let mut b = RateLimited::new(b, Buckets::default(), Buckets::new(vec![bucket]));
+
+        let start = Instant::now();
+        let (ra, rb) = tokio::join!(a.write_all(&[0u8; 750]), b.write_all(&[0u8; 750]));
+        ra.unwrap();
+        rb.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(450));
+
+        let mut buf = [0u8; 750];
+        a_peer.read_exact(&mut buf).await.unwrap();
+        b_peer.read_exact(&mut buf).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connection_limit() {
+        let limiter = Limiter::new(&LimitConfig {
+            max_connections: 2,
+            ..Default::default()
+        });
+        let first = limiter.try_connect().unwrap();
+        let _second = limiter.try_connect().unwrap();
+        assert!(limiter.try_connect().is_none());
+        drop(first);
+        assert!(limiter.try_connect().is_some());
+
+        // counted across config change
+        let limiter = limiter.with_config(&LimitConfig {
+            max_connections: 1,
+            ..Default::default()
+        });
+        assert_eq!(limiter.connections(), 1);
+        assert!(limiter.try_connect().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_new_connection_rate() {
+        let limiter = Limiter::new(&LimitConfig {
+            new_connections_per_sec: 2,
+            ..Default::default()
+        });
+        assert!(limiter.try_connect().is_some());
+        assert!(limiter.try_connect().is_some());
+        assert!(limiter.try_connect().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_usage_quota() {
+        let usage = Arc::new(Usage::default());
+        usage.set_quota(Some(10));
+        let (mut client, server) = duplex(1024);
+        let flow = FlowLimit::default().with_usage(usage.clone());
+        let mut server = RateLimited::from_flow(server, flow);
+
+        let blocked = tokio::spawn({
+            let usage = usage.clone();
+            async move { usage.blocked().await }
+        });
+        client.write_all(&[0u8; 6]).await.unwrap();
+        let mut buf = [0u8; 6];
+        server.read_exact(&mut buf).await.unwrap();
+        server.write_all(&buf).await.unwrap();
+        assert_eq!(usage.upload(), 6);
+        assert_eq!(usage.download(), 6);
+        assert!(usage.is_blocked());
+        blocked.await.unwrap();
+
+        // new period
+        usage.reset();
+        assert!(!usage.is_blocked());
+        usage.set_expired(true);
+        assert!(usage.is_blocked());
+    }
+}
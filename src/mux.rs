@@ -0,0 +1,638 @@
+//! multiplexing of logical streams inside one shadowsocks stream, so flows share one salt and
+//! handshake. the client connects [`mux_address`] as target, then both sides exchange frames:
+//!
+//! ```text
+//! +-----------+------+--------+---------+
+//! | stream id | type | length | payload |
+//! +-----------+------+--------+---------+
+//! |    u32    |  u8  |  u16   |         |
+//! +-----------+------+--------+---------+
+//! ```
+//!
+//! `OPEN` carries the target address of a new stream, the client uses odd ids. `DATA` carries
+//! bytes of the stream, `WINDOW` a u32 of bytes the receiver consumed, `FIN` ends one direction
+//! and `RST` aborts the stream. every stream starts with [`INITIAL_WINDOW`] bytes of credit in
+//! both directions, a sender never has more than that unacknowledged.
+//!
+//! when the carrier closes or fails every stream on it is reset. a peer opening more than
+//! `max_streams` streams over the limit while not reading the refusals fails the carrier.
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    task::{Context, Poll, Waker},
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::mpsc,
+    task::JoinHandle,
+};
+use tracing::{debug, trace};
+
+use crate::Address;
+
+/// target of the carrier stream, `.invalid` never resolves so it can't be a real target
+pub const MUX_HOST: &str = "mux.ss-light.invalid";
+/// credit of a new stream in each direction
+pub const INITIAL_WINDOW: u32 = 256 * 1024;
+/// payload of one DATA frame at most
+const MAX_DATA: usize = 16 * 1024;
+const HEADER_LEN: usize = 7;
+
+const OPEN: u8 = 1;
+const DATA: u8 = 2;
+const WINDOW: u8 = 3;
+const FIN: u8 = 4;
+const RST: u8 = 5;
+
+pub fn mux_address() -> Address {
+    Address::DomainNameAddress(MUX_HOST.into(), 0)
+}
+
+/// true for the target of a mux carrier
+pub fn is_mux_address(addr: &Address) -> bool {
+    matches!(addr, Address::DomainNameAddress(host, 0) if host == MUX_HOST)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MuxConfig {
+    /// accept mux carriers, otherwise [`MUX_HOST`] is connected like any target and fails
+    pub enabled: bool,
+    /// open streams of one carrier at most, more are reset
+    pub max_streams: usize,
+}
+
+impl Default for MuxConfig {
+    fn default() -> Self {
+        MuxConfig {
+            enabled: false,
+            max_streams: 128,
+        }
+    }
+}
+
+fn frame(id: u32, kind: u8, payload: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(HEADER_LEN + payload.len());
+    buf.put_u32(id);
+    buf.put_u8(kind);
+    buf.put_u16(payload.len() as u16);
+    buf.put_slice(payload);
+    buf.freeze()
+}
+
+#[derive(Default)]
+struct StreamState {
+    recv: VecDeque<Bytes>,
+    /// credit the peer still has for sending to us
+    recv_window: u32,
+    /// read by the user and not reported to the peer yet
+    consumed: u32,
+    recv_fin: bool,
+    reset: bool,
+    read_waker: Option<Waker>,
+    send_credit: u32,
+    write_waker: Option<Waker>,
+}
+
+impl StreamState {
+    fn new() -> Self {
+        StreamState {
+            recv_window: INITIAL_WINDOW,
+            send_credit: INITIAL_WINDOW,
+            ..Default::default()
+        }
+    }
+
+    fn wake(&mut self) {
+        if let Some(w) = self.read_waker.take() {
+            w.wake();
+        }
+        if let Some(w) = self.write_waker.take() {
+            w.wake();
+        }
+    }
+}
+
+type StreamRef = Arc<Mutex<StreamState>>;
+
+/// state of one carrier shared by its streams and its reader and writer tasks
+struct Session {
+    streams: Mutex<HashMap<u32, StreamRef>>,
+    frames: mpsc::UnboundedSender<Bytes>,
+    closed: AtomicBool,
+    /// streams refused since frames were last flushed, bounds the resets queued for a peer
+    /// which opens streams without reading
+    refused: AtomicUsize,
+}
+
+impl Session {
+    fn send(&self, frame: Bytes) -> io::Result<()> {
+        if self.closed.load(Ordering::Acquire) {
+            return Err(io::ErrorKind::ConnectionReset.into());
+        }
+        self.frames
+            .send(frame)
+            .map_err(|_| io::ErrorKind::ConnectionReset.into())
+    }
+
+    fn get(&self, id: u32) -> Option<StreamRef> {
+        self.streams.lock().unwrap().get(&id).cloned()
+    }
+
+    /// carrier is gone, reset every stream
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        for (_, s) in self.streams.lock().unwrap().drain() {
+            let mut s = s.lock().unwrap();
+            s.reset = true;
+            s.wake();
+        }
+    }
+
+    fn register(self: &Arc<Self>, id: u32) -> MuxStream {
+        let state = Arc::new(Mutex::new(StreamState::new()));
+        self.streams.lock().unwrap().insert(id, state.clone());
+        MuxStream {
+            id,
+            state,
+            session: self.clone(),
+            write_closed: false,
+        }
+    }
+}
+
+/// reader and writer tasks of a carrier, aborted on drop
+struct Tasks {
+    session: Arc<Session>,
+    handles: [JoinHandle<()>; 2],
+}
+
+impl Drop for Tasks {
+    fn drop(&mut self) {
+        for h in &self.handles {
+            h.abort();
+        }
+        self.session.close();
+    }
+}
+
+/// split the carrier into a reader and a writer task, `opened` gets streams opened by the peer
+fn start<S>(
+    carrier: S,
+    opened: Option<(mpsc::UnboundedSender<(Address, MuxStream)>, usize)>,
+) -> Tasks
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (tx, rx) = mpsc::unbounded_channel();
+    let session = Arc::new(Session {
+        streams: Mutex::new(HashMap::new()),
+        frames: tx,
+        closed: AtomicBool::new(false),
+        refused: AtomicUsize::new(0),
+    });
+    let (r, w) = tokio::io::split(carrier);
+    let reader = tokio::spawn(read_frames(r, Arc::downgrade(&session), opened));
+    let writer = tokio::spawn(write_frames(w, rx, Arc::downgrade(&session)));
+    Tasks {
+        session,
+        handles: [reader, writer],
+    }
+}
+
+async fn write_frames<W: AsyncWrite>(
+    w: W,
+    mut rx: mpsc::UnboundedReceiver<Bytes>,
+    session: Weak<Session>,
+) {
+    tokio::pin!(w);
+    let res: io::Result<()> = async {
+        while let Some(frame) = rx.recv().await {
+            w.write_all(&frame).await?;
+            while let Ok(frame) = rx.try_recv() {
+                w.write_all(&frame).await?;
+            }
+            w.flush().await?;
+            if let Some(s) = session.upgrade() {
+                s.refused.store(0, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+    .await;
+    if let Err(e) = res {
+        debug!("mux carrier write error: {}", e);
+    }
+    if let Some(s) = session.upgrade() {
+        s.close();
+    }
+}
+
+async fn read_frames<R: AsyncRead>(
+    r: R,
+    session: Weak<Session>,
+    opened: Option<(mpsc::UnboundedSender<(Address, MuxStream)>, usize)>,
+) {
+    tokio::pin!(r);
+    let mut header = [0u8; HEADER_LEN];
+    let res: io::Result<()> = async {
+        loop {
+            match r.read_exact(&mut header).await {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            }
+            let mut h = &header[..];
+            let (id, kind, len) = (h.get_u32(), h.get_u8(), h.get_u16() as usize);
+            let mut payload = vec![0u8; len];
+            r.read_exact(&mut payload).await?;
+            let Some(session) = session.upgrade() else {
+                return Ok(());
+            };
+            handle_frame(&session, id, kind, payload.into(), opened.as_ref())?;
+        }
+    }
+    .await;
+    if let Err(e) = res {
+        debug!("mux carrier read error: {}", e);
+    }
+    if let Some(s) = session.upgrade() {
+        s.close();
+    }
+}
+
+fn handle_frame(
+    session: &Arc<Session>,
+    id: u32,
+    kind: u8,
+    mut payload: Bytes,
+    opened: Option<&(mpsc::UnboundedSender<(Address, MuxStream)>, usize)>,
+) -> io::Result<()> {
+    trace!("mux frame id:{} type:{} len:{}", id, kind, payload.len());
+    if kind == OPEN {
+        let Some((opened, max_streams)) = opened else {
+            return Err(invalid("stream opened by the server"));
+        };
+        if id.is_multiple_of(2) || session.get(id).is_some() {
+            return Err(invalid("bad stream id"));
+        }
+        if session.streams.lock().unwrap().len() >= *max_streams {
+            debug!("mux stream {} refused, over {} streams", id, max_streams);
+            if session.refused.fetch_add(1, Ordering::Relaxed) >= *max_streams {
+                return Err(invalid("too many streams refused and not read"));
+            }
+            return session.send(frame(id, RST, &[]));
+        }
+        // reading from a slice never waits
+        let target = match Address::read_from(&mut payload.as_ref()).now_or_never() {
+            Some(Ok(target)) => target,
+            Some(Err(e)) => return Err(invalid(&format!("bad target of stream {}: {}", id, e))),
+            None => unreachable!("read from a slice"),
+        };
+        let stream = session.register(id);
+        // the receiver is gone, dropping the stream resets it
+        let _ = opened.send((target, stream));
+        return Ok(());
+    }
+
+    // frames of streams dropped already are ignored
+    let Some(stream) = session.get(id) else {
+        return Ok(());
+    };
+    if kind == RST {
+        session.streams.lock().unwrap().remove(&id);
+        reset(&stream);
+        return Ok(());
+    }
+    // the session lock is never taken while holding a stream lock
+    let mut s = stream.lock().unwrap();
+    match kind {
+        DATA => {
+            let len = payload.len() as u32;
+            if len > s.recv_window {
+                // a peer ignoring flow control, reset the stream only
+                drop(s);
+                session.streams.lock().unwrap().remove(&id);
+                reset(&stream);
+                return session.send(frame(id, RST, &[]));
+            }
+            s.recv_window -= len;
+            if !payload.is_empty() {
+                s.recv.push_back(payload);
+            }
+        }
+        WINDOW if payload.len() == 4 => {
+            s.send_credit = s.send_credit.saturating_add(payload.get_u32());
+        }
+        FIN => s.recv_fin = true,
+        _ => return Err(invalid(&format!("bad frame type {}", kind))),
+    }
+    s.wake();
+    Ok(())
+}
+
+fn reset(stream: &StreamRef) {
+    let mut s = stream.lock().unwrap();
+    s.reset = true;
+    s.wake();
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// logical stream of a carrier, dropping it before both directions finished resets it
+pub struct MuxStream {
+    id: u32,
+    state: StreamRef,
+    session: Arc<Session>,
+    write_closed: bool,
+}
+
+impl MuxStream {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut s = self.state.lock().unwrap();
+        if let Some(front) = s.recv.front_mut() {
+            let n = front.len().min(buf.remaining());
+            buf.put_slice(&front.split_to(n));
+            if front.is_empty() {
+                s.recv.pop_front();
+            }
+            s.consumed += n as u32;
+            // report in batches, not for every read
+            if s.consumed >= INITIAL_WINDOW / 2 && !s.reset {
+                let consumed = std::mem::take(&mut s.consumed);
+                s.recv_window += consumed;
+                let _ = self
+                    .session
+                    .send(frame(self.id, WINDOW, &consumed.to_be_bytes()));
+            }
+            return Poll::Ready(Ok(()));
+        }
+        if s.reset {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        if s.recv_fin {
+            return Poll::Ready(Ok(()));
+        }
+        s.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut s = self.state.lock().unwrap();
+        if s.reset {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        if self.write_closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        if s.send_credit == 0 {
+            s.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = buf.len().min(MAX_DATA).min(s.send_credit as usize);
+        s.send_credit -= n as u32;
+        self.session.send(frame(self.id, DATA, &buf[..n]))?;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // frames are flushed by the writer task
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.write_closed {
+            self.write_closed = true;
+            if !self.state.lock().unwrap().reset {
+                self.session.send(frame(self.id, FIN, &[]))?;
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        self.session.streams.lock().unwrap().remove(&self.id);
+        let s = self.state.lock().unwrap();
+        if !(s.reset || self.write_closed && s.recv_fin) {
+            let _ = self.session.send(frame(self.id, RST, &[]));
+        }
+    }
+}
+
+/// opens logical streams on a carrier connected to [`mux_address`]. dropping it closes the
+/// carrier and resets its streams
+pub struct MuxClient {
+    tasks: Tasks,
+    next_id: AtomicU32,
+}
+
+impl MuxClient {
+    pub fn new<S>(carrier: S) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        MuxClient {
+            tasks: start(carrier, None),
+            next_id: AtomicU32::new(1),
+        }
+    }
+
+    /// stream to `target`, data can be written right away. a refused stream is reset
+    pub fn open(&self, target: &Address) -> io::Result<MuxStream> {
+        let mut payload = BytesMut::new();
+        target.write_to_buf(&mut payload)?;
+        let id = self.next_id.fetch_add(2, Ordering::Relaxed);
+        let session = &self.tasks.session;
+        let stream = session.register(id);
+        session.send(frame(id, OPEN, &payload))?;
+        Ok(stream)
+    }
+
+    /// carrier closed or failed, no more streams can be opened
+    pub fn is_closed(&self) -> bool {
+        self.tasks.session.closed.load(Ordering::Acquire)
+    }
+
+    /// streams open on the carrier
+    pub fn streams(&self) -> usize {
+        self.tasks.session.streams.lock().unwrap().len()
+    }
+}
+
+/// accepts logical streams of a carrier. dropping it closes the carrier and resets its
+/// streams
+pub struct MuxServer {
+    _tasks: Tasks,
+    opened: mpsc::UnboundedReceiver<(Address, MuxStream)>,
+}
+
+impl MuxServer {
+    pub fn new<S>(carrier: S, max_streams: usize) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (tx, rx) = mpsc::unbounded_channel();
+        MuxServer {
+            _tasks: start(carrier, Some((tx, max_streams))),
+            opened: rx,
+        }
+    }
+
+    /// next stream with its target, None once the carrier is closed
+    pub async fn accept(&mut self) -> Option<(Address, MuxStream)> {
+        self.opened.recv().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    fn pair(max_streams: usize) -> (MuxClient, MuxServer) {
+        let (a, b) = duplex(64 * 1024);
+        (MuxClient::new(a), MuxServer::new(b, max_streams))
+    }
+
+    #[tokio::test]
+    async fn test_streams() {
+        let (client, mut server) = pair(16);
+        let t1 = Address::DomainNameAddress("example.org".into(), 80);
+        let t2 = Address::SocketAddress("127.0.0.1:443".parse().unwrap());
+        let mut c1 = client.open(&t1).unwrap();
+        let mut c2 = client.open(&t2).unwrap();
+        c1.write_all(b"one").await.unwrap();
+        c2.write_all(b"two").await.unwrap();
+
+        let (target, mut s1) = server.accept().await.unwrap();
+        assert_eq!(target, t1);
+        let (target, mut s2) = server.accept().await.unwrap();
+        assert_eq!(target, t2);
+        let mut buf = [0u8; 3];
+        s2.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"two");
+        s1.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"one");
+
+        // half close, the other direction still works
+        c1.shutdown().await.unwrap();
+        assert_eq!(s1.read(&mut buf).await.unwrap(), 0);
+        s1.write_all(b"bye").await.unwrap();
+        s1.shutdown().await.unwrap();
+        let mut rest = vec![];
+        c1.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"bye");
+        drop((c1, s1));
+
+        // dropped before finishing is reset
+        drop(s2);
+        let err = c2.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        drop(c2);
+        tokio::task::yield_now().await;
+        assert_eq!(client.streams(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_flow_control() {
+        let (client, mut server) = pair(16);
+        let mut c = client.open(&mux_address()).unwrap();
+        let (_, mut s) = server.accept().await.unwrap();
+
+        // the writer stops at the window until the reader consumes
+        let data: Vec<u8> = (0..INITIAL_WINDOW as usize * 4).map(|i| i as u8).collect();
+        let expected = data.clone();
+        let writer = tokio::spawn(async move {
+            c.write_all(&data).await.unwrap();
+            c.shutdown().await.unwrap();
+            c
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!writer.is_finished());
+        {
+            let st = s.state.lock().unwrap();
+            let queued: usize = st.recv.iter().map(|b| b.len()).sum();
+            assert_eq!(queued, INITIAL_WINDOW as usize);
+        }
+        let mut received = vec![];
+        s.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, expected);
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_teardown() {
+        let (client, mut server) = pair(1);
+        let mut c1 = client.open(&mux_address()).unwrap();
+        let (_, mut s1) = server.accept().await.unwrap();
+        // over max_streams
+        let mut c2 = client.open(&mux_address()).unwrap();
+        let mut buf = [0u8; 1];
+        let err = c2.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+
+        // the carrier dies, every stream on both sides is reset
+        drop(client);
+        let err = s1.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        assert!(server.accept().await.is_none());
+        let err = c1.write(b"x").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[tokio::test]
+    async fn test_refused_not_read() {
+        // the peer's reads stall once resets of refused streams fill the pipe
+        let (mut peer, carrier) = duplex(64);
+        let mut server = MuxServer::new(carrier, 2);
+        let mut open = BytesMut::new();
+        mux_address().write_to_buf(&mut open).unwrap();
+        let opens: Vec<u8> = (0..100u32)
+            .flat_map(|i| frame(i * 2 + 1, OPEN, &open).to_vec())
+            .collect();
+        let writer = tokio::spawn(async move {
+            let _ = peer.write_all(&opens).await;
+            peer
+        });
+
+        let (_, s1) = server.accept().await.unwrap();
+        let (_, s2) = server.accept().await.unwrap();
+        assert_eq!((s1.id(), s2.id()), (1, 3));
+        let closed = tokio::time::timeout(std::time::Duration::from_secs(1), server.accept());
+        assert!(closed.await.unwrap().is_none());
+        drop(server);
+        drop(writer.await.unwrap());
+    }
+}
@@ -0,0 +1,351 @@
+//! tcp socket options for inbound listeners and outbound connections
+use std::{io, net::SocketAddr, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpSocket, TcpStream},
+};
+use tracing::debug;
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TcpConfig {
+    /// set TCP_NODELAY
+    pub nodelay: bool,
+    /// sec, enable SO_KEEPALIVE with this idle time, 0 means disabled
+    pub keepalive: u64,
+    /// sec, interval between keepalive probes, 0 means system default
+    pub keepalive_interval: u64,
+    /// number of unacknowledged probes before the connection is dropped, 0 means system default
+    pub keepalive_count: u32,
+    /// ms, TCP_USER_TIMEOUT, 0 means system default
+    pub user_timeout: u64,
+    /// SO_SNDBUF, 0 means system default
+    pub send_buffer_size: usize,
+    /// SO_RCVBUF, 0 means system default
+    pub recv_buffer_size: usize,
+    /// listen backlog
+    pub backlog: u32,
+    /// IPV6_V6ONLY for ipv6 listeners, false means dual-stack when bind to `::`
+    pub ipv6_only: bool,
+    /// number of listeners bound with SO_REUSEPORT, each one has its own accept loop
+    pub acceptors: usize,
+    /// TCP_FASTOPEN on listeners and TCP_FASTOPEN_CONNECT on outbound sockets (linux)
+    pub fast_open: bool,
+    /// open sockets with IPPROTO_MPTCP, fallback to tcp if the kernel doesn't support it (linux)
+    pub mptcp: bool,
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        TcpConfig {
+            nodelay: false,
+            keepalive: 0,
+            keepalive_interval: 0,
+            keepalive_count: 0,
+            user_timeout: 0,
+            send_buffer_size: 0,
+            recv_buffer_size: 0,
+            backlog: 1024,
+            ipv6_only: false,
+            acceptors: 1,
+            fast_open: false,
+            mptcp: false,
+        }
+    }
+}
+
+impl TcpConfig {
+    fn keepalive(&self) -> Option<TcpKeepalive> {
+        if self.keepalive == 0 {
+            return None;
+        }
+        let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(self.keepalive));
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "macos",
+            target_os = "freebsd"
+        ))]
+        let keepalive = {
+            let mut keepalive = keepalive;
+            if self.keepalive_interval != 0 {
+                keepalive = keepalive.with_interval(Duration::from_secs(self.keepalive_interval));
+            }
+            if self.keepalive_count != 0 {
+                keepalive = keepalive.with_retries(self.keepalive_count);
+            }
+            keepalive
+        };
+        Some(keepalive)
+    }
+
+    /// options that can be set both before connect and on an accepted socket
+    fn apply_stream_opts(&self, socket: &SockRef) -> io::Result<()> {
+        if self.nodelay {
+            socket.set_nodelay(true)?;
+        }
+        if let Some(keepalive) = self.keepalive() {
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if self.user_timeout != 0 {
+            socket.set_tcp_user_timeout(Some(Duration::from_millis(self.user_timeout)))?;
+        }
+        Ok(())
+    }
+
+    fn apply_buffer_opts(&self, socket: &SockRef) -> io::Result<()> {
+        if self.send_buffer_size != 0 {
+            socket.set_send_buffer_size(self.send_buffer_size)?;
+        }
+        if self.recv_buffer_size != 0 {
+            socket.set_recv_buffer_size(self.recv_buffer_size)?;
+        }
+        Ok(())
+    }
+
+    /// apply per-connection options to an accepted stream
+    pub fn apply_to_stream(&self, stream: &TcpStream) -> io::Result<()> {
+        self.apply_stream_opts(&SockRef::from(stream))
+    }
+}
+
+/// bind a listener with options, set `reuse_port` when more than one listener share the address
+pub fn bind_listener(
+    addr: SocketAddr,
+    cfg: &TcpConfig,
+    reuse_port: bool,
+) -> io::Result<TcpListener> {
+    let socket = new_socket(addr, cfg)?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    #[cfg(not(unix))]
+    let _ = reuse_port;
+    if addr.is_ipv6() {
+        socket.set_only_v6(cfg.ipv6_only)?;
+    }
+    // accepted sockets inherit buffer sizes from the listener
+    cfg.apply_buffer_opts(&SockRef::from(&socket))?;
+    if cfg.fast_open {
+        #[cfg(target_os = "linux")]
+        set_int_opt(&socket, libc::TCP_FASTOPEN, cfg.backlog as libc::c_int)?;
+        #[cfg(not(target_os = "linux"))]
+        tracing::warn!("tcp fast open is not supported on this platform, ignored");
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(cfg.backlog as i32)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// connect to addr with options set before the handshake
+///
+/// with `fast_open` the handshake is deferred, data of the first write is carried in the SYN
+/// and a failed handshake only shows up later, see [`wait_established`]
+pub async fn connect(addr: SocketAddr, cfg: &TcpConfig) -> io::Result<TcpStream> {
+    connect_opts(addr, cfg, cfg.fast_open).await
+}
+
+/// connect to addr and send `data`, carried in the SYN with `fast_open`. unlike [`connect`]
+/// it returns after the handshake completed, so a refused or unreachable addr is an error here
+pub async fn connect_with_data(
+    addr: SocketAddr,
+    cfg: &TcpConfig,
+    data: &[u8],
+) -> io::Result<TcpStream> {
+    if data.is_empty() {
+        // nothing to carry, a deferred handshake would only hide errors
+        return connect_opts(addr, cfg, false).await;
+    }
+    let mut stream = connect_opts(addr, cfg, cfg.fast_open).await?;
+    stream.write_all(data).await?;
+    wait_established(&stream).await?;
+    Ok(stream)
+}
+
+/// wait until the handshake deferred by fast open completed, an empty send would block until
+/// then and fails with the connect error
+pub async fn wait_established(stream: &TcpStream) -> io::Result<()> {
+    loop {
+        stream.writable().await?;
+        match stream.try_write(&[]) {
+            Ok(_) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+async fn connect_opts(addr: SocketAddr, cfg: &TcpConfig, fast_open: bool) -> io::Result<TcpStream> {
+    let socket = new_socket(addr, cfg)?;
+    let sock_ref = SockRef::from(&socket);
+    cfg.apply_buffer_opts(&sock_ref)?;
+    cfg.apply_stream_opts(&sock_ref)?;
+    if fast_open {
+        #[cfg(target_os = "linux")]
+        set_int_opt(&socket, libc::TCP_FASTOPEN_CONNECT, 1)?;
+        #[cfg(not(target_os = "linux"))]
+        tracing::warn!("tcp fast open is not supported on this platform, ignored");
+    }
+    socket.set_nonblocking(true)?;
+    let socket = TcpSocket::from_std_stream(socket.into());
+    socket.connect(addr).await
+}
+
+fn new_socket(addr: SocketAddr, cfg: &TcpConfig) -> io::Result<Socket> {
+    let domain = Domain::for_address(addr);
+    if cfg.mptcp {
+        #[cfg(target_os = "linux")]
+        match Socket::new(domain, Type::STREAM, Some(socket2::Protocol::MPTCP)) {
+            Ok(socket) => return Ok(socket),
+            Err(e) => debug!("open mptcp socket error: {}, fallback to tcp", e),
+        }
+        #[cfg(not(target_os = "linux"))]
+        debug!("mptcp is not supported on this platform, fallback to tcp");
+    }
+    Socket::new(domain, Type::STREAM, None)
+}
+
+#[cfg(target_os = "linux")]
+fn set_int_opt(socket: &Socket, opt: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            opt,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use socket2::SockRef;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bind_and_connect_with_opts() {
+        let cfg = TcpConfig {
+            nodelay: true,
+            keepalive: 30,
+            keepalive_interval: 5,
+            keepalive_count: 3,
+            ..Default::default()
+        };
+        let listener =
+            bind_listener(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0), &cfg, false).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut client = connect(addr, &cfg).await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        cfg.apply_to_stream(&server).unwrap();
+
+        assert!(client.nodelay().unwrap());
+        assert!(server.nodelay().unwrap());
+        assert!(SockRef::from(&server).keepalive().unwrap());
+
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_reuse_port_acceptors() {
+        let cfg = TcpConfig::default();
+        let first =
+            bind_listener(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0), &cfg, true).unwrap();
+        let addr = first.local_addr().unwrap();
+        let second = bind_listener(addr, &cfg, true).unwrap();
+        assert_eq!(second.local_addr().unwrap(), addr);
+    }
+
+    #[tokio::test]
+    async fn test_dual_stack() {
+        let cfg = TcpConfig::default();
+        let listener = match bind_listener(
+            SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+            &cfg,
+            false,
+        ) {
+            Ok(l) => l,
+            Err(e) => {
+                eprintln!("skip test_dual_stack, bind [::] error: {}", e);
+                return;
+            }
+        };
+        let port = listener.local_addr().unwrap().port();
+        let _client = connect(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port), &cfg)
+            .await
+            .unwrap();
+        listener.accept().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fast_open_and_mptcp() {
+        let cfg = TcpConfig {
+            fast_open: true,
+            mptcp: true,
+            ..Default::default()
+        };
+        let listener =
+            bind_listener(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0), &cfg, false).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // the first write goes out with the SYN
+        let mut client = connect(addr, &cfg).await.unwrap();
+        client.write_all(b"early").await.unwrap();
+
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"early");
+    }
+
+    #[tokio::test]
+    async fn test_connect_with_data_confirmed() {
+        let cfg = TcpConfig {
+            fast_open: true,
+            ..Default::default()
+        };
+        let listener =
+            bind_listener(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0), &cfg, false).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // later connects have a cookie and defer the handshake if the kernel enables fast open
+        for _ in 0..3 {
+            let _client = connect_with_data(addr, &cfg, b"early").await.unwrap();
+            let (mut server, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"early");
+        }
+        let _client = connect_with_data(addr, &cfg, b"").await.unwrap();
+        listener.accept().await.unwrap();
+
+        // a refused handshake fails the connect instead of a later read
+        drop(listener);
+        let err = connect_with_data(addr, &cfg, b"early").await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        let err = connect_with_data(addr, &cfg, b"").await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+    }
+}
@@ -0,0 +1,553 @@
+//! export spans and traffic metrics to an opentelemetry collector over OTLP/HTTP with json
+//! encoding, which needs no protobuf or grpc stack.
+//!
+//! every closed span becomes an OTLP span with its fields as attributes, spans named `tcp`
+//! and `udp` are also counted as connections, and their `upload` and `download` fields summed
+//! as bytes.
+use std::{
+    collections::BTreeMap,
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time,
+};
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id, Record},
+    warn, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+/// finished spans kept until exported, more are dropped
+const MAX_PENDING_SPANS: usize = 4096;
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct OtelConfig {
+    /// base url of the collector, only http
+    pub endpoint: String,
+    pub service_name: String,
+    /// sec, between exports
+    pub interval: u64,
+}
+
+impl Default for OtelConfig {
+    fn default() -> Self {
+        OtelConfig {
+            endpoint: "http://127.0.0.1:4318".into(),
+            service_name: "ss-light".into(),
+            interval: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum AttrValue {
+    Str(String),
+    Int(i64),
+    Double(f64),
+    Bool(bool),
+}
+
+impl AttrValue {
+    fn to_json(&self) -> Value {
+        match self {
+            AttrValue::Str(s) => json!({ "stringValue": s }),
+            // 64 bit integers are strings in OTLP json
+            AttrValue::Int(i) => json!({ "intValue": i.to_string() }),
+            AttrValue::Double(d) => json!({ "doubleValue": d }),
+            AttrValue::Bool(b) => json!({ "boolValue": b }),
+        }
+    }
+}
+
+struct SpanData {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_id: Option<[u8; 8]>,
+    name: &'static str,
+    start: u64,
+    end: u64,
+    attributes: Vec<(&'static str, AttrValue)>,
+}
+
+impl SpanData {
+    fn attribute(&self, key: &str) -> Option<&AttrValue> {
+        self.attributes
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v)
+    }
+}
+
+struct AttrVisitor<'a>(&'a mut Vec<(&'static str, AttrValue)>);
+
+impl AttrVisitor<'_> {
+    fn set(&mut self, field: &Field, value: AttrValue) {
+        match self.0.iter_mut().find(|(k, _)| *k == field.name()) {
+            Some((_, v)) => *v = value,
+            None => self.0.push((field.name(), value)),
+        }
+    }
+}
+
+impl Visit for AttrVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.set(field, AttrValue::Str(format!("{:?}", value)));
+    }
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.set(field, AttrValue::Str(value.into()));
+    }
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.set(field, AttrValue::Int(value));
+    }
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.set(field, AttrValue::Int(value as i64));
+    }
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.set(field, AttrValue::Double(value));
+    }
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.set(field, AttrValue::Bool(value));
+    }
+}
+
+/// metric name, protocol and direction
+type MetricKey = (&'static str, &'static str, Option<&'static str>);
+
+struct Shared {
+    cfg: OtelConfig,
+    start: u64,
+    spans: Mutex<Vec<SpanData>>,
+    dropped: Mutex<u64>,
+    sums: Mutex<BTreeMap<MetricKey, u64>>,
+}
+
+impl Shared {
+    fn finish(&self, span: SpanData) {
+        if matches!(span.name, "tcp" | "udp") {
+            let mut sums = self.sums.lock().unwrap();
+            *sums
+                .entry(("ss_light.connections", span.name, None))
+                .or_default() += 1;
+            for direction in ["upload", "download"] {
+                if let Some(AttrValue::Int(n)) = span.attribute(direction) {
+                    *sums
+                        .entry(("ss_light.bytes", span.name, Some(direction)))
+                        .or_default() += *n as u64;
+                }
+            }
+        }
+        let mut spans = self.spans.lock().unwrap();
+        if spans.len() < MAX_PENDING_SPANS {
+            spans.push(span);
+        } else {
+            *self.dropped.lock().unwrap() += 1;
+        }
+    }
+}
+
+fn unix_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// a span not closed yet, kept in its extensions
+struct OpenSpan(SpanData);
+
+/// records spans, add it to the subscriber
+pub struct OtelLayer {
+    shared: Arc<Shared>,
+}
+
+impl<S> Layer<S> for OtelLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(id) {
+            Some(s) => s,
+            None => return,
+        };
+        let parent = span.parent().and_then(|p| {
+            p.extensions()
+                .get::<OpenSpan>()
+                .map(|o| (o.0.trace_id, o.0.span_id))
+        });
+        let (trace_id, parent_id) = match parent {
+            Some((trace_id, span_id)) => (trace_id, Some(span_id)),
+            None => (rand::random(), None),
+        };
+        let mut data = SpanData {
+            trace_id,
+            span_id: rand::random(),
+            parent_id,
+            name: span.name(),
+            start: unix_nanos(),
+            end: 0,
+            attributes: Vec::new(),
+        };
+        attrs.record(&mut AttrVisitor(&mut data.attributes));
+        span.extensions_mut().insert(OpenSpan(data));
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(o) = span.extensions_mut().get_mut::<OpenSpan>() {
+                values.record(&mut AttrVisitor(&mut o.0.attributes));
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let open = ctx
+            .span(&id)
+            .and_then(|span| span.extensions_mut().remove::<OpenSpan>());
+        if let Some(OpenSpan(mut data)) = open {
+            data.end = unix_nanos();
+            self.shared.finish(data);
+        }
+    }
+}
+
+/// sends what the layer recorded to the collector, clones share the same state
+#[derive(Clone)]
+pub struct OtelExporter {
+    shared: Arc<Shared>,
+}
+
+/// layer to add to the subscriber and the exporter of what it records
+pub fn new(cfg: &OtelConfig) -> (OtelLayer, OtelExporter) {
+    let shared = Arc::new(Shared {
+        cfg: cfg.clone(),
+        start: unix_nanos(),
+        spans: Mutex::default(),
+        dropped: Mutex::default(),
+        sums: Mutex::default(),
+    });
+    (
+        OtelLayer {
+            shared: shared.clone(),
+        },
+        OtelExporter { shared },
+    )
+}
+
+impl OtelExporter {
+    /// export every `interval`
+    pub async fn run(self) {
+        let mut interval = time::interval(Duration::from_secs(self.shared.cfg.interval.max(1)));
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.export().await {
+                warn!("otel export to {} error: {}", self.shared.cfg.endpoint, e);
+            }
+        }
+    }
+
+    /// send finished spans and current metrics, spans failed to send are dropped
+    pub async fn export(&self) -> io::Result<()> {
+        let spans = std::mem::take(&mut *self.shared.spans.lock().unwrap());
+        let dropped = std::mem::take(&mut *self.shared.dropped.lock().unwrap());
+        if dropped > 0 {
+            warn!(
+                "otel dropped {} spans over {} pending",
+                dropped, MAX_PENDING_SPANS
+            );
+        }
+        if !spans.is_empty() {
+            self.post("/v1/traces", &self.traces(&spans)).await?;
+        }
+        let metrics = self.metrics();
+        if let Some(metrics) = metrics {
+            self.post("/v1/metrics", &metrics).await?;
+        }
+        Ok(())
+    }
+
+    fn resource(&self) -> Value {
+        json!({ "attributes": [{
+            "key": "service.name",
+            "value": { "stringValue": self.shared.cfg.service_name },
+        }]})
+    }
+
+    fn scope() -> Value {
+        json!({ "name": "ss-light", "version": crate::VERSION })
+    }
+
+    fn traces(&self, spans: &[SpanData]) -> Value {
+        let spans: Vec<Value> = spans
+            .iter()
+            .map(|s| {
+                let attributes: Vec<Value> = s
+                    .attributes
+                    .iter()
+                    .map(|(k, v)| json!({ "key": k, "value": v.to_json() }))
+                    .collect();
+                // status code 2 is error
+                let status = match s.attribute("error") {
+                    Some(AttrValue::Str(e)) => json!({ "code": 2, "message": e }),
+                    _ => json!({}),
+                };
+                json!({
+                    "traceId": hex(&s.trace_id),
+                    "spanId": hex(&s.span_id),
+                    "parentSpanId": s.parent_id.map(|p| hex(&p)).unwrap_or_default(),
+                    "name": s.name,
+                    // server
+                    "kind": 2,
+                    "startTimeUnixNano": s.start.to_string(),
+                    "endTimeUnixNano": s.end.to_string(),
+                    "attributes": attributes,
+                    "status": status,
+                })
+            })
+            .collect();
+        json!({ "resourceSpans": [{
+            "resource": self.resource(),
+            "scopeSpans": [{ "scope": Self::scope(), "spans": spans }],
+        }]})
+    }
+
+    /// cumulative sums, None if nothing counted yet
+    fn metrics(&self) -> Option<Value> {
+        let sums = self.shared.sums.lock().unwrap().clone();
+        if sums.is_empty() {
+            return None;
+        }
+        let now = unix_nanos().to_string();
+        let mut points: BTreeMap<&str, Vec<Value>> = BTreeMap::new();
+        for ((name, protocol, direction), value) in sums {
+            let mut attributes = vec![json!({
+                "key": "protocol", "value": { "stringValue": protocol },
+            })];
+            if let Some(direction) = direction {
+                attributes.push(json!({
+                    "key": "direction", "value": { "stringValue": direction },
+                }));
+            }
+            points.entry(name).or_default().push(json!({
+                "attributes": attributes,
+                "startTimeUnixNano": self.shared.start.to_string(),
+                "timeUnixNano": now,
+                "asInt": value.to_string(),
+            }));
+        }
+        let metrics: Vec<Value> = points
+            .into_iter()
+            .map(|(name, points)| {
+                let unit = if name == "ss_light.bytes" { "By" } else { "1" };
+                json!({
+                    "name": name,
+                    "unit": unit,
+                    // cumulative
+                    "sum": { "dataPoints": points, "aggregationTemporality": 2, "isMonotonic": true },
+                })
+            })
+            .collect();
+        Some(json!({ "resourceMetrics": [{
+            "resource": self.resource(),
+            "scopeMetrics": [{ "scope": Self::scope(), "metrics": metrics }],
+        }]}))
+    }
+
+    async fn post(&self, path: &str, body: &Value) -> io::Result<()> {
+        let endpoint = &self.shared.cfg.endpoint;
+        let rest = endpoint.strip_prefix("http://").ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unsupported otel endpoint {}, only http://", endpoint),
+            )
+        })?;
+        let (host, base) = match rest.find('/') {
+            Some(i) => (&rest[..i], rest[i..].trim_end_matches('/')),
+            None => (rest, ""),
+        };
+        let body = body.to_string();
+        let req = format!(
+            "POST {}{} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            base,
+            path,
+            host,
+            body.len(),
+            body
+        );
+        let resp = time::timeout(HTTP_TIMEOUT, async {
+            let mut stream = TcpStream::connect(host).await?;
+            stream.write_all(req.as_bytes()).await?;
+            let mut resp = Vec::new();
+            stream.read_to_end(&mut resp).await?;
+            Ok::<_, io::Error>(resp)
+        })
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "otel export timeout"))??;
+        let status_line = String::from_utf8_lossy(&resp)
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        match status_line.split_whitespace().nth(1).map(str::parse::<u16>) {
+            Some(Ok(code)) if (200..300).contains(&code) => Ok(()),
+            _ => Err(io::Error::other(format!(
+                "collector responded {:?}",
+                status_line
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tracing::{field, info_span};
+    use tracing_subscriber::prelude::*;
+
+    /// accepts one request per connection, returns (path, json body) of each
+    async fn mock_collector(
+        status: &'static str,
+    ) -> (
+        String,
+        tokio::sync::mpsc::UnboundedReceiver<(String, Value)>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (mut s, _) = listener.accept().await.unwrap();
+                let mut buf = Vec::new();
+                let mut tmp = [0u8; 4096];
+                let (head_len, content_len) = loop {
+                    let n = s.read(&mut tmp).await.unwrap();
+                    buf.extend_from_slice(&tmp[..n]);
+                    let text = String::from_utf8_lossy(&buf).to_string();
+                    if let Some(i) = text.find("\r\n\r\n") {
+                        let len = text[..i]
+                            .lines()
+                            .find_map(|l| l.strip_prefix("Content-Length: "))
+                            .unwrap()
+                            .parse::<usize>()
+                            .unwrap();
+                        break (i + 4, len);
+                    }
+                };
+                while buf.len() < head_len + content_len {
+                    let n = s.read(&mut tmp).await.unwrap();
+                    buf.extend_from_slice(&tmp[..n]);
+                }
+                let head = String::from_utf8_lossy(&buf[..head_len]).to_string();
+                let path = head.split_whitespace().nth(1).unwrap().to_string();
+                let body = serde_json::from_slice(&buf[head_len..]).unwrap();
+                tx.send((path, body)).unwrap();
+                let resp = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+                s.write_all(resp.as_bytes()).await.unwrap();
+            }
+        });
+        (format!("http://{}/otlp", addr), rx)
+    }
+
+    #[tokio::test]
+    async fn export() {
+        let (endpoint, mut rx) = mock_collector("200 OK").await;
+        let (layer, exporter) = new(&OtelConfig {
+            endpoint,
+            ..Default::default()
+        });
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let span = info_span!(
+                "tcp",
+                peer = "1.2.3.4:5",
+                upload = field::Empty,
+                download = field::Empty
+            );
+            span.record("upload", 10u64).record("download", 20u64);
+            let _child = span.in_scope(|| info_span!("connect"));
+        });
+        exporter.export().await.unwrap();
+
+        let (path, traces) = rx.recv().await.unwrap();
+        assert_eq!(path, "/otlp/v1/traces");
+        let spans = traces["resourceSpans"][0]["scopeSpans"][0]["spans"]
+            .as_array()
+            .unwrap();
+        assert_eq!(spans.len(), 2);
+        let (child, tcp) = (&spans[0], &spans[1]);
+        assert_eq!(child["name"], "connect");
+        assert_eq!(tcp["name"], "tcp");
+        assert_eq!(child["traceId"], tcp["traceId"]);
+        assert_eq!(child["parentSpanId"], tcp["spanId"]);
+        assert!(tcp["attributes"]
+            .as_array()
+            .unwrap()
+            .contains(&json!({ "key": "upload", "value": { "intValue": "10" } })));
+
+        let (path, metrics) = rx.recv().await.unwrap();
+        assert_eq!(path, "/otlp/v1/metrics");
+        let metrics = metrics["resourceMetrics"][0]["scopeMetrics"][0]["metrics"]
+            .as_array()
+            .unwrap();
+        let bytes = metrics
+            .iter()
+            .find(|m| m["name"] == "ss_light.bytes")
+            .unwrap();
+        assert_eq!(bytes["sum"]["dataPoints"].as_array().unwrap().len(), 2);
+        let conns = metrics
+            .iter()
+            .find(|m| m["name"] == "ss_light.connections")
+            .unwrap();
+        assert_eq!(conns["sum"]["dataPoints"][0]["asInt"], "1");
+
+        // spans are sent once, metrics are cumulative
+        exporter.export().await.unwrap();
+        let (path, _) = rx.recv().await.unwrap();
+        assert_eq!(path, "/otlp/v1/metrics");
+    }
+
+    #[tokio::test]
+    async fn export_error() {
+        let (endpoint, _rx) = mock_collector("503 Service Unavailable").await;
+        let (layer, exporter) = new(&OtelConfig {
+            endpoint,
+            ..Default::default()
+        });
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            drop(info_span!("udp"));
+        });
+        assert!(exporter.export().await.is_err());
+
+        let (_, exporter) = new(&OtelConfig {
+            endpoint: "https://127.0.0.1:4318".into(),
+            ..Default::default()
+        });
+        exporter.shared.finish(SpanData {
+            trace_id: [0; 16],
+            span_id: [0; 8],
+            parent_id: None,
+            name: "tcp",
+            start: 0,
+            end: 0,
+            attributes: vec![],
+        });
+        let err = exporter.export().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
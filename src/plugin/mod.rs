@@ -1,21 +1,421 @@
 //! plugin support. SIP003 [https://shadowsocks.org/en/wiki/Plugin.html](https://shadowsocks.org/en/wiki/Plugin.html)
 use std::{
-    io,
-    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener},
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    path::PathBuf,
+    pin::Pin,
     process::{ExitStatus, Stdio},
+    task::{Context, Poll},
+    time::Duration,
 };
 
+use bytes::{Bytes, BytesMut};
 use derivative::Derivative;
 use serde::{Deserialize, Serialize};
-use tokio::process::{Child, Command};
-use tracing::{error, info, trace};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader, ReadBuf},
+    net::TcpListener,
+    process::{Child, Command},
+    time,
+};
+use tracing::{error, info, info_span, trace, warn, Instrument, Span};
+
+use crate::net::TcpConfig;
+
+pub mod obfs;
+mod supervisor;
+pub mod websocket;
+
+pub use supervisor::{RestartPolicy, Supervisor};
+
+use obfs::{ObfsMode, ObfsStream};
+use websocket::{WebSocketConfig, WebSocketStream};
+
+/// plugin is killed if it does not exit this long after SIGTERM
+const STOP_GRACE: Duration = Duration::from_secs(5);
+/// http header or tls ClientHello longer than this is refused
+const MAX_HANDSHAKE_SIZE: usize = 16 * 1024;
+/// raw bytes kept for probe defense at most
+const MAX_RECORDED: usize = 64 * 1024;
+
+/// keep raw bytes read for probe defense, given up past [`MAX_RECORDED`]
+pub(crate) fn record(recorded: &mut Option<BytesMut>, data: &[u8]) {
+    if let Some(r) = recorded {
+        if r.len() + data.len() > MAX_RECORDED {
+            *recorded = None;
+        } else {
+            r.extend_from_slice(data);
+        }
+    }
+}
+
+/// built-in plugin or tls handshake failed, with the stream and raw bytes received for
+/// probe defense
+pub struct AcceptError<S> {
+    pub stream: S,
+    pub received: Bytes,
+    pub error: io::Error,
+}
 
-#[derive(Derivative, Deserialize, Serialize, Clone)]
+#[derive(Derivative, Deserialize, Serialize, Clone, PartialEq)]
 #[derivative(Debug)]
 pub struct PluginConfig {
     pub name: String,
     pub opts: Option<String>,
     pub args: Vec<String>,
+    #[serde(default)]
+    pub restart: RestartPolicy,
+    /// sec, wait for the plugin to listen before accepting, 0 means no readiness probe
+    #[serde(default = "default_ready_timeout")]
+    pub ready_timeout: u64,
+    #[serde(default)]
+    pub plugin_mode: PluginMode,
+    /// path of a unix socket the plugin forwards to instead of loopback tcp, if it supports it
+    #[serde(default)]
+    pub local_unix_socket: Option<String>,
+}
+
+/// traffic carried by a subprocess plugin, SIP003u plugins carry udp as well
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PluginMode {
+    /// udp is served on the public address directly
+    #[default]
+    TcpOnly,
+    /// the plugin owns the public udp port too and forwards udp to the local address
+    TcpAndUdp,
+}
+
+impl PluginMode {
+    pub fn has_udp(&self) -> bool {
+        *self == PluginMode::TcpAndUdp
+    }
+}
+
+impl fmt::Display for PluginMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PluginMode::TcpOnly => write!(f, "tcp_only"),
+            PluginMode::TcpAndUdp => write!(f, "tcp_and_udp"),
+        }
+    }
+}
+
+impl std::str::FromStr for PluginMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tcp_only" => Ok(PluginMode::TcpOnly),
+            "tcp_and_udp" => Ok(PluginMode::TcpAndUdp),
+            _ => Err(format!(
+                "unknown plugin mode {}, tcp_only or tcp_and_udp",
+                s
+            )),
+        }
+    }
+}
+
+fn default_ready_timeout() -> u64 {
+    10
+}
+
+impl Default for PluginConfig {
+    fn default() -> Self {
+        PluginConfig {
+            name: String::new(),
+            opts: None,
+            args: vec![],
+            restart: RestartPolicy::default(),
+            ready_timeout: default_ready_timeout(),
+            plugin_mode: PluginMode::default(),
+            local_unix_socket: None,
+        }
+    }
+}
+
+impl PluginConfig {
+    /// plugin served in process, no subprocess is started for it
+    pub fn builtin(&self) -> Option<Builtin> {
+        self.builtin_obfs()
+            .map(Builtin::Obfs)
+            .or_else(|| self.builtin_websocket().map(Builtin::WebSocket))
+    }
+
+    /// simple-obfs server with `obfs=http` or `obfs=tls`
+    pub fn builtin_obfs(&self) -> Option<ObfsMode> {
+        if !matches!(self.name.as_str(), "obfs-server" | "simple-obfs") {
+            return None;
+        }
+        self.opts_iter().find_map(|opt| match opt {
+            ("obfs", Some("http")) => Some(ObfsMode::Http),
+            ("obfs", Some("tls")) => Some(ObfsMode::Tls),
+            _ => None,
+        })
+    }
+
+    /// v2ray-plugin in websocket `server` mode without `tls`, clients have to use `mux=0`
+    pub fn builtin_websocket(&self) -> Option<WebSocketConfig> {
+        if self.name != "v2ray-plugin" {
+            return None;
+        }
+        let mut server = false;
+        let mut cfg = WebSocketConfig::default();
+        for opt in self.opts_iter() {
+            match opt {
+                ("server", None) => server = true,
+                ("path", Some(path)) => cfg.path = path.to_string(),
+                ("host", Some(host)) => cfg.host = Some(host.to_string()),
+                ("mode", Some("websocket")) | ("mux", _) | ("loglevel", _) => {}
+                // tls, quic and anything else is left to v2ray-plugin
+                _ => return None,
+            }
+        }
+        server.then_some(cfg)
+    }
+
+    /// the built-in websocket doesn't demultiplex v2ray mux, so opts other than `mux=0`
+    /// are refused instead of failing every client
+    pub fn check_builtin(&self) -> Result<(), String> {
+        if self.builtin_websocket().is_none() {
+            return Ok(());
+        }
+        match self.opts_iter().find(|(k, _)| *k == "mux") {
+            None | Some((_, Some("0"))) => Ok(()),
+            Some((_, v)) => Err(format!(
+                "v2ray-plugin opts mux={} is not supported by the built-in websocket, set mux=0 and use it on clients",
+                v.unwrap_or_default()
+            )),
+        }
+    }
+
+    /// `key=value` or `key` items of opts
+    fn opts_iter(&self) -> impl Iterator<Item = (&str, Option<&str>)> {
+        self.opts
+            .as_deref()
+            .unwrap_or_default()
+            .split(';')
+            .map(str::trim)
+            .filter(|opt| !opt.is_empty())
+            .map(|opt| match opt.split_once('=') {
+                Some((k, v)) => (k, Some(v)),
+                None => (opt, None),
+            })
+    }
+}
+
+/// plugin served in process
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Builtin {
+    Obfs(ObfsMode),
+    WebSocket(WebSocketConfig),
+}
+
+impl fmt::Display for Builtin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Builtin::Obfs(mode) => write!(f, "obfs={}", mode),
+            Builtin::WebSocket(cfg) => write!(f, "{}", cfg),
+        }
+    }
+}
+
+/// accepted stream with or without a built-in plugin
+pub enum PluginStream<S> {
+    Plain(S),
+    Obfs(ObfsStream<S>),
+    WebSocket(WebSocketStream<S>),
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> PluginStream<S> {
+    /// handshake of the built-in plugin, failed with `TimedOut` if not done in `timeout`
+    pub async fn accept(
+        inner: S,
+        builtin: &Builtin,
+        timeout: Duration,
+    ) -> Result<Self, AcceptError<S>> {
+        match builtin {
+            Builtin::Obfs(mode) => ObfsStream::accept(inner, *mode, timeout)
+                .await
+                .map(PluginStream::Obfs),
+            Builtin::WebSocket(cfg) => WebSocketStream::accept(inner, cfg, timeout)
+                .await
+                .map(PluginStream::WebSocket),
+        }
+    }
+}
+
+impl<S> PluginStream<S> {
+    /// stop keeping raw bytes, once the peer is known to be a client
+    pub fn stop_recording(&mut self) {
+        match self {
+            PluginStream::Plain(_) => {}
+            PluginStream::Obfs(s) => s.stop_recording(),
+            PluginStream::WebSocket(s) => s.stop_recording(),
+        }
+    }
+
+    /// inner stream and raw bytes read from it if still recording
+    pub fn into_inner(self) -> (S, Option<Bytes>) {
+        match self {
+            PluginStream::Plain(s) => (s, None),
+            PluginStream::Obfs(s) => s.into_inner(),
+            PluginStream::WebSocket(s) => s.into_inner(),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for PluginStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PluginStream::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            PluginStream::Obfs(s) => Pin::new(s).poll_read(cx, buf),
+            PluginStream::WebSocket(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for PluginStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            PluginStream::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            PluginStream::Obfs(s) => Pin::new(s).poll_write(cx, buf),
+            PluginStream::WebSocket(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PluginStream::Plain(s) => Pin::new(s).poll_flush(cx),
+            PluginStream::Obfs(s) => Pin::new(s).poll_flush(cx),
+            PluginStream::WebSocket(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PluginStream::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            PluginStream::Obfs(s) => Pin::new(s).poll_shutdown(cx),
+            PluginStream::WebSocket(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+/// where the server accepts connections forwarded by a subprocess plugin
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LocalAddr {
+    Tcp(SocketAddr),
+    /// `SS_LOCAL_HOST` is the socket path and `SS_LOCAL_PORT` is empty
+    Unix(PathBuf),
+}
+
+impl fmt::Display for LocalAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LocalAddr::Tcp(addr) => write!(f, "{}", addr),
+            LocalAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// sockets the server binds before the plugin starts, so the address handed to the plugin
+/// is held by the server and can not be taken by another process in between
+pub enum LocalListeners {
+    /// acceptors on one loopback port, and udp on the same port for `tcp_and_udp`
+    Tcp {
+        listeners: Vec<TcpListener>,
+        udp: Option<UdpSocket>,
+    },
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl LocalListeners {
+    /// bind the unix socket of `local_unix_socket`, or an ephemeral loopback port
+    pub fn bind(cfg: &PluginConfig, tcp: &TcpConfig, acceptors: usize) -> io::Result<Self> {
+        if let Some(ref path) = cfg.local_unix_socket {
+            if cfg.plugin_mode.has_udp() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "plugin_mode tcp_and_udp needs loopback tcp, unset local_unix_socket",
+                ));
+            }
+            return Self::bind_unix(path);
+        }
+
+        let acceptors = acceptors.max(1);
+        let any = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0);
+        let mut last_err = None;
+        // the udp port may be taken already, try other ports
+        for _ in 0..10 {
+            let first = crate::net::bind_listener(any, tcp, acceptors > 1)?;
+            let addr = first.local_addr()?;
+            let udp = match cfg.plugin_mode.has_udp() {
+                true => match UdpSocket::bind(addr) {
+                    Ok(udp) => Some(udp),
+                    Err(e) => {
+                        last_err = Some(e);
+                        continue;
+                    }
+                },
+                false => None,
+            };
+            let mut listeners = vec![first];
+            for _ in 1..acceptors {
+                listeners.push(crate::net::bind_listener(addr, tcp, true)?);
+            }
+            return Ok(LocalListeners::Tcp { listeners, udp });
+        }
+        Err(last_err.expect("tried at least once"))
+    }
+
+    /// a socket file left by a previous run is replaced
+    #[cfg(unix)]
+    fn bind_unix(path: &str) -> io::Result<Self> {
+        use std::os::unix::fs::FileTypeExt;
+        match std::fs::symlink_metadata(path) {
+            Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)?,
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a socket", path),
+                ))
+            }
+            Err(_) => {}
+        }
+        tokio::net::UnixListener::bind(path).map(LocalListeners::Unix)
+    }
+
+    #[cfg(not(unix))]
+    fn bind_unix(_: &str) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "local_unix_socket is not supported on this platform",
+        ))
+    }
+
+    /// address handed to the plugin
+    pub fn local_addr(&self) -> io::Result<LocalAddr> {
+        match self {
+            LocalListeners::Tcp { listeners, .. } => listeners[0].local_addr().map(LocalAddr::Tcp),
+            #[cfg(unix)]
+            LocalListeners::Unix(listener) => {
+                let addr = listener.local_addr()?;
+                let path = addr.as_pathname().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "unnamed unix socket")
+                })?;
+                Ok(LocalAddr::Unix(path.to_path_buf()))
+            }
+        }
+    }
 }
 
 /// server plugin: CLIENT -> PLUGIN -> SERVER -> REMOTE
@@ -23,16 +423,22 @@ pub struct PluginConfig {
 /// plugin listen to inbound address of server
 ///
 /// server listen to local_addr
+///
+/// client plugin: CLIENT -> PLUGIN -> SERVER, plugin listen to local_addr and forward to
+/// the remote address of server
 pub struct Plugin {
     process: Child,
-    local_addr: SocketAddr,
+    local_addr: LocalAddr,
 }
 
 impl Plugin {
-    // start plugin in subprocess
-    pub fn start(cfg: &PluginConfig, remote_host: &str, remote_port: &str) -> io::Result<Plugin> {
-        let local_addr = get_local_port(Ipv4Addr::LOCALHOST.into())?;
-
+    // start plugin in subprocess forwarding to `local_addr`, which the server is listening on
+    pub fn start(
+        cfg: &PluginConfig,
+        remote_host: &str,
+        remote_port: &str,
+        local_addr: LocalAddr,
+    ) -> io::Result<Plugin> {
         trace!(
             "starting plugin {}, opts: {:?}, args: {:?} listen to {}:{}, ss will use local {}",
             cfg.name,
@@ -45,11 +451,17 @@ impl Plugin {
 
         let mut cmd = Command::new(&cfg.name);
 
+        let (local_host, local_port) = match local_addr {
+            LocalAddr::Tcp(addr) => (addr.ip().to_string(), addr.port().to_string()),
+            LocalAddr::Unix(ref path) => (path.display().to_string(), String::new()),
+        };
         cmd.env("SS_REMOTE_HOST", remote_host)
             .env("SS_REMOTE_PORT", remote_port)
-            .env("SS_LOCAL_HOST", local_addr.ip().to_string())
-            .env("SS_LOCAL_PORT", local_addr.port().to_string())
+            .env("SS_LOCAL_HOST", local_host)
+            .env("SS_LOCAL_PORT", local_port)
             .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
             .kill_on_drop(true);
 
         if let Some(opts) = &cfg.opts {
@@ -61,7 +473,14 @@ impl Plugin {
         }
 
         match cmd.spawn() {
-            Ok(process) => {
+            Ok(mut process) => {
+                let span = info_span!("plugin", name = %cfg.name);
+                if let Some(out) = process.stdout.take() {
+                    capture_lines(out, span.clone());
+                }
+                if let Some(err) = process.stderr.take() {
+                    capture_lines(err, span);
+                }
                 info!(
                     "started plugin {} on {}:{} <-> {}, pid:{}",
                     cfg.name,
@@ -87,23 +506,189 @@ impl Plugin {
         self.process.wait().await
     }
 
-    pub fn local_addr(&self) -> SocketAddr {
-        self.local_addr
+    // wait plugin exits without consuming it, can be cancelled
+    pub async fn wait(&mut self) -> io::Result<ExitStatus> {
+        self.process.wait().await
+    }
+
+    // exit status if plugin exited
+    pub fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        self.process.try_wait()
+    }
+
+    // SIGTERM plugin, SIGKILL it if still running after `STOP_GRACE`, and wait it exits
+    pub async fn stop(&mut self) -> io::Result<()> {
+        let Some(pid) = self.process.id() else {
+            // exited already
+            return Ok(());
+        };
+        info!("stopping plugin, pid:{}", pid);
+        #[cfg(unix)]
+        {
+            // safety: plain syscall, pid is a child not reaped yet
+            if unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } == 0 {
+                if let Ok(res) = time::timeout(STOP_GRACE, self.process.wait()).await {
+                    return res.map(|_| ());
+                }
+                warn!("plugin pid:{} still running after SIGTERM, killing", pid);
+            }
+        }
+        self.process.kill().await
+    }
+
+    pub fn local_addr(&self) -> &LocalAddr {
+        &self.local_addr
     }
 }
 
+/// free local port for a client plugin to listen on
 fn get_local_port(loop_ip: IpAddr) -> io::Result<SocketAddr> {
-    let listener = TcpListener::bind(SocketAddr::new(loop_ip, 0))?;
+    let listener = std::net::TcpListener::bind(SocketAddr::new(loop_ip, 0))?;
     listener.local_addr()
 }
 
+/// log lines of plugin output. tracing targets are static, so it is target `plugin` inside
+/// span `plugin{name=..}`
+fn capture_lines<R: AsyncRead + Unpin + Send + 'static>(reader: R, span: Span) {
+    tokio::spawn(
+        async move {
+            let mut lines = BufReader::new(reader).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                info!(target: "plugin", "{}", line);
+            }
+        }
+        .instrument(span),
+    );
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn test_builtin_obfs() {
+        let cfg = |name: &str, opts: &str| PluginConfig {
+            name: name.into(),
+            opts: Some(opts.into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            cfg("obfs-server", "obfs=http").builtin_obfs(),
+            Some(obfs::ObfsMode::Http)
+        );
+        assert_eq!(
+            cfg("simple-obfs", "failover=1.2.3.4:80;obfs=tls").builtin_obfs(),
+            Some(obfs::ObfsMode::Tls)
+        );
+        assert_eq!(cfg("obfs-server", "obfs=unknown").builtin_obfs(), None);
+        assert_eq!(cfg("v2ray-plugin", "server").builtin_obfs(), None);
+    }
+
+    #[test]
+    fn test_builtin_websocket() {
+        let cfg = |opts: &str| PluginConfig {
+            name: "v2ray-plugin".into(),
+            opts: Some(opts.into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            cfg("server").builtin(),
+            Some(Builtin::WebSocket(WebSocketConfig::default()))
+        );
+        assert_eq!(
+            cfg("server;path=/dog;host=xxx.com;mux=0;loglevel=none").builtin_websocket(),
+            Some(WebSocketConfig {
+                path: "/dog".into(),
+                host: Some("xxx.com".into()),
+            })
+        );
+        assert_eq!(cfg("path=/dog").builtin_websocket(), None);
+        assert_eq!(cfg("server;tls;host=xxx.com").builtin_websocket(), None);
+        assert_eq!(cfg("server;mode=quic").builtin_websocket(), None);
+
+        assert!(cfg("server;mux=0").check_builtin().is_ok());
+        assert!(cfg("server;path=/dog").check_builtin().is_ok());
+        assert!(cfg("server;mux=8").check_builtin().is_err());
+        assert!(cfg("server;mux").check_builtin().is_err());
+        // left to v2ray-plugin
+        assert!(cfg("server;tls;mux=8").check_builtin().is_ok());
+    }
+
+    #[test]
+    fn test_plugin_mode() {
+        let cfg: PluginConfig = toml::from_str("name = \"x\"\nargs = []").unwrap();
+        assert_eq!(cfg.plugin_mode, PluginMode::TcpOnly);
+        let cfg: PluginConfig =
+            toml::from_str("name = \"x\"\nargs = []\nplugin_mode = \"tcp_and_udp\"").unwrap();
+        assert!(cfg.plugin_mode.has_udp());
+        assert_eq!("tcp_only".parse(), Ok(PluginMode::TcpOnly));
+        assert!("udp_only".parse::<PluginMode>().is_err());
+    }
+
     #[test]
     fn test_get_local_port() {
         let local_addr = get_local_port(Ipv4Addr::LOCALHOST.into()).unwrap();
         println!("{:?}", local_addr);
     }
+
+    #[tokio::test]
+    async fn test_local_listeners() {
+        let tcp = TcpConfig::default();
+        let mut cfg = PluginConfig::default();
+        let local = LocalListeners::bind(&cfg, &tcp, 2).unwrap();
+        let LocalAddr::Tcp(addr) = local.local_addr().unwrap() else {
+            panic!("not tcp");
+        };
+        assert!(addr.ip().is_loopback());
+        // held by the server until dropped
+        assert!(std::net::TcpListener::bind(addr).is_err());
+        let LocalListeners::Tcp { listeners, udp } = local else {
+            panic!("not tcp");
+        };
+        assert_eq!(listeners.len(), 2);
+        assert!(udp.is_none());
+
+        cfg.plugin_mode = PluginMode::TcpAndUdp;
+        let LocalListeners::Tcp { listeners, udp } = LocalListeners::bind(&cfg, &tcp, 1).unwrap()
+        else {
+            panic!("not tcp");
+        };
+        let addr = listeners[0].local_addr().unwrap();
+        assert_eq!(udp.unwrap().local_addr().unwrap(), addr);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_local_unix_socket() {
+        let dir = std::env::temp_dir().join(format!("ss-light-local-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("ss.sock");
+        let cfg = PluginConfig {
+            local_unix_socket: Some(path.to_string_lossy().into()),
+            ..Default::default()
+        };
+        let tcp = TcpConfig::default();
+        let local = LocalListeners::bind(&cfg, &tcp, 1).unwrap();
+        assert_eq!(local.local_addr().unwrap(), LocalAddr::Unix(path.clone()));
+        drop(local);
+        // the stale socket file is replaced
+        let local = LocalListeners::bind(&cfg, &tcp, 1).unwrap();
+        let LocalListeners::Unix(listener) = local else {
+            panic!("not unix");
+        };
+        let (_client, accepted) =
+            tokio::join!(tokio::net::UnixStream::connect(&path), listener.accept());
+        accepted.unwrap();
+
+        let udp = PluginConfig {
+            plugin_mode: PluginMode::TcpAndUdp,
+            ..cfg.clone()
+        };
+        assert!(LocalListeners::bind(&udp, &tcp, 1).is_err());
+        // anything else is not removed
+        std::fs::remove_file(&path).unwrap();
+        std::fs::write(&path, "").unwrap();
+        assert!(LocalListeners::bind(&cfg, &tcp, 1).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
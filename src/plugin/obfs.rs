@@ -0,0 +1,598 @@
+//! in-process server side of [simple-obfs](https://github.com/shadowsocks/simple-obfs),
+//! `obfs=http` and `obfs=tls`, wraps the accepted stream before the shadowsocks stream.
+//!
+//! http: the client sends a websocket upgrade request with the first payload as body,
+//! the server answers `101 Switching Protocols` before its first payload, then both sides
+//! send raw bytes.
+//!
+//! tls: the client sends a ClientHello with the first payload in the session ticket
+//! extension, the server answers ServerHello, ChangeCipherSpec and a fake Finished before
+//! its first payload, then both sides send payload in application data records.
+use std::{
+    fmt, io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use rand::Rng;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+use super::{record, AcceptError, MAX_HANDSHAKE_SIZE};
+use crate::util::{base64_encode, websocket_accept};
+
+/// max payload of a tls record written
+const MAX_RECORD_SIZE: usize = 16 * 1024;
+
+const TLS_HANDSHAKE: u8 = 0x16;
+const TLS_CHANGE_CIPHER_SPEC: u8 = 0x14;
+const TLS_APPLICATION_DATA: u8 = 0x17;
+const TLS_EXT_SESSION_TICKET: u16 = 0x0023;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObfsMode {
+    Http,
+    Tls,
+}
+
+impl fmt::Display for ObfsMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ObfsMode::Http => write!(f, "http"),
+            ObfsMode::Tls => write!(f, "tls"),
+        }
+    }
+}
+
+pub struct ObfsStream<S> {
+    inner: S,
+    mode: ObfsMode,
+    /// payload not returned yet, the http body or the session ticket
+    plain: BytesMut,
+    /// tls records not parsed yet
+    rbuf: BytesMut,
+    /// payload left in the current tls record, and whether it is application data
+    record_left: usize,
+    record_is_data: bool,
+    /// sent along with the first write
+    response: Option<Bytes>,
+    wbuf: BytesMut,
+    /// raw bytes read, until `stop_recording`
+    recorded: Option<BytesMut>,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("obfs: {}", msg))
+}
+
+impl<S> ObfsStream<S> {
+    fn new(inner: S, mode: ObfsMode) -> Self {
+        ObfsStream {
+            inner,
+            mode,
+            plain: BytesMut::new(),
+            rbuf: BytesMut::new(),
+            record_left: 0,
+            record_is_data: false,
+            response: None,
+            wbuf: BytesMut::new(),
+            recorded: Some(BytesMut::new()),
+        }
+    }
+
+    pub fn mode(&self) -> ObfsMode {
+        self.mode
+    }
+
+    /// stop keeping raw bytes, once the peer is known to be a client
+    pub fn stop_recording(&mut self) {
+        self.recorded = None;
+    }
+
+    /// inner stream and raw bytes read from it if still recording
+    pub fn into_inner(self) -> (S, Option<Bytes>) {
+        (self.inner, self.recorded.map(BytesMut::freeze))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> ObfsStream<S> {
+    /// read the obfs request of client
+    pub async fn accept(
+        inner: S,
+        mode: ObfsMode,
+        timeout: Duration,
+    ) -> Result<Self, AcceptError<S>> {
+        let mut stream = ObfsStream::new(inner, mode);
+        let handshake = async {
+            match mode {
+                ObfsMode::Http => stream.accept_http().await,
+                ObfsMode::Tls => stream.accept_tls().await,
+            }
+        };
+        let res = tokio::time::timeout(timeout, handshake)
+            .await
+            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()));
+        match res {
+            Ok(()) => Ok(stream),
+            Err(error) => {
+                let (stream, received) = stream.into_inner();
+                Err(AcceptError {
+                    stream,
+                    received: received.unwrap_or_default(),
+                    error,
+                })
+            }
+        }
+    }
+
+    /// read more raw bytes into `rbuf` while accepting
+    async fn read_more(&mut self) -> io::Result<()> {
+        if self.rbuf.len() >= MAX_HANDSHAKE_SIZE {
+            return Err(invalid("handshake too large"));
+        }
+        let mut buf = [0u8; 4096];
+        let n = self.inner.read(&mut buf).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.rbuf.extend_from_slice(&buf[..n]);
+        record(&mut self.recorded, &buf[..n]);
+        Ok(())
+    }
+
+    async fn accept_http(&mut self) -> io::Result<()> {
+        let header_len = loop {
+            if let Some(i) = self.rbuf.windows(4).position(|w| w == b"\r\n\r\n") {
+                break i + 4;
+            }
+            self.read_more().await?;
+        };
+        let header = std::str::from_utf8(&self.rbuf[..header_len])
+            .map_err(|_| invalid("http header not utf8"))?;
+        let mut lines = header.split("\r\n");
+        let request_line = lines.next().unwrap_or_default();
+        if !(request_line.starts_with("GET ") || request_line.starts_with("POST "))
+            || !request_line.ends_with(" HTTP/1.1")
+        {
+            return Err(invalid("not a http request"));
+        }
+        let key = lines.find_map(|l| {
+            let (name, value) = l.split_once(':')?;
+            name.eq_ignore_ascii_case("sec-websocket-key")
+                .then(|| value.trim().to_string())
+        });
+        self.response = Some(http_response(key.as_deref()));
+        self.rbuf.advance(header_len);
+        self.plain = self.rbuf.split();
+        Ok(())
+    }
+
+    async fn accept_tls(&mut self) -> io::Result<()> {
+        while self.rbuf.len() < 5 {
+            self.read_more().await?;
+        }
+        if self.rbuf[0] != TLS_HANDSHAKE {
+            return Err(invalid("not a tls handshake"));
+        }
+        let len = u16::from_be_bytes([self.rbuf[3], self.rbuf[4]]) as usize;
+        while self.rbuf.len() < 5 + len {
+            self.read_more().await?;
+        }
+        let mut record = self.rbuf.split_to(5 + len);
+        record.advance(5);
+        let (session_id, ticket) =
+            parse_client_hello(&record).ok_or_else(|| invalid("bad tls client hello"))?;
+        self.response = Some(tls_response(&session_id));
+        self.plain = BytesMut::from(&ticket[..]);
+        Ok(())
+    }
+
+    /// read and record raw bytes into `rbuf`, Ok(0) is eof
+    fn poll_fill(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let mut buf = [0u8; 8192];
+        let mut read_buf = ReadBuf::new(&mut buf);
+        ready!(Pin::new(&mut self.inner).poll_read(cx, &mut read_buf))?;
+        let n = read_buf.filled().len();
+        self.rbuf.extend_from_slice(&buf[..n]);
+        record(&mut self.recorded, &buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_read_tls(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if self.record_left > 0 && !self.rbuf.is_empty() {
+                let n = self.record_left.min(self.rbuf.len());
+                if self.record_is_data {
+                    let n = n.min(buf.remaining());
+                    buf.put_slice(&self.rbuf[..n]);
+                    self.rbuf.advance(n);
+                    self.record_left -= n;
+                    return Poll::Ready(Ok(()));
+                }
+                self.rbuf.advance(n);
+                self.record_left -= n;
+                continue;
+            }
+            if self.record_left == 0 && self.rbuf.len() >= 5 {
+                self.record_is_data = match self.rbuf[0] {
+                    TLS_APPLICATION_DATA => true,
+                    // clients may finish the fake handshake after ServerHello
+                    TLS_CHANGE_CIPHER_SPEC | TLS_HANDSHAKE => false,
+                    _ => return Poll::Ready(Err(invalid("unexpected tls record"))),
+                };
+                self.record_left = u16::from_be_bytes([self.rbuf[3], self.rbuf[4]]) as usize;
+                self.rbuf.advance(5);
+                continue;
+            }
+            if ready!(self.poll_fill(cx))? == 0 {
+                if self.rbuf.is_empty() && self.record_left == 0 {
+                    return Poll::Ready(Ok(()));
+                }
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+        }
+    }
+
+    fn poll_flush_wbuf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.wbuf.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.wbuf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.wbuf.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// (session id, session ticket) of a ClientHello handshake message
+fn parse_client_hello(mut msg: &[u8]) -> Option<(Bytes, Bytes)> {
+    fn take<'a>(buf: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+        if buf.len() < n {
+            return None;
+        }
+        let (head, rest) = buf.split_at(n);
+        *buf = rest;
+        Some(head)
+    }
+    fn take_u8(buf: &mut &[u8]) -> Option<usize> {
+        take(buf, 1).map(|b| b[0] as usize)
+    }
+    fn take_u16(buf: &mut &[u8]) -> Option<usize> {
+        take(buf, 2).map(|b| u16::from_be_bytes([b[0], b[1]]) as usize)
+    }
+
+    // client hello, 3 bytes length, version, random
+    if take_u8(&mut msg)? != 1 {
+        return None;
+    }
+    take(&mut msg, 3 + 2 + 32)?;
+    let sid_len = take_u8(&mut msg)?;
+    let session_id = Bytes::copy_from_slice(take(&mut msg, sid_len)?);
+    let n = take_u16(&mut msg)?;
+    take(&mut msg, n)?; // cipher suites
+    let n = take_u8(&mut msg)?;
+    take(&mut msg, n)?; // compression methods
+    let n = take_u16(&mut msg)?;
+    let mut exts = take(&mut msg, n)?;
+    while !exts.is_empty() {
+        let typ = take_u16(&mut exts)? as u16;
+        let n = take_u16(&mut exts)?;
+        let data = take(&mut exts, n)?;
+        if typ == TLS_EXT_SESSION_TICKET {
+            return Some((session_id, Bytes::copy_from_slice(data)));
+        }
+    }
+    None
+}
+
+fn http_response(key: Option<&str>) -> Bytes {
+    let mut rng = rand::thread_rng();
+    let accept = match key {
+        Some(key) => websocket_accept(key),
+        None => base64_encode(&rng.gen::<[u8; 20]>()),
+    };
+    let now = time::OffsetDateTime::now_utc();
+    let date = format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        &now.weekday().to_string()[..3],
+        now.day(),
+        &now.month().to_string()[..3],
+        now.year(),
+        now.hour(),
+        now.minute(),
+        now.second()
+    );
+    Bytes::from(format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Server: nginx/1.{}.{}\r\n\
+         Date: {}\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        rng.gen_range(0..12),
+        rng.gen_range(0..12),
+        date,
+        accept
+    ))
+}
+
+/// ServerHello echoing `session_id`, ChangeCipherSpec and a fake encrypted Finished
+fn tls_response(session_id: &[u8]) -> Bytes {
+    let mut rng = rand::thread_rng();
+    let mut body = BytesMut::new();
+    body.put_u16(0x0303);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or_default();
+    body.put_u32(now);
+    body.put_slice(&rng.gen::<[u8; 28]>());
+    body.put_u8(session_id.len() as u8);
+    body.put_slice(session_id);
+    body.put_u16(0xcca8); // ECDHE-RSA-CHACHA20-POLY1305
+    body.put_u8(0); // no compression
+    let exts: &[u8] = &[
+        0xff, 0x01, 0x00, 0x01, 0x00, // renegotiation info
+        0x00, 0x17, 0x00, 0x00, // extended master secret
+        0x00, 0x0b, 0x00, 0x02, 0x01, 0x00, // ec point formats
+    ];
+    body.put_u16(exts.len() as u16);
+    body.put_slice(exts);
+
+    let mut out = BytesMut::new();
+    out.put_slice(&[TLS_HANDSHAKE, 0x03, 0x01]);
+    out.put_u16(body.len() as u16 + 4);
+    out.put_u8(2); // server hello
+    out.put_u8(0);
+    out.put_u16(body.len() as u16);
+    out.put_slice(&body);
+    out.put_slice(&[TLS_CHANGE_CIPHER_SPEC, 0x03, 0x03, 0x00, 0x01, 0x01]);
+    out.put_slice(&[TLS_HANDSHAKE, 0x03, 0x03, 0x00, 0x20]);
+    out.put_slice(&rng.gen::<[u8; 32]>());
+    out.freeze()
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for ObfsStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.plain.is_empty() {
+            let n = this.plain.len().min(buf.remaining());
+            buf.put_slice(&this.plain.split_to(n));
+            return Poll::Ready(Ok(()));
+        }
+        match this.mode {
+            ObfsMode::Tls => this.poll_read_tls(cx, buf),
+            ObfsMode::Http => {
+                let before = buf.filled().len();
+                ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+                record(&mut this.recorded, &buf.filled()[before..]);
+                Poll::Ready(Ok(()))
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for ObfsStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_flush_wbuf(cx))?;
+        if let Some(response) = this.response.take() {
+            this.wbuf.extend_from_slice(&response);
+        }
+        match this.mode {
+            ObfsMode::Http => this.wbuf.extend_from_slice(buf),
+            ObfsMode::Tls => {
+                for chunk in buf.chunks(MAX_RECORD_SIZE) {
+                    this.wbuf.put_slice(&[TLS_APPLICATION_DATA, 0x03, 0x03]);
+                    this.wbuf.put_u16(chunk.len() as u16);
+                    this.wbuf.put_slice(chunk);
+                }
+            }
+        }
+        // accepted, the rest is written on later calls
+        if let Poll::Ready(Err(e)) = this.poll_flush_wbuf(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_flush_wbuf(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_flush_wbuf(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncWriteExt};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// ClientHello as simple-obfs sends it, with `payload` as session ticket
+    fn client_hello(payload: &[u8]) -> Vec<u8> {
+        let mut body = BytesMut::new();
+        body.put_u16(0x0303);
+        body.put_slice(&[7u8; 32]);
+        body.put_u8(32);
+        body.put_slice(&[9u8; 32]);
+        body.put_u16(4);
+        body.put_slice(&[0xc0, 0x2b, 0xc0, 0x2f]);
+        body.put_slice(&[1, 0]);
+        let host = b"www.bing.com";
+        let mut exts = BytesMut::new();
+        exts.put_u16(TLS_EXT_SESSION_TICKET);
+        exts.put_u16(payload.len() as u16);
+        exts.put_slice(payload);
+        exts.put_u16(0); // server name
+        exts.put_u16(host.len() as u16 + 5);
+        exts.put_u16(host.len() as u16 + 3);
+        exts.put_u8(0);
+        exts.put_u16(host.len() as u16);
+        exts.put_slice(host);
+        body.put_u16(exts.len() as u16);
+        body.put_slice(&exts);
+
+        let mut out = vec![TLS_HANDSHAKE, 0x03, 0x01];
+        out.extend_from_slice(&(body.len() as u16 + 4).to_be_bytes());
+        out.extend_from_slice(&[1, 0]);
+        out.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        out.extend_from_slice(&body);
+        out
+    }
+
+    fn app_data(payload: &[u8]) -> Vec<u8> {
+        let mut out = vec![TLS_APPLICATION_DATA, 0x03, 0x03];
+        out.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        out.extend_from_slice(payload);
+        out
+    }
+
+    #[tokio::test]
+    async fn test_http() {
+        let (mut client, server) = duplex(4096);
+        client
+            .write_all(
+                b"GET / HTTP/1.1\r\nHost: www.bing.com\r\nUser-Agent: curl/7.58.0\r\n\
+                  Upgrade: websocket\r\nConnection: Upgrade\r\n\
+                  Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nContent-Length: 5\r\n\r\nhello",
+            )
+            .await
+            .unwrap();
+        let mut server = ObfsStream::accept(server, ObfsMode::Http, TIMEOUT)
+            .await
+            .ok()
+            .unwrap();
+        client.write_all(b" world").await.unwrap();
+        let mut buf = [0u8; 11];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello world");
+
+        server.write_all(b"resp").await.unwrap();
+        server.write_all(b"onse").await.unwrap();
+        server.flush().await.unwrap();
+        drop(server);
+        let mut resp = String::new();
+        client.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(resp.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert!(resp.ends_with("\r\n\r\nresponse"));
+    }
+
+    #[tokio::test]
+    async fn test_tls() {
+        let (mut client, server) = duplex(64 * 1024);
+        client.write_all(&client_hello(b"hello")).await.unwrap();
+        let mut server = ObfsStream::accept(server, ObfsMode::Tls, TIMEOUT)
+            .await
+            .ok()
+            .unwrap();
+        // fake handshake finish of client, then data split across records and writes
+        let mut rest = vec![TLS_CHANGE_CIPHER_SPEC, 0x03, 0x03, 0x00, 0x01, 0x01];
+        rest.extend_from_slice(&app_data(b" wor"));
+        rest.extend_from_slice(&app_data(b"ld"));
+        let (a, b) = rest.split_at(9);
+        client.write_all(a).await.unwrap();
+        client.write_all(b).await.unwrap();
+        let mut buf = [0u8; 11];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello world");
+
+        let big = vec![3u8; MAX_RECORD_SIZE + 1];
+        server.write_all(&big).await.unwrap();
+        server.flush().await.unwrap();
+        let mut head = [0u8; 5];
+        client.read_exact(&mut head).await.unwrap();
+        assert_eq!(head[0], TLS_HANDSHAKE);
+        let mut hello = vec![0u8; u16::from_be_bytes([head[3], head[4]]) as usize];
+        client.read_exact(&mut hello).await.unwrap();
+        assert_eq!(hello[0], 2);
+        // session id echoed
+        assert_eq!(&hello[38..39 + 32], &[&[32u8][..], &[9u8; 32]].concat()[..]);
+        let mut ccs = [0u8; 6];
+        client.read_exact(&mut ccs).await.unwrap();
+        assert_eq!(ccs[0], TLS_CHANGE_CIPHER_SPEC);
+        let mut finished = [0u8; 5 + 32];
+        client.read_exact(&mut finished).await.unwrap();
+        assert_eq!(finished[..5], [TLS_HANDSHAKE, 0x03, 0x03, 0x00, 0x20]);
+        let mut received = Vec::new();
+        for _ in 0..2 {
+            client.read_exact(&mut head).await.unwrap();
+            assert_eq!(head[0], TLS_APPLICATION_DATA);
+            let mut data = vec![0u8; u16::from_be_bytes([head[3], head[4]]) as usize];
+            client.read_exact(&mut data).await.unwrap();
+            received.extend_from_slice(&data);
+        }
+        assert_eq!(received, big);
+
+        drop(client);
+        let mut buf = [0u8; 1];
+        assert_eq!(server.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_accept_error() {
+        let probe = b"\x05\x01\x00 random probe\r\n\r\n";
+        let (mut client, server) = duplex(4096);
+        client.write_all(probe).await.unwrap();
+        let err = ObfsStream::accept(server, ObfsMode::Http, TIMEOUT)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(&err.received[..], probe);
+
+        let (mut client, server) = duplex(4096);
+        client.write_all(probe).await.unwrap();
+        let err = ObfsStream::accept(server, ObfsMode::Tls, TIMEOUT)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(&err.received[..], probe);
+
+        // a stalled handshake times out with what was received
+        let (mut client, server) = duplex(4096);
+        client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        let err = ObfsStream::accept(server, ObfsMode::Http, Duration::from_millis(50))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.error.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(&err.received[..], b"GET / HTTP/1.1\r\n");
+
+        // recorded raw bytes until stopped
+        let (mut client, server) = duplex(4096);
+        let hello = client_hello(b"hello");
+        client.write_all(&hello).await.unwrap();
+        let mut server = ObfsStream::accept(server, ObfsMode::Tls, TIMEOUT)
+            .await
+            .ok()
+            .unwrap();
+        let mut buf = [0u8; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        let (_, recorded) = server.into_inner();
+        assert_eq!(recorded.unwrap(), hello);
+    }
+}
@@ -0,0 +1,325 @@
+//! keep a plugin running: readiness probe after every start, restarts with exponential
+//! backoff until a max number of restarts in a row.
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::{lookup_host, TcpStream},
+    time::{self, Instant},
+};
+use tracing::{debug, error, info, warn};
+
+use super::{get_local_port, LocalAddr, Plugin, PluginConfig};
+
+/// interval between connects of the readiness probe
+const PROBE_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RestartPolicy {
+    /// restarts in a row before giving up, 0 means never restart
+    pub max_restarts: u32,
+    /// ms, backoff before the first restart, doubled for every restart in a row
+    pub backoff_min: u64,
+    /// ms
+    pub backoff_max: u64,
+    /// sec, a plugin running this long is healthy again and the restarts in a row reset
+    pub reset_after: u64,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        RestartPolicy {
+            max_restarts: 5,
+            backoff_min: 500,
+            backoff_max: 30_000,
+            reset_after: 60,
+        }
+    }
+}
+
+impl RestartPolicy {
+    /// backoff before restart `n` in a row, from 1
+    pub fn backoff(&self, n: u32) -> Duration {
+        let ms = self
+            .backoff_min
+            .saturating_mul(1u64 << n.saturating_sub(1).min(32))
+            .min(self.backoff_max);
+        Duration::from_millis(ms)
+    }
+}
+
+/// server plugin listening on the remote address and forwarding to a local address, or
+/// client plugin listening on the local address and forwarding to the remote server. the
+/// local address stays the same across restarts
+pub struct Supervisor {
+    cfg: PluginConfig,
+    remote_host: String,
+    remote_port: String,
+    local_addr: LocalAddr,
+    /// client plugin, which listens on the local address
+    client: bool,
+    plugin: Option<Plugin>,
+}
+
+impl Supervisor {
+    /// start the plugin forwarding to `local_addr`, which the server is listening on, and wait
+    /// until it is ready
+    pub async fn start(
+        cfg: &PluginConfig,
+        remote_host: &str,
+        remote_port: &str,
+        local_addr: LocalAddr,
+    ) -> io::Result<Self> {
+        let mut supervisor = Supervisor {
+            cfg: cfg.clone(),
+            remote_host: remote_host.to_string(),
+            remote_port: remote_port.to_string(),
+            local_addr,
+            client: false,
+            plugin: None,
+        };
+        supervisor.spawn().await?;
+        Ok(supervisor)
+    }
+
+    /// start a client plugin forwarding to the server, and wait until it listens on a free
+    /// loopback port, which is [`Supervisor::local_addr`]
+    pub async fn start_client(
+        cfg: &PluginConfig,
+        server_host: &str,
+        server_port: &str,
+    ) -> io::Result<Self> {
+        if cfg.local_unix_socket.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "local_unix_socket is for server plugins only",
+            ));
+        }
+        // the plugin binds it, so it is only known to be free right before the start
+        let local_addr = get_local_port(Ipv4Addr::LOCALHOST.into())?;
+        let mut supervisor = Supervisor {
+            cfg: cfg.clone(),
+            remote_host: server_host.to_string(),
+            remote_port: server_port.to_string(),
+            local_addr: LocalAddr::Tcp(local_addr),
+            client: true,
+            plugin: None,
+        };
+        supervisor.spawn().await?;
+        Ok(supervisor)
+    }
+
+    pub fn local_addr(&self) -> &LocalAddr {
+        &self.local_addr
+    }
+
+    async fn spawn(&mut self) -> io::Result<()> {
+        let plugin = Plugin::start(
+            &self.cfg,
+            &self.remote_host,
+            &self.remote_port,
+            self.local_addr.clone(),
+        )?;
+        self.plugin = Some(plugin);
+        if let Err(e) = self.wait_ready().await {
+            self.stop().await?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// connect the address the plugin listens on until it accepts
+    async fn wait_ready(&mut self) -> io::Result<()> {
+        if self.cfg.ready_timeout == 0 {
+            return Ok(());
+        }
+        let addr = match self.local_addr {
+            LocalAddr::Tcp(addr) if self.client => addr.to_string(),
+            _ => format!("{}:{}", self.remote_host, self.remote_port),
+        };
+        let mut probe = lookup_host(&addr)
+            .await?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, addr.clone()))?;
+        // listening on all interfaces is reachable on loopback
+        match probe.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => probe.set_ip(Ipv4Addr::LOCALHOST.into()),
+            IpAddr::V6(ip) if ip.is_unspecified() => probe.set_ip(Ipv6Addr::LOCALHOST.into()),
+            _ => {}
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(self.cfg.ready_timeout);
+        let plugin = self.plugin.as_mut().expect("plugin started");
+        loop {
+            if let Ok(Ok(_)) = time::timeout(PROBE_INTERVAL, TcpStream::connect(probe)).await {
+                debug!("plugin {} ready on {}", self.cfg.name, probe);
+                return Ok(());
+            }
+            if let Some(status) = plugin.try_wait()? {
+                return Err(io::Error::other(format!(
+                    "plugin exited before ready with status: {}",
+                    status
+                )));
+            }
+            if Instant::now() >= deadline {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("plugin not listening on {} in time", probe),
+                ));
+            }
+            time::sleep(PROBE_INTERVAL).await;
+        }
+    }
+
+    /// wait the plugin exits and restart it, returns the error when restarts in a row run out
+    pub async fn supervise(&mut self) -> io::Error {
+        let policy = self.cfg.restart.clone();
+        let mut restarts = 0;
+        let mut started = Instant::now();
+        loop {
+            let exited = match self.plugin.as_mut() {
+                Some(p) => match p.wait().await {
+                    Ok(status) => format!("plugin exited with status: {}", status),
+                    Err(e) => format!("plugin exited with error: {}", e),
+                },
+                None => "plugin not running".to_string(),
+            };
+            self.plugin = None;
+            if started.elapsed() >= Duration::from_secs(policy.reset_after) {
+                restarts = 0;
+            }
+            loop {
+                if restarts >= policy.max_restarts {
+                    return io::Error::other(format!(
+                        "{}, gave up after {} restarts in a row",
+                        exited, restarts
+                    ));
+                }
+                restarts += 1;
+                let backoff = policy.backoff(restarts);
+                warn!(
+                    "{}, restart {}/{} in {:?}",
+                    exited, restarts, policy.max_restarts, backoff
+                );
+                time::sleep(backoff).await;
+                match self.spawn().await {
+                    Ok(()) => {
+                        info!("plugin {} restarted", self.cfg.name);
+                        started = Instant::now();
+                        break;
+                    }
+                    Err(e) => error!("restart plugin {} error: {}", self.cfg.name, e),
+                }
+            }
+        }
+    }
+
+    /// stop the plugin if running
+    pub async fn stop(&mut self) -> io::Result<()> {
+        match self.plugin.take() {
+            Some(mut p) => p.stop().await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    fn sh(script: &str, restart: RestartPolicy, ready_timeout: u64) -> PluginConfig {
+        PluginConfig {
+            name: "sh".into(),
+            opts: None,
+            args: vec!["-c".into(), script.into()],
+            restart,
+            ready_timeout,
+            ..Default::default()
+        }
+    }
+
+    fn local() -> LocalAddr {
+        LocalAddr::Tcp(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 1))
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RestartPolicy::default();
+        assert_eq!(policy.backoff(1), Duration::from_millis(500));
+        assert_eq!(policy.backoff(3), Duration::from_millis(2000));
+        assert_eq!(policy.backoff(100), Duration::from_millis(30_000));
+    }
+
+    #[tokio::test]
+    async fn test_supervise() {
+        let policy = RestartPolicy {
+            max_restarts: 2,
+            backoff_min: 10,
+            ..Default::default()
+        };
+        let dir = std::env::temp_dir().join(format!("ss-light-plugin-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let starts = dir.join("starts");
+        let script = format!("echo started >> {}; exit 3", starts.display());
+        let mut s = Supervisor::start(&sh(&script, policy, 0), "127.0.0.1", "1", local())
+            .await
+            .unwrap();
+        let err = s.supervise().await;
+        assert!(
+            err.to_string().contains("gave up after 2 restarts"),
+            "{}",
+            err
+        );
+        let starts = std::fs::read_to_string(&starts).unwrap();
+        assert_eq!(starts.lines().count(), 3);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ready_and_stop() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        let script = "trap 'exit 0' TERM; while true; do sleep 0.1; done";
+        let mut s = Supervisor::start(
+            &sh(script, RestartPolicy::default(), 1),
+            "0.0.0.0",
+            &port,
+            local(),
+        )
+        .await
+        .unwrap();
+        let start = Instant::now();
+        s.stop().await.unwrap();
+        // exited on SIGTERM without waiting for the grace period
+        assert!(start.elapsed() < Duration::from_secs(2));
+        drop(listener);
+
+        // nothing listens, and a plugin exiting early is not ready either
+        let err = Supervisor::start(
+            &sh("sleep 5", RestartPolicy::default(), 1),
+            "127.0.0.1",
+            &port,
+            local(),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        let err = Supervisor::start(
+            &sh("exit 1", RestartPolicy::default(), 1),
+            "127.0.0.1",
+            &port,
+            local(),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("exited before ready"), "{}", err);
+    }
+}
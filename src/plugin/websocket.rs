@@ -0,0 +1,532 @@
+//! in-process websocket server transport, compatible with
+//! [v2ray-plugin](https://github.com/shadowsocks/v2ray-plugin) clients in websocket mode
+//! with `mux=0`.
+//!
+//! the client upgrades a `GET` request on `path`, then the shadowsocks stream is carried
+//! in binary frames both ways.
+use std::{
+    fmt, io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    time,
+};
+
+use super::{record, AcceptError, MAX_HANDSHAKE_SIZE};
+use crate::util::websocket_accept;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+/// payload of control frames is at most 125 bytes
+const MAX_CONTROL_SIZE: u64 = 125;
+
+/// server options from v2ray-plugin opts `path` and `host`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebSocketConfig {
+    /// request path to upgrade, `/` by default
+    pub path: String,
+    /// required `Host` header without port, any host if None
+    pub host: Option<String>,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        WebSocketConfig {
+            path: "/".to_string(),
+            host: None,
+        }
+    }
+}
+
+impl fmt::Display for WebSocketConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "websocket path={}", self.path)?;
+        if let Some(ref host) = self.host {
+            write!(f, " host={}", host)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct WebSocketStream<S> {
+    inner: S,
+    /// frames not parsed yet
+    rbuf: BytesMut,
+    /// payload left in the current frame, its opcode, mask and offset in the frame
+    frame_left: u64,
+    opcode: u8,
+    mask: [u8; 4],
+    mask_pos: usize,
+    /// payload of the current control frame
+    control: BytesMut,
+    /// close frame received, reads are eof
+    closed: bool,
+    /// close frame sent
+    close_sent: bool,
+    wbuf: BytesMut,
+    /// raw bytes read, until `stop_recording`
+    recorded: Option<BytesMut>,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("websocket: {}", msg))
+}
+
+/// header of an unmasked frame sent by server
+fn put_frame_header(buf: &mut BytesMut, opcode: u8, len: usize) {
+    buf.put_u8(0x80 | opcode);
+    if len < 126 {
+        buf.put_u8(len as u8);
+    } else if len <= u16::MAX as usize {
+        buf.put_u8(126);
+        buf.put_u16(len as u16);
+    } else {
+        buf.put_u8(127);
+        buf.put_u64(len as u64);
+    }
+}
+
+impl<S> WebSocketStream<S> {
+    /// stop keeping raw bytes, once the peer is known to be a client
+    pub fn stop_recording(&mut self) {
+        self.recorded = None;
+    }
+
+    /// inner stream and raw bytes read from it if still recording
+    pub fn into_inner(self) -> (S, Option<Bytes>) {
+        (self.inner, self.recorded.map(BytesMut::freeze))
+    }
+
+    fn unmask(&mut self, data: &mut [u8]) {
+        for b in data.iter_mut() {
+            *b ^= self.mask[self.mask_pos % 4];
+            self.mask_pos += 1;
+        }
+    }
+
+    /// parse a frame header from `rbuf`, false if more bytes are needed
+    fn parse_header(&mut self) -> io::Result<bool> {
+        if self.rbuf.len() < 2 {
+            return Ok(false);
+        }
+        let (b0, b1) = (self.rbuf[0], self.rbuf[1]);
+        if b1 & 0x80 == 0 {
+            return Err(invalid("client frame not masked"));
+        }
+        let (len, header_len) = match b1 & 0x7f {
+            126 if self.rbuf.len() >= 4 => {
+                (u16::from_be_bytes([self.rbuf[2], self.rbuf[3]]) as u64, 4)
+            }
+            127 if self.rbuf.len() >= 10 => {
+                let mut b = [0u8; 8];
+                b.copy_from_slice(&self.rbuf[2..10]);
+                (u64::from_be_bytes(b), 10)
+            }
+            126 | 127 => return Ok(false),
+            n => (n as u64, 2),
+        };
+        if self.rbuf.len() < header_len + 4 {
+            return Ok(false);
+        }
+        let opcode = b0 & 0x0f;
+        match opcode {
+            OPCODE_CONTINUATION | OPCODE_TEXT | OPCODE_BINARY => {}
+            OPCODE_CLOSE | OPCODE_PING | OPCODE_PONG if len <= MAX_CONTROL_SIZE => {}
+            _ => return Err(invalid("bad frame")),
+        }
+        self.mask
+            .copy_from_slice(&self.rbuf[header_len..header_len + 4]);
+        self.rbuf.advance(header_len + 4);
+        self.opcode = opcode;
+        self.frame_left = len;
+        self.mask_pos = 0;
+        self.control.clear();
+        Ok(true)
+    }
+
+    /// answer a complete control frame
+    fn on_control(&mut self) {
+        match self.opcode {
+            OPCODE_PING => {
+                put_frame_header(&mut self.wbuf, OPCODE_PONG, self.control.len());
+                self.wbuf.extend_from_slice(&self.control);
+            }
+            OPCODE_CLOSE => {
+                self.closed = true;
+                if !self.close_sent {
+                    self.close_sent = true;
+                    put_frame_header(&mut self.wbuf, OPCODE_CLOSE, 2);
+                    self.wbuf.put_u16(1000);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn is_data(&self) -> bool {
+        self.opcode < OPCODE_CLOSE
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WebSocketStream<S> {
+    /// read the upgrade request of client and answer it
+    pub async fn accept(
+        inner: S,
+        cfg: &WebSocketConfig,
+        timeout: Duration,
+    ) -> Result<Self, AcceptError<S>> {
+        let mut stream = WebSocketStream {
+            inner,
+            rbuf: BytesMut::new(),
+            frame_left: 0,
+            opcode: OPCODE_BINARY,
+            mask: [0; 4],
+            mask_pos: 0,
+            control: BytesMut::new(),
+            closed: false,
+            close_sent: false,
+            wbuf: BytesMut::new(),
+            recorded: Some(BytesMut::new()),
+        };
+        let res = time::timeout(timeout, stream.handshake(cfg))
+            .await
+            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()));
+        match res {
+            Ok(()) => Ok(stream),
+            Err(error) => {
+                let (stream, received) = stream.into_inner();
+                Err(AcceptError {
+                    stream,
+                    received: received.unwrap_or_default(),
+                    error,
+                })
+            }
+        }
+    }
+
+    async fn handshake(&mut self, cfg: &WebSocketConfig) -> io::Result<()> {
+        let header_len = loop {
+            if let Some(i) = self.rbuf.windows(4).position(|w| w == b"\r\n\r\n") {
+                break i + 4;
+            }
+            if self.rbuf.len() >= MAX_HANDSHAKE_SIZE {
+                return Err(invalid("handshake too large"));
+            }
+            let mut buf = [0u8; 4096];
+            let n = self.inner.read(&mut buf).await?;
+            if n == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            self.rbuf.extend_from_slice(&buf[..n]);
+            record(&mut self.recorded, &buf[..n]);
+        };
+        let header = std::str::from_utf8(&self.rbuf[..header_len])
+            .map_err(|_| invalid("http header not utf8"))?;
+        let mut lines = header.split("\r\n");
+        let path = match lines
+            .next()
+            .unwrap_or_default()
+            .split(' ')
+            .collect::<Vec<_>>()[..]
+        {
+            ["GET", uri, "HTTP/1.1"] => uri.split('?').next().unwrap_or_default(),
+            _ => return Err(invalid("not a http get request")),
+        };
+        if path != cfg.path {
+            return Err(invalid("path mismatch"));
+        }
+        let (mut host, mut upgrade, mut key) = (None, false, None);
+        for line in lines {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("host") {
+                host = Some(value.rsplit_once(':').map_or(value, |(h, _)| h));
+            } else if name.eq_ignore_ascii_case("upgrade") {
+                upgrade = value.eq_ignore_ascii_case("websocket");
+            } else if name.eq_ignore_ascii_case("sec-websocket-key") {
+                key = Some(value);
+            }
+        }
+        if let Some(ref want) = cfg.host {
+            if !host.is_some_and(|h| h.eq_ignore_ascii_case(want)) {
+                return Err(invalid("host mismatch"));
+            }
+        }
+        let key = match key {
+            Some(key) if upgrade => key,
+            _ => return Err(invalid("not a websocket upgrade")),
+        };
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n\r\n",
+            websocket_accept(key)
+        );
+        self.rbuf.advance(header_len);
+        self.inner.write_all(response.as_bytes()).await
+    }
+
+    /// read and record raw bytes into `rbuf`, Ok(0) is eof
+    fn poll_fill(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let mut buf = [0u8; 8192];
+        let mut read_buf = ReadBuf::new(&mut buf);
+        ready!(Pin::new(&mut self.inner).poll_read(cx, &mut read_buf))?;
+        let n = read_buf.filled().len();
+        self.rbuf.extend_from_slice(&buf[..n]);
+        record(&mut self.recorded, &buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush_wbuf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.wbuf.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.wbuf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.wbuf.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocketStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.closed {
+                return Poll::Ready(Ok(()));
+            }
+            if this.frame_left > 0 && !this.rbuf.is_empty() {
+                let mut n = this.frame_left.min(this.rbuf.len() as u64) as usize;
+                if this.is_data() {
+                    n = n.min(buf.remaining());
+                }
+                let mut data = this.rbuf.split_to(n);
+                this.unmask(&mut data);
+                this.frame_left -= n as u64;
+                if this.is_data() {
+                    buf.put_slice(&data);
+                    return Poll::Ready(Ok(()));
+                }
+                this.control.extend_from_slice(&data);
+            } else if this.frame_left == 0 && this.parse_header()? {
+                // a control frame may be empty
+            } else if ready!(this.poll_fill(cx))? == 0 {
+                if this.rbuf.is_empty() && this.frame_left == 0 {
+                    return Poll::Ready(Ok(()));
+                }
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            } else {
+                continue;
+            }
+            if this.frame_left == 0 && !this.is_data() {
+                this.on_control();
+                // best effort, the rest goes out with the next write
+                if let Poll::Ready(Err(e)) = this.poll_flush_wbuf(cx) {
+                    return Poll::Ready(Err(e));
+                }
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocketStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_flush_wbuf(cx))?;
+        if this.close_sent {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        put_frame_header(&mut this.wbuf, OPCODE_BINARY, buf.len());
+        this.wbuf.extend_from_slice(buf);
+        // accepted, the rest is written on later calls
+        if let Poll::Ready(Err(e)) = this.poll_flush_wbuf(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_flush_wbuf(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.close_sent {
+            this.close_sent = true;
+            put_frame_header(&mut this.wbuf, OPCODE_CLOSE, 2);
+            this.wbuf.put_u16(1000);
+        }
+        ready!(this.poll_flush_wbuf(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    const REQUEST: &str =
+        "GET /dog HTTP/1.1\r\nHost: xxx.com:443\r\nUser-Agent: Go-http-client/1.1\r\n\
+        Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+        Sec-WebSocket-Version: 13\r\nUpgrade: websocket\r\n\r\n";
+
+    fn cfg() -> WebSocketConfig {
+        WebSocketConfig {
+            path: "/dog".into(),
+            host: Some("xxx.com".into()),
+        }
+    }
+
+    /// frame as a client sends it, masked
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [1u8, 2, 3, 4];
+        let mut out = vec![if fin { 0x80 } else { 0 } | opcode];
+        if payload.len() < 126 {
+            out.push(0x80 | payload.len() as u8);
+        } else {
+            out.push(0x80 | 126);
+            out.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        }
+        out.extend_from_slice(&mask);
+        out.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        out
+    }
+
+    async fn read_frame<R: AsyncRead + Unpin>(r: &mut R) -> (u8, Vec<u8>) {
+        let mut head = [0u8; 2];
+        r.read_exact(&mut head).await.unwrap();
+        assert_eq!(head[1] & 0x80, 0);
+        let len = match head[1] {
+            126 => r.read_u16().await.unwrap() as usize,
+            127 => r.read_u64().await.unwrap() as usize,
+            n => n as usize,
+        };
+        let mut payload = vec![0u8; len];
+        r.read_exact(&mut payload).await.unwrap();
+        (head[0], payload)
+    }
+
+    #[tokio::test]
+    async fn test_websocket() {
+        let (mut client, server) = duplex(256 * 1024);
+        client.write_all(REQUEST.as_bytes()).await.unwrap();
+        let mut server = WebSocketStream::accept(server, &cfg(), TIMEOUT)
+            .await
+            .ok()
+            .unwrap();
+        let expected = "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+            Connection: Upgrade\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n";
+        let mut resp = vec![0u8; expected.len()];
+        client.read_exact(&mut resp).await.unwrap();
+        assert_eq!(resp, expected.as_bytes());
+
+        // fragmented message with a ping in between, split across writes
+        let big = vec![5u8; 300];
+        let mut frames = client_frame(false, OPCODE_BINARY, b"hello");
+        frames.extend(client_frame(true, OPCODE_PING, b"p"));
+        frames.extend(client_frame(true, OPCODE_CONTINUATION, b" world"));
+        frames.extend(client_frame(true, OPCODE_BINARY, &big));
+        let (a, b) = frames.split_at(7);
+        client.write_all(a).await.unwrap();
+        client.write_all(b).await.unwrap();
+        let mut buf = vec![0u8; 11 + big.len()];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf[..11], b"hello world");
+        assert_eq!(&buf[11..], &big[..]);
+        assert_eq!(
+            read_frame(&mut client).await,
+            (0x80 | OPCODE_PONG, b"p".to_vec())
+        );
+
+        let big = vec![7u8; 70000];
+        server.write_all(b"response").await.unwrap();
+        server.write_all(&big).await.unwrap();
+        server.flush().await.unwrap();
+        assert_eq!(
+            read_frame(&mut client).await,
+            (0x80 | OPCODE_BINARY, b"response".to_vec())
+        );
+        assert_eq!(read_frame(&mut client).await, (0x80 | OPCODE_BINARY, big));
+
+        // close is answered and ends reading
+        client
+            .write_all(&client_frame(true, OPCODE_CLOSE, &1000u16.to_be_bytes()))
+            .await
+            .unwrap();
+        assert_eq!(server.read(&mut buf).await.unwrap(), 0);
+        server.flush().await.unwrap();
+        assert_eq!(
+            read_frame(&mut client).await,
+            (0x80 | OPCODE_CLOSE, 1000u16.to_be_bytes().to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_accept_error() {
+        for request in [
+            REQUEST.replace("/dog", "/cat"),
+            REQUEST.replace("xxx.com", "yyy.com"),
+            REQUEST.replace("Upgrade: websocket\r\n", ""),
+            "\x05\x01\x00 random probe\r\n\r\n".to_string(),
+        ] {
+            let (mut client, server) = duplex(4096);
+            client.write_all(request.as_bytes()).await.unwrap();
+            let err = WebSocketStream::accept(server, &cfg(), TIMEOUT)
+                .await
+                .err()
+                .unwrap();
+            assert_eq!(err.error.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(&err.received[..], request.as_bytes());
+        }
+
+        // any host and the default path
+        let (mut client, server) = duplex(4096);
+        client
+            .write_all(REQUEST.replace("/dog", "/?ed=2048").as_bytes())
+            .await
+            .unwrap();
+        assert!(
+            WebSocketStream::accept(server, &WebSocketConfig::default(), TIMEOUT)
+                .await
+                .is_ok()
+        );
+
+        // unmasked client frame
+        let (mut client, server) = duplex(4096);
+        client.write_all(REQUEST.as_bytes()).await.unwrap();
+        let mut server = WebSocketStream::accept(server, &cfg(), TIMEOUT)
+            .await
+            .ok()
+            .unwrap();
+        client.write_all(&[0x82, 0x01, 0x00]).await.unwrap();
+        let mut buf = [0u8; 1];
+        let err = server.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
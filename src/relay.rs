@@ -0,0 +1,372 @@
+//! bidirectional relay with idle timeout, lifetime limit and half-close linger
+use std::{
+    fmt::{self, Formatter},
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{future, ready};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{self, Instant},
+};
+
+const RELAY_BUFFER_SIZE: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RelayConfig {
+    /// sec, close when no data is transferred in either direction, 0 means no limit
+    pub idle_timeout: u64,
+    /// sec, close when buffered data can't be written to the other side, 0 means no limit
+    pub write_timeout: u64,
+    /// sec, max lifetime of a connection, 0 means no limit
+    pub max_lifetime: u64,
+    /// sec, after one side sends FIN, wait the other side at most this long, 0 means no limit
+    pub half_close_linger: u64,
+}
+
+fn deadline(from: Instant, secs: u64) -> Option<Instant> {
+    if secs == 0 {
+        None
+    } else {
+        Some(from + Duration::from_secs(secs))
+    }
+}
+
+#[derive(Debug)]
+pub enum CloseReason {
+    /// both sides finished
+    Eof,
+    IdleTimeout,
+    WriteTimeout,
+    LifetimeExceeded,
+    LingerTimeout,
+    Error(io::Error),
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            CloseReason::Eof => write!(f, "eof"),
+            CloseReason::IdleTimeout => write!(f, "idle timeout"),
+            CloseReason::WriteTimeout => write!(f, "write timeout"),
+            CloseReason::LifetimeExceeded => write!(f, "lifetime exceeded"),
+            CloseReason::LingerTimeout => write!(f, "half-close linger timeout"),
+            CloseReason::Error(ref e) => write!(f, "error: {}", e),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RelayResult {
+    /// bytes from a to b
+    pub a2b: u64,
+    /// bytes from b to a
+    pub b2a: u64,
+    pub reason: CloseReason,
+}
+
+/// one direction of the relay
+struct Transfer {
+    buf: Box<[u8]>,
+    pos: usize,
+    cap: usize,
+    amt: u64,
+    read_done: bool,
+    need_flush: bool,
+    done: bool,
+    last_activity: Instant,
+    write_pending_since: Option<Instant>,
+}
+
+impl Transfer {
+    fn new(now: Instant) -> Self {
+        Transfer {
+            buf: vec![0u8; RELAY_BUFFER_SIZE].into_boxed_slice(),
+            pos: 0,
+            cap: 0,
+            amt: 0,
+            read_done: false,
+            need_flush: false,
+            done: false,
+            last_activity: now,
+            write_pending_since: None,
+        }
+    }
+
+    fn poll_copy<R, W>(
+        &mut self,
+        cx: &mut Context,
+        mut reader: Pin<&mut R>,
+        mut writer: Pin<&mut W>,
+    ) -> Poll<io::Result<()>>
+    where
+        R: AsyncRead + ?Sized,
+        W: AsyncWrite + ?Sized,
+    {
+        loop {
+            if self.pos == self.cap && !self.read_done {
+                let mut buf = ReadBuf::new(&mut self.buf);
+                match reader.as_mut().poll_read(cx, &mut buf) {
+                    Poll::Ready(Ok(())) => {
+                        let n = buf.filled().len();
+                        if n == 0 {
+                            self.read_done = true;
+                        } else {
+                            self.pos = 0;
+                            self.cap = n;
+                            self.last_activity = Instant::now();
+                        }
+                    }
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => {
+                        if self.need_flush {
+                            ready!(writer.as_mut().poll_flush(cx))?;
+                            self.need_flush = false;
+                        }
+                        return Poll::Pending;
+                    }
+                }
+            }
+
+            while self.pos < self.cap {
+                let n = match writer
+                    .as_mut()
+                    .poll_write(cx, &self.buf[self.pos..self.cap])
+                {
+                    Poll::Ready(r) => r?,
+                    Poll::Pending => {
+                        self.write_pending_since.get_or_insert_with(Instant::now);
+                        return Poll::Pending;
+                    }
+                };
+                if n == 0 {
+                    return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                }
+                self.pos += n;
+                self.amt += n as u64;
+                self.need_flush = true;
+                self.last_activity = Instant::now();
+                self.write_pending_since = None;
+            }
+
+            if self.pos == self.cap && self.read_done {
+                ready!(writer.as_mut().poll_flush(cx))?;
+                ready!(writer.as_mut().poll_shutdown(cx))?;
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+/// copy data between a and b in both directions until both sides finish or a limit is hit
+///
+/// when one side sends FIN, the write half of the other side is shut down and the remaining
+/// direction keeps running for at most `half_close_linger`
+pub async fn relay<A, B>(a: &mut A, b: &mut B, cfg: &RelayConfig) -> RelayResult
+where
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let start = Instant::now();
+    let mut a2b = Transfer::new(start);
+    let mut b2a = Transfer::new(start);
+    let mut half_closed_at: Option<Instant> = None;
+    let sleep = time::sleep_until(start);
+    tokio::pin!(sleep);
+
+    let reason = future::poll_fn(|cx| loop {
+        if !a2b.done {
+            match a2b.poll_copy(cx, Pin::new(&mut *a), Pin::new(&mut *b)) {
+                Poll::Ready(Ok(())) => a2b.done = true,
+                Poll::Ready(Err(e)) => return Poll::Ready(CloseReason::Error(e)),
+                Poll::Pending => {}
+            }
+        }
+        if !b2a.done {
+            match b2a.poll_copy(cx, Pin::new(&mut *b), Pin::new(&mut *a)) {
+                Poll::Ready(Ok(())) => b2a.done = true,
+                Poll::Ready(Err(e)) => return Poll::Ready(CloseReason::Error(e)),
+                Poll::Pending => {}
+            }
+        }
+        if a2b.done && b2a.done {
+            return Poll::Ready(CloseReason::Eof);
+        }
+        let now = Instant::now();
+        if a2b.done || b2a.done {
+            half_closed_at.get_or_insert(now);
+        }
+
+        let last_activity = a2b.last_activity.max(b2a.last_activity);
+        let write_pending_since = match (a2b.write_pending_since, b2a.write_pending_since) {
+            (Some(x), Some(y)) => Some(x.min(y)),
+            (x, y) => x.or(y),
+        };
+        let deadlines = [
+            (
+                deadline(last_activity, cfg.idle_timeout),
+                CloseReason::IdleTimeout,
+            ),
+            (
+                write_pending_since.and_then(|t| deadline(t, cfg.write_timeout)),
+                CloseReason::WriteTimeout,
+            ),
+            (
+                deadline(start, cfg.max_lifetime),
+                CloseReason::LifetimeExceeded,
+            ),
+            (
+                half_closed_at.and_then(|t| deadline(t, cfg.half_close_linger)),
+                CloseReason::LingerTimeout,
+            ),
+        ];
+        let earliest = deadlines
+            .into_iter()
+            .filter_map(|(d, reason)| d.map(|d| (d, reason)))
+            .min_by_key(|(d, _)| *d);
+
+        match earliest {
+            None => return Poll::Pending,
+            Some((d, reason)) => {
+                if d <= now {
+                    return Poll::Ready(reason);
+                }
+                if sleep.deadline() != d {
+                    sleep.as_mut().reset(d);
+                }
+                if sleep.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                // timer fired between reading the clock and polling, check again
+            }
+        }
+    })
+    .await;
+
+    RelayResult {
+        a2b: a2b.amt,
+        b2a: b2a.amt,
+        reason,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::{relay, CloseReason, RelayConfig};
+
+    #[tokio::test]
+    async fn test_relay_both_directions() {
+        let (mut client, mut a) = duplex(64);
+        let (mut b, mut remote) = duplex(64);
+        let handle =
+            tokio::spawn(async move { relay(&mut a, &mut b, &RelayConfig::default()).await });
+
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        remote.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        remote.write_all(b"pong!").await.unwrap();
+        let mut buf = [0u8; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong!");
+
+        client.shutdown().await.unwrap();
+        remote.shutdown().await.unwrap();
+        let result = handle.await.unwrap();
+        assert_eq!(result.a2b, 4);
+        assert_eq!(result.b2a, 5);
+        assert!(matches!(result.reason, CloseReason::Eof));
+    }
+
+    #[tokio::test]
+    async fn test_relay_idle_timeout() {
+        let (_client, mut a) = duplex(64);
+        let (mut b, _remote) = duplex(64);
+        let cfg = RelayConfig {
+            idle_timeout: 1,
+            ..Default::default()
+        };
+        let result = tokio::time::timeout(Duration::from_secs(3), relay(&mut a, &mut b, &cfg))
+            .await
+            .unwrap();
+        assert!(matches!(result.reason, CloseReason::IdleTimeout));
+    }
+
+    #[tokio::test]
+    async fn test_relay_half_close_linger() {
+        let (mut client, mut a) = duplex(64);
+        let (mut b, mut remote) = duplex(64);
+        let cfg = RelayConfig {
+            half_close_linger: 1,
+            ..Default::default()
+        };
+        let handle = tokio::spawn(async move { relay(&mut a, &mut b, &cfg).await });
+
+        client.write_all(b"bye").await.unwrap();
+        client.shutdown().await.unwrap();
+
+        // FIN is forwarded to the remote
+        let mut buf = Vec::new();
+        remote.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"bye");
+
+        // remote never closes, linger ends the relay
+        let result = tokio::time::timeout(Duration::from_secs(3), handle)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(result.a2b, 3);
+        assert!(matches!(result.reason, CloseReason::LingerTimeout));
+    }
+
+    #[tokio::test]
+    async fn test_relay_max_lifetime() {
+        let (mut client, mut a) = duplex(64);
+        let (mut b, mut remote) = duplex(64);
+        let cfg = RelayConfig {
+            max_lifetime: 1,
+            ..Default::default()
+        };
+        let handle = tokio::spawn(async move { relay(&mut a, &mut b, &cfg).await });
+
+        // keep the connection busy, lifetime still ends it
+        let mut buf = [0u8; 1];
+        for _ in 0..3 {
+            client.write_all(b"x").await.unwrap();
+            remote.read_exact(&mut buf).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(300)).await;
+        }
+        let result = handle.await.unwrap();
+        assert!(matches!(result.reason, CloseReason::LifetimeExceeded));
+    }
+
+    #[tokio::test]
+    async fn test_relay_write_timeout() {
+        let (mut client, mut a) = duplex(64);
+        // remote never reads, writes block when the small buffer fills up
+        let (mut b, _remote) = duplex(8);
+        let cfg = RelayConfig {
+            write_timeout: 1,
+            ..Default::default()
+        };
+        let handle = tokio::spawn(async move { relay(&mut a, &mut b, &cfg).await });
+
+        client.write_all(&[0u8; 32]).await.unwrap();
+        let result = tokio::time::timeout(Duration::from_secs(3), handle)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(result.reason, CloseReason::WriteTimeout));
+    }
+}
@@ -0,0 +1,472 @@
+//! tls termination of the server listener with rustls.
+//!
+//! the certificate is picked by the SNI of the client, falling back to the default one, and
+//! certificate files are reloaded when their modification time changes.
+use std::{
+    collections::HashMap,
+    fs::File,
+    future::Future,
+    io::{self, BufReader},
+    pin::Pin,
+    sync::{Arc, Mutex, RwLock, Weak},
+    task::{ready, Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+use bytes::BytesMut;
+
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::{self, CertifiedKey},
+    Certificate, PrivateKey, ServerConfig,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+    time::{self, Sleep},
+};
+use tokio_rustls::server::TlsStream;
+use tracing::{info, warn};
+
+use crate::{
+    defense::ProbeStream,
+    plugin::{record, AcceptError},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    /// pem certificate chain
+    pub cert: String,
+    /// pem private key, pkcs8, rsa or ec
+    pub key: String,
+    /// protocols offered in ALPN, like `http/1.1`
+    pub alpn: Vec<String>,
+    /// certificates selected by SNI
+    pub sni: Vec<SniCert>,
+    /// sec, between checks of certificate files for changes, 0 means no reload
+    pub reload_interval: u64,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            cert: String::new(),
+            key: String::new(),
+            alpn: vec![],
+            sni: vec![],
+            reload_interval: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SniCert {
+    /// like `example.com`, or `*.example.com` for one level of subdomains
+    pub server_name: String,
+    pub cert: String,
+    pub key: String,
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn load_certified_key(cert: &str, key: &str) -> io::Result<CertifiedKey> {
+    let open = |path: &str| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| io::Error::new(e.kind(), format!("open {}: {}", path, e)))
+    };
+    let certs: Vec<_> = rustls_pemfile::certs(&mut open(cert)?)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(invalid(format!("no certificate in {}", cert)));
+    }
+    let key = rustls_pemfile::read_all(&mut open(key)?)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(k)
+            | rustls_pemfile::Item::RSAKey(k)
+            | rustls_pemfile::Item::ECKey(k) => Some(PrivateKey(k)),
+            _ => None,
+        })
+        .ok_or_else(|| invalid(format!("no private key in {}", key)))?;
+    let key = sign::any_supported_type(&key)
+        .map_err(|e| invalid(format!("private key {}: {}", cert, e)))?;
+    Ok(CertifiedKey::new(certs, key))
+}
+
+struct Certs {
+    default: Arc<CertifiedKey>,
+    names: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl Certs {
+    fn load(cfg: &TlsConfig) -> io::Result<Self> {
+        let default = Arc::new(load_certified_key(&cfg.cert, &cfg.key)?);
+        let mut names = HashMap::new();
+        for sni in &cfg.sni {
+            let key = load_certified_key(&sni.cert, &sni.key)?;
+            names.insert(sni.server_name.to_ascii_lowercase(), Arc::new(key));
+        }
+        Ok(Certs { default, names })
+    }
+
+    fn get(&self, server_name: Option<&str>) -> Arc<CertifiedKey> {
+        let found = server_name.and_then(|name| {
+            let name = name.to_ascii_lowercase();
+            self.names.get(&name).or_else(|| {
+                let (_, parent) = name.split_once('.')?;
+                self.names.get(&format!("*.{}", parent))
+            })
+        });
+        found.unwrap_or(&self.default).clone()
+    }
+}
+
+struct CertResolver(RwLock<Certs>);
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let certs = self.0.read().unwrap_or_else(|e| e.into_inner());
+        Some(certs.get(client_hello.server_name()))
+    }
+}
+
+pub struct TlsAcceptor {
+    cfg: TlsConfig,
+    resolver: Arc<CertResolver>,
+    acceptor: tokio_rustls::TlsAcceptor,
+    /// modification time of every certificate and key file when loaded
+    modified: Mutex<Vec<Option<SystemTime>>>,
+}
+
+impl TlsAcceptor {
+    pub fn new(cfg: &TlsConfig) -> io::Result<Self> {
+        let modified = Self::modified(cfg);
+        let resolver = Arc::new(CertResolver(RwLock::new(Certs::load(cfg)?)));
+        let mut server = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(resolver.clone());
+        server.alpn_protocols = cfg.alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+        Ok(TlsAcceptor {
+            cfg: cfg.clone(),
+            resolver,
+            acceptor: Arc::new(server).into(),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    /// tls handshake, failed with `TimedOut` if not done in `timeout`. the stream and raw
+    /// bytes received are given back on failure
+    pub async fn accept<S>(
+        &self,
+        stream: S,
+        timeout: Duration,
+    ) -> Result<TlsStream<TlsSocket<S>>, AcceptError<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let socket = TlsSocket {
+            inner: stream,
+            deadline: Some(Box::pin(time::sleep(timeout))),
+            recorded: Some(BytesMut::new()),
+        };
+        match self.acceptor.accept(socket).into_fallible().await {
+            Ok(mut stream) => {
+                let socket = stream.get_mut().0;
+                socket.deadline = None;
+                socket.recorded = None;
+                Ok(stream)
+            }
+            Err((error, socket)) => Err(AcceptError {
+                stream: socket.inner,
+                received: socket.recorded.map(BytesMut::freeze).unwrap_or_default(),
+                error,
+            }),
+        }
+    }
+
+    fn modified(cfg: &TlsConfig) -> Vec<Option<SystemTime>> {
+        let files = [&cfg.cert, &cfg.key]
+            .into_iter()
+            .chain(cfg.sni.iter().flat_map(|s| [&s.cert, &s.key]));
+        files
+            .map(|f| std::fs::metadata(f).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+
+    /// load certificates again if any file changed, Ok(true) if reloaded. on error the
+    /// current certificates are kept
+    pub fn reload_if_changed(&self) -> io::Result<bool> {
+        let modified = Self::modified(&self.cfg);
+        let mut last = self.modified.lock().unwrap_or_else(|e| e.into_inner());
+        if *last == modified {
+            return Ok(false);
+        }
+        // not retried until the files change again
+        *last = modified;
+        let certs = Certs::load(&self.cfg)?;
+        *self.resolver.0.write().unwrap_or_else(|e| e.into_inner()) = certs;
+        Ok(true)
+    }
+
+    /// check certificate files every `reload_interval` until the acceptor is dropped
+    pub fn watch(acceptor: &Arc<Self>) {
+        if acceptor.cfg.reload_interval == 0 {
+            return;
+        }
+        let interval = Duration::from_secs(acceptor.cfg.reload_interval);
+        let weak: Weak<Self> = Arc::downgrade(acceptor);
+        tokio::spawn(async move {
+            loop {
+                time::sleep(interval).await;
+                let Some(acceptor) = weak.upgrade() else {
+                    return;
+                };
+                match acceptor.reload_if_changed() {
+                    Ok(true) => info!("tls certificates reloaded"),
+                    Ok(false) => {}
+                    Err(e) => warn!("reload tls certificates error: {}, keep current ones", e),
+                }
+            }
+        });
+    }
+}
+
+/// socket under tls, reads and writes fail with `TimedOut` after the handshake deadline and
+/// raw bytes are kept for probe defense until the handshake is done
+pub struct TlsSocket<S> {
+    inner: S,
+    deadline: Option<Pin<Box<Sleep>>>,
+    recorded: Option<BytesMut>,
+}
+
+impl<S> TlsSocket<S> {
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    fn poll_deadline(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        let expired = match self.deadline {
+            Some(ref mut deadline) => deadline.as_mut().poll(cx).is_ready(),
+            None => false,
+        };
+        if expired {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        Ok(())
+    }
+}
+
+impl<S: ProbeStream> ProbeStream for TlsSocket<S> {
+    fn tcp(&self) -> Option<&TcpStream> {
+        self.inner.tcp()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TlsSocket<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.poll_deadline(cx)?;
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        record(&mut this.recorded, &buf.filled()[filled..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TlsSocket<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.poll_deadline(cx)?;
+        Pin::new(&mut this.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.poll_deadline(cx)?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::{ClientConfig, RootCertStore, ServerName};
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsConnector;
+
+    /// self-signed certificate for `names`, written to `dir/<file>.crt` and `.key`
+    fn self_signed(dir: &std::path::Path, file: &str, names: &[&str]) -> (SniCert, Certificate) {
+        let cert = rcgen::generate_simple_self_signed(
+            names.iter().map(|n| n.to_string()).collect::<Vec<_>>(),
+        )
+        .unwrap();
+        let (crt, key) = (
+            dir.join(format!("{}.crt", file)),
+            dir.join(format!("{}.key", file)),
+        );
+        std::fs::write(&crt, cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&key, cert.serialize_private_key_pem()).unwrap();
+        let sni = SniCert {
+            server_name: names[0].to_string(),
+            cert: crt.to_string_lossy().into(),
+            key: key.to_string_lossy().into(),
+        };
+        (sni, Certificate(cert.serialize_der().unwrap()))
+    }
+
+    /// handshake as a client trusting only `root`, returns the negotiated alpn
+    async fn connect(
+        acceptor: &TlsAcceptor,
+        server_name: &str,
+        root: &Certificate,
+        alpn: &[&str],
+    ) -> io::Result<Option<Vec<u8>>> {
+        let mut roots = RootCertStore::empty();
+        roots.add(root).unwrap();
+        let mut cfg = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        cfg.alpn_protocols = alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+        let connector = TlsConnector::from(Arc::new(cfg));
+        let (client, server) = duplex(64 * 1024);
+        let name = ServerName::try_from(server_name).unwrap();
+        let (client, server) = tokio::join!(
+            connector.connect(name, client),
+            acceptor.accept(server, Duration::from_secs(5))
+        );
+        let (mut client, mut server) = (client?, server.map_err(|e| e.error)?);
+        client.write_all(b"hello").await?;
+        client.flush().await?;
+        let mut buf = [0u8; 5];
+        server.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello");
+        Ok(server.get_ref().1.alpn_protocol().map(|p| p.to_vec()))
+    }
+
+    #[tokio::test]
+    async fn test_tls_acceptor() {
+        let dir = std::env::temp_dir().join(format!("ss-light-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (default, default_root) = self_signed(&dir, "default", &["default.test"]);
+        let (a, a_root) = self_signed(&dir, "a", &["a.test"]);
+        let (mut wild, wild_root) = self_signed(&dir, "wild", &["*.b.test"]);
+        wild.server_name = "*.B.test".into();
+        let cfg = TlsConfig {
+            cert: default.cert.clone(),
+            key: default.key.clone(),
+            alpn: vec!["http/1.1".into()],
+            sni: vec![a.clone(), wild],
+            ..Default::default()
+        };
+        let acceptor = TlsAcceptor::new(&cfg).unwrap();
+
+        let alpn = connect(&acceptor, "a.test", &a_root, &["h2", "http/1.1"])
+            .await
+            .unwrap();
+        assert_eq!(alpn.as_deref(), Some(&b"http/1.1"[..]));
+        connect(&acceptor, "x.b.test", &wild_root, &[])
+            .await
+            .unwrap();
+        connect(&acceptor, "default.test", &default_root, &[])
+            .await
+            .unwrap();
+        // unknown names get the default certificate
+        assert!(connect(&acceptor, "c.test", &a_root, &[]).await.is_err());
+
+        // replaced files are picked up
+        assert!(!acceptor.reload_if_changed().unwrap());
+        let (_, new_root) = self_signed(&dir, "a", &["a.test"]);
+        let later = SystemTime::now() + Duration::from_secs(10);
+        File::options()
+            .write(true)
+            .open(&a.cert)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert!(acceptor.reload_if_changed().unwrap());
+        connect(&acceptor, "a.test", &new_root, &[]).await.unwrap();
+        assert!(connect(&acceptor, "a.test", &a_root, &[]).await.is_err());
+
+        // broken files keep the current certificates
+        std::fs::write(&a.key, "broken").unwrap();
+        File::options()
+            .write(true)
+            .open(&a.key)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert!(acceptor.reload_if_changed().is_err());
+        connect(&acceptor, "a.test", &new_root, &[]).await.unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_handshake_timeout() {
+        let dir = std::env::temp_dir().join(format!("ss-light-tls-to-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (default, root) = self_signed(&dir, "default", &["default.test"]);
+        let cfg = TlsConfig {
+            cert: default.cert,
+            key: default.key,
+            ..Default::default()
+        };
+        let acceptor = TlsAcceptor::new(&cfg).unwrap();
+
+        // a stalled handshake gives back the socket and what was received
+        let (mut client, server) = duplex(4096);
+        client.write_all(b"\x16\x03\x01\x00").await.unwrap();
+        let err = acceptor
+            .accept(server, Duration::from_millis(50))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.error.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(&err.received[..], b"\x16\x03\x01\x00");
+
+        // the deadline is gone once the handshake is done
+        let mut roots = RootCertStore::empty();
+        roots.add(&root).unwrap();
+        let client_cfg = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(client_cfg));
+        let (client, server) = duplex(64 * 1024);
+        let name = ServerName::try_from("default.test").unwrap();
+        let (client, server) = tokio::join!(
+            connector.connect(name, client),
+            acceptor.accept(server, Duration::from_millis(200))
+        );
+        let (mut client, mut server) = (client.unwrap(), server.ok().unwrap());
+        time::sleep(Duration::from_millis(300)).await;
+        client.write_all(b"hello").await.unwrap();
+        client.flush().await.unwrap();
+        let mut buf = [0u8; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,33 +1,80 @@
 use std::{
+    collections::HashMap,
     net::{Ipv4Addr, Ipv6Addr, SocketAddr},
     sync::Arc,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use bytes::Bytes;
-use futures::future;
+use futures::{future, Future};
 use lru_time_cache::LruCache;
 use tokio::{
     io,
     net::{lookup_host, UdpSocket},
-    sync::mpsc,
+    sync::{mpsc, watch},
     task::JoinHandle,
     time,
 };
-use tracing::{debug, error, trace, warn};
+use tracing::{debug, error, field, info_span, trace, warn, Instrument, Span};
 
 use crate::{
     consts::{MAXIMUM_UDP_PAYLOAD_SIZE, UDP_KEEP_ALIVE_CHANNEL_SIZE, UDP_SEND_CHANNEL_SIZE},
     crypto::PacketCipher,
-    Address, CipherKind,
+    limit::FlowLimit,
+    outbound::{Outbound, Upstream},
+    Address, CipherKind, Error,
 };
 
+/// per user policy of new associations
+pub trait UdpPolicy: Send + Sync {
+    /// limit of a new association of `user`, None refuses it
+    fn associate(&self, user: usize) -> Option<FlowLimit>;
+
+    /// called when an association expires or the server stops
+    fn finished(&self, _association: UdpAssociation) {}
+
+    /// name of `user` shown in the span of its associations, default to the index
+    fn user_name(&self, _user: usize) -> Option<String> {
+        None
+    }
+}
+
+/// summary of a finished association
+#[derive(Debug)]
+pub struct UdpAssociation {
+    pub peer: SocketAddr,
+    pub user: usize,
+    /// first target of the association
+    pub target: Option<Address>,
+    /// None when sent through an upstream
+    pub resolved: Option<SocketAddr>,
+    /// bytes from peer to targets
+    pub upload: u64,
+    /// bytes from targets to peer
+    pub download: u64,
+    pub duration: Duration,
+}
+
+/// keys and the policy of their users, swapped together so user indexes always match
+#[derive(Clone)]
+pub struct UdpUsers {
+    pub cipher: Arc<PacketCipher>,
+    pub policy: Option<Arc<dyn UdpPolicy>>,
+    /// upstreams packets to matching targets are relayed through
+    pub outbound: Option<Arc<Outbound>>,
+    /// of associating with an upstream
+    pub timeout: Duration,
+}
+
+/// peer addr and the key of its user, every user from a peer has its own worker
+type RouteKey = (SocketAddr, Bytes);
+
 pub struct UdpServer {
-    cipher: Arc<PacketCipher>,
+    users: watch::Receiver<UdpUsers>, // replaced on key change, workers keep the old one
     socket: Arc<UdpSocket>,
-    route_table: LruCache<SocketAddr, UdpTunnelWorkerHandle>, // peer addr -> worker
-    keepalive_tx: mpsc::Sender<SocketAddr>,
-    keepalive_rx: mpsc::Receiver<SocketAddr>,
+    route_table: LruCache<RouteKey, UdpTunnelWorkerHandle>, // peer addr and key -> worker
+    keepalive_tx: mpsc::Sender<RouteKey>,
+    keepalive_rx: mpsc::Receiver<RouteKey>,
     time_to_live: Duration,
 }
 
@@ -39,13 +86,27 @@ impl UdpServer {
         cap: usize,
         time_to_live: Duration,
     ) -> Self {
-        let cipher = PacketCipher::new(kind, key);
-        let cipher = Arc::new(cipher);
+        let users = UdpUsers {
+            cipher: Arc::new(PacketCipher::new(kind, key)),
+            policy: None,
+            outbound: None,
+            timeout: Duration::ZERO,
+        };
+        Self::new_with_users(socket, watch::channel(users).1, cap, time_to_live)
+    }
+
+    /// server with swappable users, new associations use the latest ones
+    pub fn new_with_users(
+        socket: UdpSocket,
+        users: watch::Receiver<UdpUsers>,
+        cap: usize,
+        time_to_live: Duration,
+    ) -> Self {
         let route_table = LruCache::with_expiry_duration_and_capacity(time_to_live, cap);
         let (keepalive_tx, keepalive_rx) = mpsc::channel(UDP_KEEP_ALIVE_CHANNEL_SIZE);
         let socket = Arc::new(socket);
         UdpServer {
-            cipher,
+            users,
             socket,
             route_table,
             keepalive_tx,
@@ -54,18 +115,33 @@ impl UdpServer {
         }
     }
 
-    pub async fn run(mut self) {
+    pub async fn run(self) {
+        self.run_with_drain(future::pending()).await
+    }
+
+    /// run until `stop` resolves, then drop packets from new peers and return
+    /// once all existing associations expired
+    pub async fn run_with_drain<F>(mut self, stop: F)
+    where
+        F: Future<Output = ()>,
+    {
         let recv_buf = &mut [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
         let mut cleanup_timer = time::interval(self.time_to_live);
+        let mut draining = false;
+        tokio::pin!(stop);
         loop {
             tokio::select! {
-                result = self.cipher.recv_from(&self.socket, recv_buf) => {
+                result = Self::recv_from(&self.users, &self.socket, recv_buf) => {
                     match result {
-                        Ok((n, peer, target)) => {
+                        Ok((n, peer, target, user_cipher)) => {
                             if n == 0 {continue;}
+                            if draining && !self.route_table.contains_key(&user_cipher.route(peer)) {
+                                trace!("udp proxy draining, drop {} bytes from new peer {}", n, peer);
+                                continue;
+                            }
                             let data = &recv_buf[..n];
 
-                            if let Err(e) = self.send_to_tunnle_worker(peer, target, data).await {
+                            if let Err(e) = self.send_to_tunnle_worker(peer, target, data, user_cipher).await {
                                 error!("udp proxy peer {} with {} bytes, send to tunnle worker error: {}", peer,  n, e);
                             }
                         }
@@ -78,40 +154,92 @@ impl UdpServer {
 
                 _ = cleanup_timer.tick() => {
                     let _ = self.route_table.iter();
+                    if draining && self.route_table.is_empty() {
+                        debug!("udp proxy drained");
+                        return;
+                    }
                 }
 
                 peer_addr_keep_opt = self.keepalive_rx.recv() => {
-                    let peer = peer_addr_keep_opt.expect("keep-alive channel closed unexpectly");
-                    self.route_table.get(&peer);
+                    let route = peer_addr_keep_opt.expect("keep-alive channel closed unexpectly");
+                    self.route_table.get(&route);
+                }
+
+                _ = &mut stop, if !draining => {
+                    debug!("udp proxy start draining, {} associations", self.route_table.len());
+                    draining = true;
+                    if self.route_table.is_empty() {
+                        return;
+                    }
                 }
             }
         }
     }
 
+    /// receive and decrypt with the latest cipher, return the users and the identified one
+    async fn recv_from(
+        users: &watch::Receiver<UdpUsers>,
+        socket: &UdpSocket,
+        buf: &mut [u8],
+    ) -> Result<(usize, SocketAddr, Address, UserCipher), Error> {
+        let (n, peer) = socket.recv_from(buf).await?;
+        let users = users.borrow().clone();
+        let (n, user, target) = users.cipher.decrypt_packet(&mut buf[..n]).await?;
+        Ok((n, peer, target, UserCipher { users, user }))
+    }
+
     async fn send_to_tunnle_worker(
         &mut self,
         peer: SocketAddr,
         target: Address,
         data: &[u8],
+        user_cipher: UserCipher,
     ) -> io::Result<()> {
-        if let Some(worker_handle) = self.route_table.get(&peer) {
+        let route = user_cipher.route(peer);
+        if let Some(worker_handle) = self.route_table.get(&route) {
             return worker_handle.try_send_to_worker((target, Bytes::copy_from_slice(data)));
         }
         // create a new worker
         debug!("new udp proxy request {} <-> ...", peer);
+        let UserCipher { users, user } = user_cipher;
+        let limit = match users.policy {
+            Some(ref policy) => match policy.associate(user) {
+                Some(limit) => limit,
+                None => {
+                    debug!("udp proxy request {} of user {} refused", peer, user);
+                    return Ok(());
+                }
+            },
+            None => FlowLimit::default(),
+        };
         let woker_handle = UdpTunnelWorkerHandle::new(
             self.socket.clone(),
             self.keepalive_tx.clone(),
-            peer,
-            self.cipher.clone(),
+            route.clone(),
+            Arc::new(users.cipher.for_user(user)),
+            limit,
+            users.policy.map(|policy| (policy, user)),
+            users.outbound.map(|outbound| (outbound, users.timeout)),
         );
 
         woker_handle.try_send_to_worker((target, Bytes::copy_from_slice(data)))?;
-        self.route_table.insert(peer, woker_handle);
+        self.route_table.insert(route, woker_handle);
         Ok(())
     }
 }
 
+/// users a packet was decrypted with and the index of the key which opened it
+struct UserCipher {
+    users: UdpUsers,
+    user: usize,
+}
+
+impl UserCipher {
+    fn route(&self, peer: SocketAddr) -> RouteKey {
+        (peer, self.users.cipher.key(self.user).clone())
+    }
+}
+
 struct UdpTunnelWorkerHandle {
     join_handle: JoinHandle<()>,
     sender: mpsc::Sender<(Address, Bytes)>,
@@ -126,61 +254,146 @@ impl Drop for UdpTunnelWorkerHandle {
 impl UdpTunnelWorkerHandle {
     fn new(
         server_socket: Arc<UdpSocket>,
-        keepalive_tx: mpsc::Sender<SocketAddr>,
-        peer_addr: SocketAddr,
+        keepalive_tx: mpsc::Sender<RouteKey>,
+        route: RouteKey,
         cipher: Arc<PacketCipher>,
+        limit: FlowLimit,
+        policy: Option<(Arc<dyn UdpPolicy>, usize)>,
+        outbound: Option<(Arc<Outbound>, Duration)>,
     ) -> Self {
-        let (join_handle, sender) =
-            UdpTunnelWorker::create(server_socket, keepalive_tx, peer_addr, cipher);
+        let (join_handle, sender) = UdpTunnelWorker::create(
+            server_socket,
+            keepalive_tx,
+            route,
+            cipher,
+            limit,
+            policy,
+            outbound,
+        );
         UdpTunnelWorkerHandle {
             join_handle,
             sender,
         }
     }
     fn try_send_to_worker(&self, data: (Address, Bytes)) -> io::Result<()> {
-        if let Err(..) = self.sender.try_send(data) {
-            let err = io::Error::new(io::ErrorKind::Other, "udp send channel full");
+        if self.sender.try_send(data).is_err() {
+            let err = io::Error::other("udp send channel full");
             return Err(err);
         }
         Ok(())
     }
 }
 
+/// packets to an upstream and the task relaying them
+struct UpstreamRelay {
+    tx: mpsc::Sender<(Address, Bytes)>,
+    task: JoinHandle<()>,
+}
+
 struct UdpTunnelWorker {
-    keepalive_tx: mpsc::Sender<SocketAddr>,
+    keepalive_tx: mpsc::Sender<RouteKey>,
     keepalive_flag: bool,
+    route: RouteKey,
     server_socket: Arc<UdpSocket>,
     peer_addr: SocketAddr,
     outbound_ipv4_socket: Option<UdpSocket>,
     outbound_ipv6_socket: Option<UdpSocket>,
+    outbound: Option<(Arc<Outbound>, Duration)>, // and timeout of associating
+    /// associations of upstreams by name, each relayed by its own task
+    upstream_relays: HashMap<String, UpstreamRelay>,
+    upstream_tx: mpsc::Sender<(SocketAddr, Bytes)>,
     cipher: Arc<PacketCipher>,
+    limit: FlowLimit,
+    policy: Option<(Arc<dyn UdpPolicy>, usize)>, // and user
+    start: Instant,
+    first_target: Option<(Address, Option<SocketAddr>)>,
+    upload: u64,
+    download: u64,
+}
+
+/// the worker is dropped when its task is aborted on expiry
+impl Drop for UdpTunnelWorker {
+    fn drop(&mut self) {
+        for relay in self.upstream_relays.values() {
+            relay.task.abort();
+        }
+        Span::current()
+            .record("upload", self.upload)
+            .record("download", self.download);
+        if let Some((ref policy, user)) = self.policy {
+            let first_target = self.first_target.take();
+            policy.finished(UdpAssociation {
+                peer: self.peer_addr,
+                user,
+                resolved: first_target.as_ref().and_then(|(_, sa)| *sa),
+                target: first_target.map(|(addr, _)| addr),
+                upload: self.upload,
+                download: self.download,
+                duration: self.start.elapsed(),
+            });
+        }
+    }
 }
 
 impl UdpTunnelWorker {
     fn create(
         server_socket: Arc<UdpSocket>,
-        keepalive_tx: mpsc::Sender<SocketAddr>,
-        peer_addr: SocketAddr,
+        keepalive_tx: mpsc::Sender<RouteKey>,
+        route: RouteKey,
         cipher: Arc<PacketCipher>,
+        limit: FlowLimit,
+        policy: Option<(Arc<dyn UdpPolicy>, usize)>,
+        outbound: Option<(Arc<Outbound>, Duration)>,
     ) -> (JoinHandle<()>, mpsc::Sender<(Address, Bytes)>) {
         let (tx, rx) = mpsc::channel(UDP_SEND_CHANNEL_SIZE);
+        let (upstream_tx, upstream_rx) = mpsc::channel(UDP_SEND_CHANNEL_SIZE);
+        let peer_addr = route.0;
 
+        let span = info_span!(
+            "udp",
+            peer = %peer_addr,
+            user = field::Empty,
+            target = field::Empty,
+            upload = field::Empty,
+            download = field::Empty,
+        );
+        if let Some((ref policy, user)) = policy {
+            match policy.user_name(user) {
+                Some(name) => span.record("user", name),
+                None => span.record("user", user),
+            };
+        }
         let woker = UdpTunnelWorker {
             keepalive_tx,
             keepalive_flag: false,
+            route,
             server_socket,
             peer_addr,
             outbound_ipv4_socket: None,
             outbound_ipv6_socket: None,
+            outbound,
+            upstream_relays: HashMap::new(),
+            upstream_tx,
             cipher,
+            limit,
+            policy,
+            start: Instant::now(),
+            first_target: None,
+            upload: 0,
+            download: 0,
         };
 
-        let join_handle = tokio::spawn(async move { woker.run(rx).await });
+        let join_handle =
+            tokio::spawn(async move { woker.run(rx, upstream_rx).await }.instrument(span));
 
         (join_handle, tx)
     }
 
-    async fn run(mut self, mut rx: mpsc::Receiver<(Address, Bytes)>) {
+    async fn run(
+        mut self,
+        mut rx: mpsc::Receiver<(Address, Bytes)>,
+        mut upstream_rx: mpsc::Receiver<(SocketAddr, Bytes)>,
+    ) {
         let mut outbound_ipv4_buffer = Vec::new();
         let mut outbound_ipv6_buffer = Vec::new();
         let mut keepalive_interval = time::interval(Duration::from_secs(1));
@@ -224,9 +437,13 @@ impl UdpTunnelWorker {
                     self.send_data_to_peer(target_addr, &outbound_ipv6_buffer[..n]).await;
                 }
 
+                Some((target_addr, data)) = upstream_rx.recv() => {
+                    self.send_data_to_peer(target_addr, &data).await;
+                }
+
                 _ = keepalive_interval.tick() => {
                     if self.keepalive_flag {
-                        if let Err(..) = self.keepalive_tx.try_send(self.peer_addr) {
+                        if self.keepalive_tx.try_send(self.route.clone()).is_err() {
                             debug!("udp tunnel worker for peer {} keep-alive failed, channel full or closed", self.peer_addr);
                         } else {
                             self.keepalive_flag = false;
@@ -238,6 +455,20 @@ impl UdpTunnelWorker {
     }
 
     async fn send_data_to_target(&mut self, target_addr: &Address, data: &[u8]) -> io::Result<()> {
+        // packets queue up in the channel while waiting, and are dropped when it is full
+        if !self.limit.upload_packet(data.len()).await {
+            trace!("udp proxy {} user blocked, drop packet", self.peer_addr);
+            return Ok(());
+        }
+
+        if let Some((ref outbound, timeout)) = self.outbound {
+            if let Some(upstream) = outbound.select(target_addr) {
+                let upstream = upstream.clone();
+                self.send_data_to_upstream(upstream, timeout, target_addr, data);
+                return Ok(());
+            }
+        }
+
         let target_sa: SocketAddr;
         match *target_addr {
             Address::SocketAddress(sa) => target_sa = sa,
@@ -247,18 +478,18 @@ impl UdpTunnelWorker {
                         match v.next() {
                             Some(sa) => target_sa = sa,
                             None => {
-                                return Err(io::Error::new(
-                                    io::ErrorKind::Other,
-                                    format!("dns resolve exmpty: {}", domain),
-                                ))
+                                return Err(io::Error::other(format!(
+                                    "dns resolve exmpty: {}",
+                                    domain
+                                )))
                             }
                         };
                     }
                     Err(e) => {
-                        return Err(io::Error::new(
-                            io::ErrorKind::Other,
-                            format!("dns resolve {} error: {}", domain, e),
-                        ))
+                        return Err(io::Error::other(format!(
+                            "dns resolve {} error: {}",
+                            domain, e
+                        )))
                     }
                 };
             }
@@ -284,6 +515,11 @@ impl UdpTunnelWorker {
         };
 
         let n = socket.send_to(data, target_sa).await?;
+        self.upload += n as u64;
+        if self.first_target.is_none() {
+            Span::current().record("target", field::display(target_addr));
+            self.first_target = Some((target_addr.clone(), Some(target_sa)));
+        }
         if n != data.len() {
             warn!(
                 "udp proxy {} -> {} sent {} bytes != expected {} bytes",
@@ -296,6 +532,111 @@ impl UdpTunnelWorker {
         Ok(())
     }
 
+    /// queue a packet to the task of the upstream, which is started on the first packet or
+    /// again after its association failed or closed
+    fn send_data_to_upstream(
+        &mut self,
+        upstream: Arc<Upstream>,
+        timeout: Duration,
+        target_addr: &Address,
+        data: &[u8],
+    ) {
+        if !upstream.has_udp() {
+            debug!(
+                "udp proxy {} -> {}, upstream {} has no udp, drop packet",
+                self.peer_addr,
+                target_addr,
+                upstream.name()
+            );
+            return;
+        }
+        let name = upstream.name().to_string();
+        if self
+            .upstream_relays
+            .get(&name)
+            .is_none_or(|r| r.task.is_finished())
+        {
+            let (tx, rx) = mpsc::channel(UDP_SEND_CHANNEL_SIZE);
+            let task = tokio::spawn(
+                Self::relay_upstream(upstream, timeout, rx, self.upstream_tx.clone())
+                    .in_current_span(),
+            );
+            self.upstream_relays
+                .insert(name.clone(), UpstreamRelay { tx, task });
+        }
+        let packet = (target_addr.clone(), Bytes::copy_from_slice(data));
+        if self.upstream_relays[&name].tx.try_send(packet).is_err() {
+            debug!(
+                "udp proxy {} -> {}, upstream {} busy, drop packet",
+                self.peer_addr, target_addr, name
+            );
+            return;
+        }
+        self.upload += data.len() as u64;
+        if self.first_target.is_none() {
+            Span::current().record("target", field::display(target_addr));
+            self.first_target = Some((target_addr.clone(), None));
+        }
+    }
+
+    /// associate with an upstream within `timeout`, then relay packets of the worker to it
+    /// and replies back until the worker is dropped or the association failed or closed
+    async fn relay_upstream(
+        upstream: Arc<Upstream>,
+        timeout: Duration,
+        mut rx: mpsc::Receiver<(Address, Bytes)>,
+        tx: mpsc::Sender<(SocketAddr, Bytes)>,
+    ) {
+        let name = upstream.name();
+        let relay = match time::timeout(timeout, upstream.associate_udp()).await {
+            Ok(Ok(relay)) => relay,
+            Ok(Err(e)) => {
+                warn!("udp upstream {} associate error: {}", name, e);
+                return;
+            }
+            Err(_) => {
+                warn!("udp upstream {} associate timeout", name);
+                return;
+            }
+        };
+        debug!("udp associated with upstream {}", name);
+        let mut buf = vec![0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+        loop {
+            tokio::select! {
+                packet = rx.recv() => {
+                    let Some((target, data)) = packet else {
+                        return;
+                    };
+                    if let Err(e) = relay.send_to(&target, &data).await {
+                        debug!("udp upstream {} send to {} error: {}", name, target, e);
+                    }
+                }
+                received = relay.recv_from(&mut buf) => {
+                    let data = match received {
+                        Ok((n, Address::SocketAddress(source))) => {
+                            (source, Bytes::copy_from_slice(&buf[..n]))
+                        }
+                        Ok((_, source)) => {
+                            trace!("udp upstream {} reply from {}, drop packet", name, source);
+                            continue;
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::ConnectionAborted => {
+                            debug!("udp upstream {} association closed: {}", name, e);
+                            return;
+                        }
+                        Err(e) => {
+                            debug!("udp upstream {} recv error: {}", name, e);
+                            continue;
+                        }
+                    };
+                    if tx.send(data).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
     async fn recv_data_from_target(
         socket: &Option<UdpSocket>,
         buf: &mut Vec<u8>,
@@ -313,6 +654,10 @@ impl UdpTunnelWorker {
 
     async fn send_data_to_peer(&mut self, target: SocketAddr, data: &[u8]) {
         self.keepalive_flag = true;
+        if !self.limit.download_packet(data.len()).await {
+            trace!("udp proxy {} user blocked, drop packet", self.peer_addr);
+            return;
+        }
 
         if let Err(e) = self
             .cipher
@@ -327,6 +672,7 @@ impl UdpTunnelWorker {
                 e
             );
         } else {
+            self.download += data.len() as u64;
             debug!(
                 "udp proxy {} <-> {}, R2L {} bytes",
                 self.peer_addr,
@@ -336,3 +682,184 @@ impl UdpTunnelWorker {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::util;
+
+    /// users of associations in the order they were created
+    #[derive(Default)]
+    struct Associated(Mutex<Vec<usize>>);
+
+    impl UdpPolicy for Associated {
+        fn associate(&self, user: usize) -> Option<FlowLimit> {
+            self.0.lock().unwrap().push(user);
+            Some(FlowLimit::default())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_worker_per_user() {
+        let kind = CipherKind::AES_256_GCM;
+        let keys: Vec<Bytes> = ["alice", "bob"]
+            .iter()
+            .map(|pwd| Bytes::from(util::evp_bytes_to_key(pwd.as_bytes(), kind.key_len())))
+            .collect();
+
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            while let Ok((n, from)) = echo.recv_from(&mut buf).await {
+                let _ = echo.send_to(&buf[..n], from).await;
+            }
+        });
+
+        let policy = Arc::new(Associated::default());
+        let (_users_tx, users) = watch::channel(UdpUsers {
+            cipher: Arc::new(PacketCipher::new_with_keys(kind, Arc::from(keys.clone()))),
+            policy: Some(policy.clone()),
+            outbound: None,
+            timeout: Duration::ZERO,
+        });
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = socket.local_addr().unwrap();
+        let server = UdpServer::new_with_users(socket, users, 16, Duration::from_secs(30));
+        tokio::spawn(server.run());
+
+        // one peer switching keys is associated once per user, each charged to its own
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for key in keys.iter().chain(keys.iter()) {
+            let cipher = PacketCipher::new(kind, key);
+            cipher
+                .send_to(&client, b"ping", server_addr, echo_addr)
+                .await
+                .unwrap();
+            let mut buf = [0u8; 64];
+            let (n, ..) =
+                time::timeout(Duration::from_secs(3), cipher.recv_from(&client, &mut buf))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            assert_eq!(&buf[..n], b"ping");
+        }
+        assert_eq!(*policy.0.lock().unwrap(), vec![0, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_upstream_associate_again() {
+        let kind = CipherKind::AES_256_GCM;
+        let key = Bytes::from(util::evp_bytes_to_key(b"passwd", kind.key_len()));
+        let proxy = crate::outbound::tests::socks5_stub().await;
+        let cfg: crate::outbound::OutboundConfig = toml::from_str(&format!(
+            "default = \"s\"\n[[upstreams]]\nname = \"s\"\nurl = \"socks5://user:pass@{}\"",
+            proxy
+        ))
+        .unwrap();
+        let outbound = Outbound::new(&cfg, &Default::default()).unwrap();
+
+        let (_users_tx, users) = watch::channel(UdpUsers {
+            cipher: Arc::new(PacketCipher::new(kind, &key)),
+            policy: None,
+            outbound: Some(Arc::new(outbound)),
+            timeout: Duration::from_secs(3),
+        });
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = socket.local_addr().unwrap();
+        let server = UdpServer::new_with_users(socket, users, 16, Duration::from_secs(30));
+        tokio::spawn(server.run());
+
+        // echoed by the association of the stub, which `close` ends with its control connection
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let cipher = PacketCipher::new(kind, &key);
+        let target: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        for payload in [&b"ping"[..], b"close", b"ping"] {
+            cipher
+                .send_to(&client, payload, server_addr, target)
+                .await
+                .unwrap();
+            if payload == b"close" {
+                time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+            let mut buf = [0u8; 64];
+            let (n, ..) =
+                time::timeout(Duration::from_secs(3), cipher.recv_from(&client, &mut buf))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            assert_eq!(&buf[..n], b"ping");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upstream_stalled() {
+        let kind = CipherKind::AES_256_GCM;
+        let key = Bytes::from(util::evp_bytes_to_key(b"passwd", kind.key_len()));
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            while let Ok((n, from)) = echo.recv_from(&mut buf).await {
+                let _ = echo.send_to(&buf[..n], from).await;
+            }
+        });
+        // accepts and never answers the handshake
+        let stalled = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let cfg: crate::outbound::OutboundConfig = toml::from_str(&format!(
+            "[[upstreams]]\nname = \"s\"\nurl = \"socks5://{}\"\n\
+             [[rules]]\ntargets = [\"10.0.0.0/8\"]\noutbound = \"s\"",
+            stalled.local_addr().unwrap()
+        ))
+        .unwrap();
+        let outbound = Outbound::new(&cfg, &Default::default()).unwrap();
+        let accepted = Arc::new(Mutex::new(Vec::new()));
+        let conns = accepted.clone();
+        let acceptor = tokio::spawn(async move {
+            while let Ok((s, _)) = stalled.accept().await {
+                conns.lock().unwrap().push(s);
+            }
+        });
+
+        let (_users_tx, users) = watch::channel(UdpUsers {
+            cipher: Arc::new(PacketCipher::new(kind, &key)),
+            policy: None,
+            outbound: Some(Arc::new(outbound)),
+            timeout: Duration::from_millis(200),
+        });
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = socket.local_addr().unwrap();
+        let server = UdpServer::new_with_users(socket, users, 16, Duration::from_secs(30));
+        tokio::spawn(server.run());
+
+        // the association stalls, direct targets of the same peer are still relayed
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let cipher = PacketCipher::new(kind, &key);
+        let upstream_target: SocketAddr = "10.0.0.1:9".parse().unwrap();
+        for target in [upstream_target, echo_addr] {
+            cipher
+                .send_to(&client, b"ping", server_addr, target)
+                .await
+                .unwrap();
+        }
+        let mut buf = [0u8; 64];
+        let (n, ..) = time::timeout(Duration::from_secs(3), cipher.recv_from(&client, &mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..n], b"ping");
+
+        // associated again after the timeout
+        time::sleep(Duration::from_millis(400)).await;
+        cipher
+            .send_to(&client, b"ping", server_addr, upstream_target)
+            .await
+            .unwrap();
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(accepted.lock().unwrap().len(), 2);
+        acceptor.abort();
+    }
+}
@@ -40,7 +40,15 @@ impl PacketCipher {
     ) -> Result<(usize, SocketAddr, Address), Error> {
         let (n, peer) = socket.recv_from(buf).await?;
 
-        let data_size = self.decrypt_from(&mut buf[..n])?;
+        let (n, _, target) = self.decrypt_packet(&mut buf[..n]).await?;
+
+        Ok((n, peer, target))
+    }
+
+    /// decrypt a received packet in place, return (payload size, user index, target addr),
+    /// payload is moved to the beginning of buf
+    pub async fn decrypt_packet(&self, buf: &mut [u8]) -> Result<(usize, usize, Address), Error> {
+        let (data_size, user) = self.decrypt_user_from(buf)?;
 
         let mut cur = Cursor::new(&mut buf[..data_size]);
 
@@ -50,7 +58,7 @@ impl PacketCipher {
         let payload = cur.into_inner();
         payload.copy_within(pos.., 0);
 
-        Ok((payload.len() - pos, peer, target))
+        Ok((payload.len() - pos, user, target))
     }
 }
 
@@ -58,12 +66,102 @@ pub async fn read_forever<R>(reader: &mut R) -> io::Result<()>
 where
     R: AsyncRead + Unpin,
 {
-    static mut READ_FOREVER_BUF: &mut [u8] = &mut [0u8; 1024];
+    let mut buf = [0u8; 1024];
     loop {
-        let n = unsafe { reader.read(READ_FOREVER_BUF).await? };
+        let n = reader.read(&mut buf).await?;
         if n == 0 {
             break;
         }
     }
     Ok(())
 }
+
+/// standard base64 with padding
+pub fn base64_encode(data: &[u8]) -> String {
+    const TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(TABLE[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// standard or url safe base64, padding optional
+pub fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim_end_matches('=');
+    let mut out = Vec::with_capacity(s.len() * 3 / 4);
+    let (mut acc, mut bits) = (0u32, 0);
+    for c in s.bytes() {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            _ => return None,
+        };
+        acc = acc << 6 | v as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    // a single leftover character can't encode a byte
+    (bits < 6).then_some(out)
+}
+
+/// `Sec-WebSocket-Accept` for `Sec-WebSocket-Key` of a websocket upgrade request
+pub fn websocket_accept(key: &str) -> String {
+    const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+    let mut ctx = ring::digest::Context::new(&ring::digest::SHA1_FOR_LEGACY_USE_ONLY);
+    ctx.update(key.as_bytes());
+    ctx.update(GUID.as_bytes());
+    base64_encode(ctx.finish().as_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base64_encode() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foo"), "Zm9v");
+        assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+    }
+
+    #[test]
+    fn test_base64_decode() {
+        for data in [&b""[..], b"f", b"fo", b"foo", b"foobar", &[0xfb, 0xff]] {
+            assert_eq!(base64_decode(&base64_encode(data)).as_deref(), Some(data));
+        }
+        assert_eq!(base64_decode("-_8").as_deref(), Some(&[0xfb, 0xff][..]));
+        assert_eq!(base64_decode("Zm9vY"), None);
+        assert_eq!(base64_decode("Zm9v!"), None);
+    }
+
+    #[test]
+    fn test_websocket_accept() {
+        // example of rfc 6455
+        assert_eq!(
+            websocket_accept("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+}